@@ -0,0 +1,41 @@
+//! feeds whatever libFuzzer comes up with through `Emote::try_new`, the first
+//! byte picks which format it gets decoded as and whether it gets shrunk
+//!
+//! `cargo +nightly fuzz run try_new` from `api/`
+
+#![no_main]
+
+use image::ImageFormat;
+use libfuzzer_sys::fuzz_target;
+use twitch_emote_api::emote::{
+    limits::DecodeLimits,
+    resolution::{Downscale, Resolution},
+    Emote, EmoteOptions,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&first, data)) = data.split_first() else {
+        return;
+    };
+    let formats: Vec<_> = ImageFormat::all().collect();
+    let format = formats[(first & 0x7f) as usize % formats.len()];
+    let downscale = (first & 0x80 != 0).then_some(Downscale::Height(16));
+
+    // small enough that libFuzzer doesn't run out of memory before we do
+    let options = EmoteOptions {
+        limits: DecodeLimits {
+            max_dimension: 1024,
+            max_decoded_bytes: 64 * 1024 * 1024,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let _ = Emote::try_new(
+        data,
+        format,
+        "fuzz",
+        Resolution::default(),
+        downscale,
+        &options,
+    );
+});
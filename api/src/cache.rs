@@ -1,36 +1,75 @@
-use std::{borrow::Borrow, hash::Hash};
+use std::{
+    borrow::Borrow,
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap},
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock, Weak,
+    },
+    time::{Duration, Instant},
+};
 
 use dashmap::{
     mapref::one::{MappedRef, MappedRefMut},
     DashMap,
 };
+use parking_lot::Mutex;
+use serde::Serialize;
+
+/// a reference to a cached value, the cache can't be written to while it's
+/// held
+pub type Hit<'a, K, V> = MappedRef<'a, K, CachedItem<V>, V>;
 
 #[derive(Debug, Clone)]
 pub struct Cache<K: Hash + Eq, V: Sized> {
     map: DashMap<K, CachedItem<V>>,
     max_age: std::time::Duration,
+    /// how long stale entries stick around for [`Self::get_stale`] before
+    /// they're gone for good
+    grace: std::time::Duration,
 }
 
 impl<K: Hash + Eq, V: Sized> Cache<K, V> {
     pub fn new(max_age: std::time::Duration) -> Self {
+        Self::with_grace(max_age, std::time::Duration::ZERO)
+    }
+
+    pub fn with_grace(max_age: std::time::Duration, grace: std::time::Duration) -> Self {
         Self {
             map: Default::default(),
             max_age,
+            grace,
         }
     }
 
-    pub fn get<Q>(&self, key: &Q) -> Option<MappedRef<'_, K, CachedItem<V>, V>>
+    pub fn get<Q>(&self, key: &Q) -> Option<Hit<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.get_stale(key) {
+            Some((hit, false)) => Some(hit),
+            _ => None,
+        }
+    }
+
+    /// like [`Self::get`], but entries that are stale and still in their
+    /// grace period are handed out too, along with whether they're stale
+    pub fn get_stale<Q>(&self, key: &Q) -> Option<(Hit<'_, K, V>, bool)>
     where
         K: Borrow<Q>,
         Q: Hash + Eq + ?Sized,
     {
         if let Some(hit) = self.map.get(key) {
-            if std::time::Instant::now() > hit.added_timestamp + self.max_age {
+            if hit.is_stale(self.max_age, self.grace) {
                 drop(hit);
                 self.map.remove(key);
                 return None;
             }
-            Some(hit.map(|r| &r.data))
+            hit.touch();
+            let stale = hit.is_stale(self.max_age, Duration::ZERO);
+            Some((hit.map(|r| &r.data), stale))
         } else {
             None
         }
@@ -42,9 +81,10 @@ impl<K: Hash + Eq, V: Sized> Cache<K, V> {
         Q: Hash + Eq + ?Sized,
     {
         if let Some(hit) = self.map.get_mut(key) {
-            if std::time::Instant::now() > hit.added_timestamp + self.max_age {
+            if hit.is_stale(self.max_age, Duration::ZERO) {
                 return None;
             }
+            hit.touch();
             Some(hit.map(|r| &mut r.data))
         } else {
             None
@@ -60,10 +100,10 @@ impl<K: Hash + Eq, V: Sized> Cache<K, V> {
         }
     }
 
+    /// drops everything that's past its grace period too
     pub fn evict_stale(&self) {
-        let now = std::time::Instant::now();
         self.map
-            .retain(|_, v| now < v.added_timestamp + self.max_age)
+            .retain(|_, v| !v.is_stale(self.max_age, self.grace))
     }
 
     /// gulp
@@ -74,27 +114,272 @@ impl<K: Hash + Eq, V: Sized> Cache<K, V> {
     pub fn insert(&self, key: K, value: V) -> Option<V> {
         self.map.insert(key, CachedItem::new(value)).map(|r| r.data)
     }
+
+    /// for entries that shouldn't live as long as the rest, like ones that
+    /// are known to be incomplete
+    pub fn insert_with_max_age(&self, key: K, value: V, max_age: std::time::Duration) -> Option<V> {
+        let mut item = CachedItem::new(value);
+        item.max_age = Some(max_age);
+        self.map.insert(key, item).map(|r| r.data)
+    }
+
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map.remove(key).map(|(_, r)| r.data)
+    }
+
+    /// drops every entry for which `f` returns false, stale or not, entries
+    /// that are kept can be modified in place
+    pub fn retain(&self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        self.map.retain(|k, v| f(k, &mut v.data))
+    }
 }
 
-#[derive(Debug, Clone)]
+/// what `last_used` counts from
+static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// nanoseconds since [`STARTED`]
+fn ticks() -> u64 {
+    STARTED.elapsed().as_nanos() as u64
+}
+
+#[derive(Debug)]
 pub struct CachedItem<V: Sized> {
     added_timestamp: std::time::Instant,
+    /// last time it was looked up in [`ticks`], for [`MemoryBudget`]. atomic
+    /// so lookups only need a read lock
+    last_used: AtomicU64,
+    /// overrides the max age of the cache it's in
+    max_age: Option<std::time::Duration>,
     data: V,
 }
 
+impl<V: Clone> Clone for CachedItem<V> {
+    fn clone(&self) -> Self {
+        Self {
+            added_timestamp: self.added_timestamp,
+            last_used: AtomicU64::new(self.last_used()),
+            max_age: self.max_age,
+            data: self.data.clone(),
+        }
+    }
+}
+
 impl<V: Sized> CachedItem<V> {
     pub fn new(data: V) -> Self {
         Self {
             added_timestamp: std::time::Instant::now(),
+            last_used: AtomicU64::new(ticks()),
+            max_age: None,
             data,
         }
     }
 
+    fn touch(&self) {
+        self.last_used.store(ticks(), Ordering::Relaxed);
+    }
+
+    fn last_used(&self) -> u64 {
+        self.last_used.load(Ordering::Relaxed)
+    }
+
+    /// `extra` on top of the max age, for grace periods
+    fn is_stale(&self, default_max_age: std::time::Duration, extra: std::time::Duration) -> bool {
+        std::time::Instant::now()
+            > self.added_timestamp + self.max_age.unwrap_or(default_max_age) + extra
+    }
+
     pub fn refresh(&mut self) {
         self.added_timestamp = std::time::Instant::now()
     }
 }
 
+/// anything that knows roughly how much memory it's holding on to
+pub trait MemorySize {
+    /// in bytes, doesn't have to be exact
+    fn memory_size(&self) -> usize;
+}
+
+/// a cache that can have its least recently used entries thrown out
+trait Budgeted: Send + Sync {
+    /// when every entry was last used, in [`ticks`], and how big it is
+    fn usage(&self) -> Vec<(u64, usize)>;
+    /// drops everything that hasn't been used since `cutoff`, returns how
+    /// many entries that was
+    fn evict_unused_since(&self, cutoff: u64) -> usize;
+}
+
+impl<K, V> Budgeted for Cache<K, V>
+where
+    K: Hash + Eq + Send + Sync,
+    V: MemorySize + Send + Sync,
+{
+    fn usage(&self) -> Vec<(u64, usize)> {
+        self.map
+            .iter()
+            .map(|r| (r.last_used(), r.data.memory_size()))
+            .collect()
+    }
+
+    fn evict_unused_since(&self, cutoff: u64) -> usize {
+        let mut evicted = 0;
+        self.map.retain(|_, v| {
+            let keep = v.last_used() > cutoff;
+            evicted += usize::from(!keep);
+            keep
+        });
+        evicted
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct CacheUsage {
+    pub bytes: u64,
+    pub entries: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub struct MemoryStats {
+    /// no limit when it's missing
+    pub max_bytes: Option<u64>,
+    pub used_bytes: u64,
+    pub entries: usize,
+    /// thrown out to stay under the limit since startup
+    pub evicted: u64,
+    /// by the name every cache got registered with
+    pub caches: BTreeMap<&'static str, CacheUsage>,
+}
+
+/// a limit on how much memory a few caches can take up together, the least
+/// recently used entries across all of them get thrown out first when it's
+/// over
+#[derive(Default)]
+pub struct MemoryBudget {
+    max_bytes: Option<u64>,
+    caches: Mutex<Vec<(&'static str, Weak<dyn Budgeted>)>>,
+    /// roughly what everything takes up, counted up on every insert and
+    /// set to the real thing on every trim. entries that grow or get
+    /// dropped in between only show up after the next one
+    used: AtomicU64,
+    /// only one trim at a time, everyone else can skip theirs
+    trimming: Mutex<()>,
+    evicted: AtomicU64,
+}
+
+impl std::fmt::Debug for MemoryBudget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemoryBudget")
+            .field("max_bytes", &self.max_bytes)
+            .field("caches", &self.caches.lock().len())
+            .finish()
+    }
+}
+
+impl MemoryBudget {
+    /// `None` never throws anything out, but still keeps track
+    pub fn new(max_bytes: Option<u64>) -> Self {
+        Self {
+            max_bytes,
+            ..Default::default()
+        }
+    }
+
+    /// counts `cache` towards the budget until it's dropped
+    pub fn register<K, V>(&self, name: &'static str, cache: &Arc<Cache<K, V>>)
+    where
+        K: Hash + Eq + Send + Sync + 'static,
+        V: MemorySize + Send + Sync + 'static,
+    {
+        let cache: Arc<dyn Budgeted> = cache.clone();
+        self.caches.lock().push((name, Arc::downgrade(&cache)));
+    }
+
+    fn caches(&self) -> Vec<(&'static str, Arc<dyn Budgeted>)> {
+        let mut caches = self.caches.lock();
+        caches.retain(|(_, c)| c.strong_count() > 0);
+        caches
+            .iter()
+            .filter_map(|(name, c)| Some((*name, c.upgrade()?)))
+            .collect()
+    }
+
+    /// counts an entry of `bytes` that just got cached, and trims if that
+    /// puts everything over
+    pub fn charge(&self, bytes: usize) {
+        let Some(max_bytes) = self.max_bytes else {
+            return;
+        };
+        let used = self.used.fetch_add(bytes as u64, Ordering::Relaxed) + bytes as u64;
+        if used > max_bytes {
+            self.trim();
+        }
+    }
+
+    /// throws out the least recently used entries if everything doesn't
+    /// fit, down to 90% of the limit so the next few inserts don't have to
+    /// do it all over again. entries can grow after they're inserted so this
+    /// is worth calling every once in a while too
+    pub fn trim(&self) {
+        let Some(max_bytes) = self.max_bytes else {
+            return;
+        };
+        let Some(_trimming) = self.trimming.try_lock() else {
+            return;
+        };
+
+        let caches = self.caches();
+        let usage: Vec<_> = caches.iter().flat_map(|(_, c)| c.usage()).collect();
+        let mut total: u64 = usage.iter().map(|(_, size)| *size as u64).sum();
+        if total <= max_bytes {
+            self.used.store(total, Ordering::Relaxed);
+            return;
+        }
+
+        // oldest first, until what's left fits
+        let target = max_bytes - max_bytes / 10;
+        let mut oldest: BinaryHeap<_> = usage.into_iter().map(Reverse).collect();
+        let mut cutoff = None;
+        while total > target {
+            let Some(Reverse((used, size))) = oldest.pop() else {
+                break;
+            };
+            total -= size as u64;
+            cutoff = Some(used);
+        }
+        self.used.store(total, Ordering::Relaxed);
+        let Some(cutoff) = cutoff else {
+            return;
+        };
+
+        let evicted: usize = caches
+            .iter()
+            .map(|(_, c)| c.evict_unused_since(cutoff))
+            .sum();
+        self.evicted.fetch_add(evicted as u64, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        let mut stats = MemoryStats {
+            max_bytes: self.max_bytes,
+            evicted: self.evicted.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+        for (name, cache) in self.caches() {
+            let usage = cache.usage();
+            let bytes: u64 = usage.iter().map(|(_, size)| *size as u64).sum();
+            let entry = stats.caches.entry(name).or_default();
+            entry.bytes += bytes;
+            entry.entries += usage.len();
+            stats.used_bytes += bytes;
+            stats.entries += usage.len();
+        }
+        stats
+    }
+}
+
 // impl Deref for EmoteCache {
 //     type Target = DashMap<String, Emote>;
 
@@ -102,3 +387,113 @@ impl<V: Sized> CachedItem<V> {
 //         &self.cache
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used, reason = "bruh this is a tests module")]
+
+    use std::{sync::Arc, time::Duration};
+
+    use super::{Cache, MemoryBudget, MemorySize};
+
+    struct Blob(usize);
+
+    impl MemorySize for Blob {
+        fn memory_size(&self) -> usize {
+            self.0
+        }
+    }
+
+    #[test]
+    fn grace() {
+        let cache = Cache::with_grace(Duration::from_secs(60), Duration::from_secs(60));
+        cache.insert_with_max_age("stale", 1, Duration::ZERO);
+        cache.insert("fresh", 3);
+
+        assert!(cache.get("stale").is_none());
+        assert_eq!(
+            cache.get_stale("stale").map(|(v, s)| (*v, s)),
+            Some((1, true))
+        );
+        assert_eq!(
+            cache.get_stale("fresh").map(|(v, s)| (*v, s)),
+            Some((3, false))
+        );
+
+        // past the grace period too
+        let cache = Cache::<&str, u32>::with_grace(Duration::ZERO, Duration::ZERO);
+        cache.insert("gone", 2);
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(cache.get_stale("gone").is_none());
+        cache.insert("gone", 2);
+        std::thread::sleep(Duration::from_millis(2));
+        cache.evict_stale();
+        assert!(cache.remove("gone").is_none());
+    }
+
+    #[test]
+    fn budget() {
+        let budget = MemoryBudget::new(Some(100));
+        let a = Arc::new(Cache::new(Duration::from_secs(60)));
+        let b = Arc::new(Cache::new(Duration::from_secs(60)));
+        budget.register("a", &a);
+        budget.register("b", &b);
+
+        a.insert(1, Blob(40));
+        std::thread::sleep(Duration::from_millis(2));
+        b.insert(2, Blob(40));
+        std::thread::sleep(Duration::from_millis(2));
+        budget.trim();
+        assert_eq!(budget.stats().used_bytes, 80);
+
+        // 1 was used more recently than 2 now, so 2's the one that goes
+        a.get(&1).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        a.insert(3, Blob(40));
+        budget.trim();
+        assert!(a.get(&1).is_some());
+        assert!(b.get(&2).is_none());
+        assert!(a.get(&3).is_some());
+
+        let stats = budget.stats();
+        assert_eq!(stats.used_bytes, 80);
+        assert_eq!(stats.entries, 2);
+        assert_eq!(stats.evicted, 1);
+        assert_eq!(stats.caches["a"].entries, 2);
+        assert_eq!(stats.caches["b"].entries, 0);
+
+        // caches that are gone don't count anymore
+        drop(a);
+        assert_eq!(budget.stats().used_bytes, 0);
+    }
+
+    #[test]
+    fn charge() {
+        let budget = MemoryBudget::new(Some(100));
+        let cache = Arc::new(Cache::new(Duration::from_secs(60)));
+        budget.register("cache", &cache);
+
+        let insert = |key| {
+            cache.insert(key, Blob(10));
+            budget.charge(10);
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        for key in 0..10 {
+            insert(key);
+        }
+        // exactly full still fits
+        assert_eq!(budget.stats().evicted, 0);
+
+        // going over makes room for a few more instead of just the one
+        insert(10);
+        let stats = budget.stats();
+        assert_eq!(stats.evicted, 2);
+        assert_eq!(stats.used_bytes, 90);
+        assert!(cache.get(&0).is_none());
+        assert!(cache.get(&1).is_none());
+        assert!(cache.get(&2).is_some());
+
+        insert(11);
+        assert_eq!(budget.stats().evicted, 2);
+    }
+}
@@ -1,6 +1,24 @@
-use std::sync::LazyLock;
+use std::{
+    path::PathBuf,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
 
 use clap::Parser;
+use tracing::warn;
+use url::Url;
+
+use crate::{
+    emote::{
+        atlas::{AtlasLayout, AtlasMode},
+        limits::DecodeLimits,
+        scheduler::DecodeScheduler,
+        worker::{Decoder, WorkerConfig, WorkerPool},
+        EmoteOptions,
+    },
+    platforms::{warmup::WarmupConfig, EmoteManagerConfig, PlatformPriority, UpstreamUrls},
+    store::StoreConfig,
+};
 
 pub static ARGS: LazyLock<Args> = LazyLock::new(|| {
     let _ = dotenvy::dotenv();
@@ -18,4 +36,280 @@ pub struct Args {
     /// port to listen on
     #[arg(long, default_value_t = 8080)]
     pub port: u16,
+    /// keep 7TV emote sets up to date live through the 7TV EventAPI
+    #[arg(long, env = "SEVENTV_EVENTS")]
+    pub seventv_events: bool,
+    /// keep BetterTTV channel emotes up to date live through the BTTV websocket
+    #[arg(long, env = "BTTV_EVENTS")]
+    pub bttv_events: bool,
+    /// which platform wins when channel emotes share a name, highest first,
+    /// platforms left out keep their default order after the listed ones
+    #[arg(long, env = "EMOTE_PRIORITY", default_value_t)]
+    pub emote_priority: PlatformPriority,
+    /// seconds a channel's emotes keep being served after they expire while
+    /// they're refreshed, platforms that fail to refresh keep their old emotes
+    /// for that long too
+    #[arg(long, env = "CHANNEL_GRACE", default_value_t = 60 * 60)]
+    pub channel_grace: u64,
+    /// how frames get laid out on atlas textures
+    #[arg(
+        long,
+        env = "ATLAS_MODE",
+        value_enum,
+        default_value_t,
+        help_heading = "Atlases"
+    )]
+    pub atlas_mode: AtlasMode,
+    /// transparent pixels around every frame on atlases
+    #[arg(
+        long,
+        env = "ATLAS_PADDING",
+        default_value_t = 0,
+        help_heading = "Atlases"
+    )]
+    pub atlas_padding: u32,
+    /// fill atlas padding with each frame's edge pixels instead
+    #[arg(long, env = "ATLAS_BLEED", help_heading = "Atlases")]
+    pub atlas_bleed: bool,
+    /// biggest width or height an atlas page can have, more pages get made
+    /// when the frames don't fit, e.g. the client's `MAX_TEXTURE_SIZE`
+    #[arg(long, env = "ATLAS_MAX_SIZE", help_heading = "Atlases")]
+    pub atlas_max_size: Option<u32>,
+    /// merge runs of identical frames into one, adding up their delays
+    #[arg(long, env = "DEDUP_FRAMES")]
+    pub dedup_frames: bool,
+    /// biggest emote file that gets downloaded, in bytes
+    #[arg(
+        long,
+        env = "MAX_DOWNLOAD_BYTES",
+        default_value_t = DecodeLimits::default().max_download_bytes,
+        help_heading = "Limits"
+    )]
+    pub max_download_bytes: u64,
+    /// widest or tallest an emote can be, in pixels
+    #[arg(
+        long,
+        env = "MAX_EMOTE_DIMENSION",
+        default_value_t = DecodeLimits::default().max_dimension,
+        help_heading = "Limits"
+    )]
+    pub max_emote_dimension: u32,
+    /// most frames an animated emote can have
+    #[arg(
+        long,
+        env = "MAX_EMOTE_FRAMES",
+        default_value_t = DecodeLimits::default().max_frames,
+        help_heading = "Limits"
+    )]
+    pub max_emote_frames: u32,
+    /// most memory every frame of an emote can take up decoded, in bytes
+    #[arg(
+        long,
+        env = "MAX_DECODED_BYTES",
+        default_value_t = DecodeLimits::default().max_decoded_bytes,
+        help_heading = "Limits"
+    )]
+    pub max_decoded_bytes: u64,
+    /// most emotes decoding at once, one per core by default, requests waiting
+    /// on one go ahead of background work
+    #[arg(long, env = "DECODE_CONCURRENCY", help_heading = "Decoding")]
+    pub decode_concurrency: Option<usize>,
+    /// decode emotes in this many worker processes instead of the server
+    /// itself, so a decoder crashing or hanging can't take it down. on Linux
+    /// they're sandboxed with seccomp too
+    #[arg(
+        long,
+        env = "DECODE_WORKERS",
+        default_value_t = 0,
+        help_heading = "Decoding"
+    )]
+    pub decode_workers: usize,
+    /// seconds a worker gets for a single emote before it's killed
+    #[arg(
+        long,
+        env = "DECODE_WORKER_TIMEOUT",
+        default_value_t = 30,
+        help_heading = "Decoding"
+    )]
+    pub decode_worker_timeout: u64,
+    /// memory every worker can use, in bytes
+    #[arg(
+        long,
+        env = "DECODE_WORKER_MEMORY",
+        default_value_t = 2 * 1024 * 1024 * 1024,
+        help_heading = "Decoding"
+    )]
+    pub decode_worker_memory: u64,
+    /// most memory cached emotes can take up together, in bytes, the least
+    /// recently used ones get thrown out past that. 0 for no limit
+    #[arg(
+        long,
+        env = "EMOTE_MEMORY_BUDGET",
+        default_value_t = 1024 * 1024 * 1024
+    )]
+    pub emote_memory_budget: u64,
+    /// keep decoded emotes in an SQLite database here, so they don't all have
+    /// to be downloaded again after a restart
+    #[arg(long, env = "EMOTE_STORE_PATH", help_heading = "Store")]
+    pub store_path: Option<PathBuf>,
+    /// most the stored emotes can take up together, in bytes
+    #[arg(
+        long,
+        env = "EMOTE_STORE_MAX_BYTES",
+        default_value_t = 1024 * 1024 * 1024,
+        help_heading = "Store"
+    )]
+    pub store_max_bytes: u64,
+    /// seconds an emote stays stored before it's downloaded again
+    #[arg(
+        long,
+        env = "EMOTE_STORE_MAX_AGE",
+        default_value_t = 7 * 24 * 60 * 60,
+        help_heading = "Store"
+    )]
+    pub store_max_age: u64,
+    /// twitch usernames whose emotes get fetched at startup, before anyone
+    /// asks for them
+    #[arg(
+        long,
+        env = "WARMUP_CHANNELS",
+        value_delimiter = ',',
+        help_heading = "Warm-up"
+    )]
+    pub warmup_channels: Vec<String>,
+    /// seconds between warming them up again, only once at startup if unset
+    #[arg(long, env = "WARMUP_INTERVAL", help_heading = "Warm-up")]
+    pub warmup_interval: Option<u64>,
+    /// decode every emote the channels have too, not just their lists
+    #[arg(long, env = "WARMUP_DECODE", help_heading = "Warm-up")]
+    pub warmup_decode: bool,
+    /// most channels or emotes fetched per second while warming up
+    #[arg(
+        long,
+        env = "WARMUP_RATE",
+        default_value_t = 5.0,
+        help_heading = "Warm-up"
+    )]
+    pub warmup_rate: f64,
+    /// override for the Twitch OAuth token endpoint
+    #[arg(long, env = "TWITCH_OAUTH_URL", help_heading = "Upstreams")]
+    pub twitch_oauth_url: Option<Url>,
+    /// override for the Twitch Helix API base url
+    #[arg(long, env = "TWITCH_HELIX_URL", help_heading = "Upstreams")]
+    pub twitch_helix_url: Option<Url>,
+    /// override for the Twitch emote CDN base url
+    #[arg(long, env = "TWITCH_CDN_URL", help_heading = "Upstreams")]
+    pub twitch_cdn_url: Option<Url>,
+    /// override for the 7TV API base url
+    #[arg(long, env = "SEVENTV_API_URL", help_heading = "Upstreams")]
+    pub seventv_api_url: Option<Url>,
+    /// override for the 7TV emote CDN base url
+    #[arg(long, env = "SEVENTV_CDN_URL", help_heading = "Upstreams")]
+    pub seventv_cdn_url: Option<Url>,
+    /// override for the 7TV EventAPI websocket url
+    #[arg(long, env = "SEVENTV_EVENTS_URL", help_heading = "Upstreams")]
+    pub seventv_events_url: Option<Url>,
+    /// override for the BetterTTV API base url
+    #[arg(long, env = "BTTV_API_URL", help_heading = "Upstreams")]
+    pub bttv_api_url: Option<Url>,
+    /// override for the BetterTTV emote CDN base url
+    #[arg(long, env = "BTTV_CDN_URL", help_heading = "Upstreams")]
+    pub bttv_cdn_url: Option<Url>,
+    /// override for the BetterTTV websocket url
+    #[arg(long, env = "BTTV_SOCKET_URL", help_heading = "Upstreams")]
+    pub bttv_socket_url: Option<Url>,
+    /// override for the FrankerFaceZ API base url
+    #[arg(long, env = "FFZ_API_URL", help_heading = "Upstreams")]
+    pub ffz_api_url: Option<Url>,
+    /// override for the FrankerFaceZ emote CDN base url
+    #[arg(long, env = "FFZ_CDN_URL", help_heading = "Upstreams")]
+    pub ffz_cdn_url: Option<Url>,
+}
+
+impl Args {
+    pub fn upstream_urls(&self) -> UpstreamUrls {
+        let mut urls = UpstreamUrls::default();
+
+        let overrides = [
+            (&mut urls.twitch.oauth, &self.twitch_oauth_url),
+            (&mut urls.twitch.helix, &self.twitch_helix_url),
+            (&mut urls.twitch.cdn, &self.twitch_cdn_url),
+            (&mut urls.seventv.api, &self.seventv_api_url),
+            (&mut urls.seventv.cdn, &self.seventv_cdn_url),
+            (&mut urls.seventv.events, &self.seventv_events_url),
+            (&mut urls.bttv.api, &self.bttv_api_url),
+            (&mut urls.bttv.cdn, &self.bttv_cdn_url),
+            (&mut urls.bttv.socket, &self.bttv_socket_url),
+            (&mut urls.ffz.api, &self.ffz_api_url),
+            (&mut urls.ffz.cdn, &self.ffz_cdn_url),
+        ];
+        for (url, over) in overrides {
+            if let Some(over) = over {
+                *url = over.clone();
+            }
+        }
+
+        urls
+    }
+
+    pub fn decoder(&self) -> Decoder {
+        if self.decode_workers == 0 {
+            return Decoder::InProcess;
+        }
+
+        match WorkerPool::new(WorkerConfig {
+            workers: self.decode_workers,
+            timeout: Duration::from_secs(self.decode_worker_timeout),
+            memory_limit: self.decode_worker_memory,
+        }) {
+            Ok(pool) => Decoder::Workers(Arc::new(pool)),
+            Err(e) => {
+                // still works, just without the isolation
+                warn!("can't find our own executable, decoding without workers: {e}");
+                Decoder::InProcess
+            }
+        }
+    }
+
+    pub fn manager_config(&self) -> EmoteManagerConfig {
+        EmoteManagerConfig {
+            urls: self.upstream_urls(),
+            seventv_events: self.seventv_events,
+            bttv_events: self.bttv_events,
+            priority: self.emote_priority,
+            emote: EmoteOptions {
+                atlas: AtlasLayout {
+                    mode: self.atlas_mode,
+                    padding: self.atlas_padding,
+                    bleed: self.atlas_bleed,
+                    max_size: self.atlas_max_size,
+                },
+                dedup_frames: self.dedup_frames,
+                limits: DecodeLimits {
+                    max_download_bytes: self.max_download_bytes,
+                    max_dimension: self.max_emote_dimension,
+                    max_frames: self.max_emote_frames,
+                    max_decoded_bytes: self.max_decoded_bytes,
+                },
+                decoder: self.decoder(),
+                scheduler: self
+                    .decode_concurrency
+                    .map(DecodeScheduler::new)
+                    .unwrap_or_default(),
+            },
+            store: self.store_path.clone().map(|path| StoreConfig {
+                path,
+                max_bytes: self.store_max_bytes,
+                max_age: Duration::from_secs(self.store_max_age),
+            }),
+            memory_budget: (self.emote_memory_budget != 0).then_some(self.emote_memory_budget),
+            channel_grace: Duration::from_secs(self.channel_grace),
+            warmup: (!self.warmup_channels.is_empty()).then(|| WarmupConfig {
+                channels: self.warmup_channels.clone(),
+                every: self.warmup_interval.map(Duration::from_secs),
+                decode: self.warmup_decode,
+                delay: Duration::from_secs_f64(1.0 / self.warmup_rate.max(0.001)),
+            }),
+        }
+    }
 }
@@ -0,0 +1,339 @@
+use std::sync::{Arc, OnceLock};
+
+use bytes::Bytes;
+use image::{
+    error::{DecodingError, EncodingError, ImageFormatHint, ParameterError, ParameterErrorKind},
+    ImageError, ImageFormat,
+};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+use super::{format::OutputFormat, frame::Frame, timed};
+
+/// formats a whole animation can be rebuilt as, png meaning APNG
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AnimatedFormat {
+    WebP,
+    Gif,
+    Png,
+}
+
+impl AnimatedFormat {
+    /// there's no animated AVIF encoder around, so that one's missing
+    pub fn from_output(format: OutputFormat) -> Option<Self> {
+        match format {
+            OutputFormat::WebP => Some(Self::WebP),
+            OutputFormat::Gif => Some(Self::Gif),
+            OutputFormat::Png => Some(Self::Png),
+            OutputFormat::Avif => None,
+        }
+    }
+
+    pub fn output(self) -> OutputFormat {
+        match self {
+            AnimatedFormat::WebP => OutputFormat::WebP,
+            AnimatedFormat::Gif => OutputFormat::Gif,
+            AnimatedFormat::Png => OutputFormat::Png,
+        }
+    }
+}
+
+/// animated versions of an emote, built the first time they're asked for
+#[derive(Clone, Default)]
+pub struct AnimatedCache(Arc<[OnceLock<Bytes>; 3]>);
+
+impl std::fmt::Debug for AnimatedCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AnimatedCache(even more bytes)")
+    }
+}
+
+impl AnimatedCache {
+    pub fn slot(&self, format: AnimatedFormat) -> &OnceLock<Bytes> {
+        match format {
+            AnimatedFormat::WebP => &self.0[0],
+            AnimatedFormat::Gif => &self.0[1],
+            AnimatedFormat::Png => &self.0[2],
+        }
+    }
+
+    /// bytes of every version that's been built so far
+    pub fn size(&self) -> usize {
+        self.0
+            .iter()
+            .filter_map(OnceLock::get)
+            .map(Bytes::len)
+            .sum()
+    }
+}
+
+fn delay_millis(frame: &Frame) -> u32 {
+    // still images have f64::MAX, which is about as long as it gets anyway
+    (frame.delay * 1000.0)
+        .round()
+        .clamp(0.0, f64::from(u32::MAX)) as u32
+}
+
+/// `frames` as a single file that loops forever, every frame has to be
+/// `width` x `height`. frames get encoded on as many threads as rayon has,
+/// and put together in order afterwards
+pub fn encode(
+    frames: &[Frame],
+    width: u32,
+    height: u32,
+    format: AnimatedFormat,
+) -> Result<Bytes, ImageError> {
+    let span = tracing::debug_span!(
+        "encode_animated",
+        ?format,
+        frames = frames.len(),
+        millis = tracing::field::Empty
+    );
+    timed(span, || match format {
+        AnimatedFormat::WebP => encode_webp(frames, width, height),
+        AnimatedFormat::Gif => encode_gif(frames, width, height),
+        AnimatedFormat::Png => encode_apng(frames, width, height),
+    })
+}
+
+fn encode_gif(frames: &[Frame], width: u32, height: u32) -> Result<Bytes, ImageError> {
+    let gif_error = |e: gif::EncodingError| {
+        ImageError::Encoding(EncodingError::new(
+            ImageFormatHint::Exact(ImageFormat::Gif),
+            e,
+        ))
+    };
+    let (Ok(width), Ok(height)) = (u16::try_from(width), u16::try_from(height)) else {
+        return Err(ImageError::Parameter(ParameterError::from_kind(
+            ParameterErrorKind::DimensionMismatch,
+        )));
+    };
+
+    // quantizing is what takes forever, and every frame gets its own palette
+    let span = tracing::Span::current();
+    let gif_frames = frames
+        .par_iter()
+        .map(|frame| {
+            let _span = span.enter();
+            let mut pixels = frame.image().render()?.into_raw();
+            // speed 1 takes ages on anything bigger than a few frames
+            let mut gif_frame = gif::Frame::from_rgba_speed(width, height, &mut pixels, 10);
+            // gif delays are in centiseconds and a u16
+            gif_frame.delay = (delay_millis(frame) / 10).min(u16::MAX.into()) as u16;
+            gif_frame.dispose = gif::DisposalMethod::Background;
+            Ok(gif_frame)
+        })
+        .collect::<Result<Vec<_>, ImageError>>()?;
+
+    let mut out = Vec::new();
+    {
+        let mut encoder = gif::Encoder::new(&mut out, width, height, &[]).map_err(gif_error)?;
+        encoder
+            .set_repeat(gif::Repeat::Infinite)
+            .map_err(gif_error)?;
+        for frame in &gif_frames {
+            encoder.write_frame(frame).map_err(gif_error)?;
+        }
+    }
+    Ok(out.into())
+}
+
+fn encode_apng(frames: &[Frame], width: u32, height: u32) -> Result<Bytes, ImageError> {
+    let png_error = |e: png::EncodingError| {
+        ImageError::Encoding(EncodingError::new(
+            ImageFormatHint::Exact(ImageFormat::Png),
+            e,
+        ))
+    };
+
+    let mut out = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut out, width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .set_animated(frames.len() as u32, 0)
+            .map_err(png_error)?;
+        let mut writer = encoder.write_header().map_err(png_error)?;
+        for frame in frames {
+            let millis = delay_millis(frame).min(u16::MAX.into()) as u16;
+            writer.set_frame_delay(millis, 1000).map_err(png_error)?;
+            writer
+                .write_image_data(frame.image().render()?.as_raw())
+                .map_err(png_error)?;
+        }
+        writer.finish().map_err(png_error)?;
+    }
+    Ok(out.into())
+}
+
+/// there's no animated WebP encoder, but our frames are lossless WebPs
+/// already so their bitstreams just get moved into ANMF chunks
+fn encode_webp(frames: &[Frame], width: u32, height: u32) -> Result<Bytes, ImageError> {
+    // durations in ANMF are 24 bits
+    const MAX_DURATION: u32 = (1 << 24) - 1;
+
+    let mut body = Vec::new();
+
+    let mut vp8x = vec![0x10 | 0x02, 0, 0, 0]; // alpha and animation
+    push_u24(&mut vp8x, width - 1);
+    push_u24(&mut vp8x, height - 1);
+    push_chunk(&mut body, b"VP8X", &vp8x);
+
+    // transparent background, loop forever
+    push_chunk(&mut body, b"ANIM", &[0, 0, 0, 0, 0, 0]);
+
+    let span = tracing::Span::current();
+    let webps = frames
+        .par_iter()
+        .map(|frame| {
+            let _span = span.enter();
+            frame.image().encode(OutputFormat::WebP)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (frame, webp) in frames.iter().zip(webps) {
+        let mut anmf = Vec::new();
+        push_u24(&mut anmf, 0);
+        push_u24(&mut anmf, 0);
+        push_u24(&mut anmf, width - 1);
+        push_u24(&mut anmf, height - 1);
+        push_u24(&mut anmf, delay_millis(frame).min(MAX_DURATION));
+        // every frame covers the whole canvas so just don't blend
+        anmf.push(0b10);
+        copy_bitstream(&webp, &mut anmf)?;
+        push_chunk(&mut body, b"ANMF", &anmf);
+    }
+
+    let mut out = Vec::with_capacity(body.len() + 12);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(body.len() as u32 + 4).to_le_bytes());
+    out.extend_from_slice(b"WEBP");
+    out.extend_from_slice(&body);
+    Ok(out.into())
+}
+
+fn push_u24(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes()[..3]);
+}
+
+fn push_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], payload: &[u8]) {
+    out.extend_from_slice(fourcc);
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(payload);
+    if payload.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+/// copies the chunks with the actual image out of a still WebP
+fn copy_bitstream(webp: &[u8], out: &mut Vec<u8>) -> Result<(), ImageError> {
+    let bad = || {
+        ImageError::Decoding(DecodingError::new(
+            ImageFormatHint::Exact(ImageFormat::WebP),
+            "frame isn't a WebP we made",
+        ))
+    };
+
+    if webp.get(0..4) != Some(b"RIFF") || webp.get(8..12) != Some(b"WEBP") {
+        return Err(bad());
+    }
+
+    let mut rest = &webp[12..];
+    while !rest.is_empty() {
+        let header: [u8; 8] = rest
+            .get(0..8)
+            .and_then(|h| h.try_into().ok())
+            .ok_or_else(bad)?;
+        let fourcc = [header[0], header[1], header[2], header[3]];
+        let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let payload = rest.get(8..8 + size).ok_or_else(bad)?;
+        if matches!(&fourcc, b"VP8L" | b"VP8 " | b"ALPH") {
+            push_chunk(out, &fourcc, payload);
+        }
+        rest = rest.get(8 + size + size % 2..).unwrap_or_default();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used, reason = "bruh this is a tests module")]
+
+    use std::{io::Cursor, sync::Arc};
+
+    use image::{AnimationDecoder, Rgba, RgbaImage};
+
+    use super::{encode, AnimatedFormat};
+    use crate::emote::{
+        frame::{Frame, RawFrames},
+        scheduler::DecodeScheduler,
+    };
+
+    fn frames() -> Vec<Frame> {
+        let frames: Vec<_> = (0..3u8)
+            .map(|i| RgbaImage::from_pixel(5, 3, Rgba([i * 100, 0, 0, 255 - i])))
+            .collect();
+        let raw = RawFrames::new(5, 3, frames.iter()).unwrap();
+        Frame::from_raw(&Arc::new(raw), [0.02, 0.04, 0.06])
+    }
+
+    fn delays(frames: Vec<image::Frame>) -> Vec<u32> {
+        frames
+            .iter()
+            .map(|f| {
+                let (num, den) = f.delay().numer_denom_ms();
+                num / den
+            })
+            .collect()
+    }
+
+    #[test]
+    fn webp() {
+        let data = encode(&frames(), 5, 3, AnimatedFormat::WebP).unwrap();
+        let decoder = image::codecs::webp::WebPDecoder::new(Cursor::new(data)).unwrap();
+        assert!(decoder.has_animation());
+        let decoded = decoder.into_frames().collect_frames().unwrap();
+        assert_eq!(delays(decoded.clone()), [20, 40, 60]);
+        // lossless all the way through
+        assert_eq!(decoded[1].buffer().get_pixel(2, 2), &Rgba([100, 0, 0, 254]));
+    }
+
+    #[test]
+    fn gif() {
+        let data = encode(&frames(), 5, 3, AnimatedFormat::Gif).unwrap();
+        let decoder = image::codecs::gif::GifDecoder::new(Cursor::new(data)).unwrap();
+        let decoded = decoder.into_frames().collect_frames().unwrap();
+        assert_eq!(delays(decoded), [20, 40, 60]);
+    }
+
+    #[test]
+    fn apng() {
+        let data = encode(&frames(), 5, 3, AnimatedFormat::Png).unwrap();
+        let decoder = image::codecs::png::PngDecoder::new(Cursor::new(data)).unwrap();
+        assert!(decoder.is_apng().unwrap());
+        let decoded = decoder
+            .apng()
+            .unwrap()
+            .into_frames()
+            .collect_frames()
+            .unwrap();
+        assert_eq!(delays(decoded), [20, 40, 60]);
+    }
+
+    #[test]
+    fn deterministic() {
+        let frames: Vec<_> = (0..24u32)
+            .map(|i| RgbaImage::from_fn(16, 16, |x, y| Rgba([(x * i) as u8, y as u8, 7, 255])))
+            .collect();
+        let raw = Arc::new(RawFrames::new(16, 16, frames.iter()).unwrap());
+        let encode_with = |threads, format| {
+            // fresh frames every time so nothing's cached from the last one
+            let frames = Frame::from_raw(&raw, vec![0.05; 24]);
+            DecodeScheduler::new(threads).install(|| encode(&frames, 16, 16, format).unwrap())
+        };
+
+        for format in [AnimatedFormat::WebP, AnimatedFormat::Gif] {
+            assert_eq!(encode_with(1, format), encode_with(4, format));
+        }
+    }
+}
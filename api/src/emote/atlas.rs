@@ -1,94 +1,396 @@
-use std::{io::Cursor, sync::LazyLock};
+use std::sync::Arc;
 
-use axum::{
-    body::Body,
-    response::{IntoResponse, Response},
+use image::{GenericImage, GenericImageView, ImageBuffer, ImageError, Rgba, RgbaImage};
+use rayon::{
+    iter::{IndexedParallelIterator, ParallelIterator},
+    slice::ParallelSliceMut,
 };
-use bytes::Bytes;
-use http::{
-    header::{CACHE_CONTROL, CONTENT_TYPE},
-    HeaderValue,
-};
-use image::{GenericImage, ImageError, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+use super::{format::EncodedImage, frame::RawFrames, timed};
+
+/// how frames get arranged on an atlas page
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum AtlasMode {
+    /// roughly square grid, pages are exactly as big as they need to be
+    #[default]
+    Grid,
+    /// grid with power of two page sizes, picking whichever column count
+    /// wastes the least space
+    PowerOfTwo,
+    /// every frame in a single row
+    Horizontal,
+    /// every frame in a single column
+    Vertical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct AtlasLayout {
+    pub mode: AtlasMode,
+    /// transparent pixels around every frame, so texture filtering doesn't
+    /// pick up the neighbouring ones
+    pub padding: u32,
+    /// fill the padding with each frame's edge pixels instead of leaving it
+    /// transparent
+    pub bleed: bool,
+    /// pages never get wider or taller than this, frames that don't fit get
+    /// put on more pages, unless a single frame is already too big
+    pub max_size: Option<u32>,
+}
+
+/// where frames go, same for every page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Placement {
+    columns: u32,
+    rows: u32,
+    pages: u32,
+    page_width: u32,
+    page_height: u32,
+}
+
+impl AtlasLayout {
+    fn place(&self, width: u32, height: u32, frame_count: u32) -> Placement {
+        let frame_count = frame_count.max(1);
+        let cell_width = width + self.padding * 2;
+        let cell_height = height + self.padding * 2;
+
+        let max_size = match (self.max_size, self.mode) {
+            // the page gets rounded up afterwards, so the space to fill has
+            // to be a power of two already
+            (Some(max), AtlasMode::PowerOfTwo) => prev_power_of_two(max),
+            (Some(max), _) => max,
+            (None, _) => u32::MAX,
+        };
+        let max_columns = (max_size / cell_width.max(1)).clamp(1, frame_count);
+        let max_rows = (max_size / cell_height.max(1)).clamp(1, frame_count);
+
+        let rows_for = |columns: u32| frame_count.div_ceil(columns).min(max_rows);
+
+        let (columns, rows) = match self.mode {
+            AtlasMode::Grid => {
+                let columns = (f64::from(frame_count).sqrt().ceil() as u32).min(max_columns);
+                (columns, rows_for(columns))
+            }
+            AtlasMode::PowerOfTwo => (1..=max_columns)
+                .map(|columns| (columns, rows_for(columns)))
+                .min_by_key(|&(columns, rows)| {
+                    let pages = frame_count.div_ceil(columns * rows);
+                    let width = (columns * cell_width).next_power_of_two();
+                    let height = (rows * cell_height).next_power_of_two();
+                    (
+                        u64::from(pages) * u64::from(width) * u64::from(height),
+                        pages,
+                        width.abs_diff(height),
+                    )
+                })
+                .expect("there's always at least one column"),
+            AtlasMode::Horizontal => (max_columns, 1),
+            AtlasMode::Vertical => (1, max_rows),
+        };
+
+        let (page_width, page_height) = match self.mode {
+            AtlasMode::PowerOfTwo => (
+                (columns * cell_width).next_power_of_two(),
+                (rows * cell_height).next_power_of_two(),
+            ),
+            _ => (columns * cell_width, rows * cell_height),
+        };
+
+        Placement {
+            columns,
+            rows,
+            pages: frame_count.div_ceil(columns * rows),
+            page_width,
+            page_height,
+        }
+    }
+}
+
+fn prev_power_of_two(n: u32) -> u32 {
+    match n {
+        0 => 0,
+        n => 1 << n.ilog2(),
+    }
+}
 
 #[derive(Clone)]
 pub struct AtlasTexture {
-    /// WebP encoded atlas image
-    pub data: Bytes,
+    pub pages: Arc<[EncodedImage]>,
     pub frame_count: u32,
+    /// columns on every page
     pub x_size: u32,
+    /// rows on every page
     pub y_size: u32,
+    pub page_width: u32,
+    pub page_height: u32,
+    pub layout: AtlasLayout,
 }
 
 impl std::fmt::Debug for AtlasTexture {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("AtlasTexture")
             .field(
-                "atlas",
-                &format!("bunch of bytes!!!, length: {}", self.data.len()),
+                "pages",
+                &format!("bunch of bytes!!!, pages: {}", self.pages.len()),
             )
             .field("frame_count", &self.frame_count)
             .field("x_size", &self.x_size)
             .field("y_size", &self.y_size)
+            .field("page_width", &self.page_width)
+            .field("page_height", &self.page_height)
+            .field("layout", &self.layout)
             .finish()
     }
 }
 
 impl AtlasTexture {
-    // TODO: make the atlas texture size always a power of two
-    pub fn new<'a>(
-        iter: impl IntoIterator<Item = &'a RgbaImage>,
-        width: u32,
-        height: u32,
-        frame_count: u32,
-    ) -> Result<Self, ImageError> {
-        let x_size = f64::from(frame_count).sqrt().ceil() as u32;
-        let y_size = {
-            let full_lines = frame_count / x_size;
-            if frame_count % x_size == 0 {
-                full_lines
-            } else {
-                full_lines + 1
-            }
-        };
-        let mut atlas = RgbaImage::new(width * x_size, height * y_size);
-        for (i, frame) in iter.into_iter().enumerate() {
-            let i = i as u32;
-            atlas.copy_from(frame, width * (i % x_size), height * (i / x_size))?
-        }
+    /// lays out every frame in `raw`, pages only get drawn once they're asked
+    /// for
+    pub fn new(raw: &Arc<RawFrames>, layout: &AtlasLayout) -> Self {
+        let frame_count = raw.len() as u32;
+        let placement = layout.place(raw.width(), raw.height(), frame_count);
 
-        let mut out = Cursor::new(Vec::new());
-        atlas.write_to(&mut out, image::ImageFormat::WebP)?;
+        let pages = (0..placement.pages)
+            .map(|page| {
+                let raw = raw.clone();
+                let layout = *layout;
+                EncodedImage::new(move || draw_page(&raw, &layout, &placement, page))
+            })
+            .collect();
 
-        Ok(Self {
-            data: Bytes::from(out.into_inner()),
+        Self {
+            pages,
             frame_count,
-            x_size,
-            y_size,
-        })
+            x_size: placement.columns,
+            y_size: placement.rows,
+            page_width: placement.page_width,
+            page_height: placement.page_height,
+            layout: *layout,
+        }
+    }
+
+    pub fn page(&self, page: usize) -> Option<AtlasPage> {
+        self.pages.get(page).cloned().map(AtlasPage)
     }
 }
 
-impl IntoResponse for AtlasTexture {
-    fn into_response(self) -> axum::response::Response {
-        static CACHE_HEADER: LazyLock<HeaderValue> = LazyLock::new(|| {
-            format!("max-age={}, public", { 60 * 60 * 15 })
-                .try_into()
-                .expect("oh no")
-        });
-
-        let mut resp = Response::new(Body::from(self.data));
-
-        resp.headers_mut().insert(
-            CONTENT_TYPE,
-            image::ImageFormat::WebP
-                .to_mime_type()
-                .try_into()
-                .expect("this should never fail erm"),
+fn draw_page(
+    raw: &RawFrames,
+    layout: &AtlasLayout,
+    placement: &Placement,
+    page: u32,
+) -> Result<RgbaImage, ImageError> {
+    let span = tracing::debug_span!(
+        "draw_atlas_page",
+        page,
+        width = placement.page_width,
+        height = placement.page_height,
+        millis = tracing::field::Empty
+    );
+    timed(span, || draw_page_untimed(raw, layout, placement, page))
+}
+
+/// every row of frames gets drawn on its own thread, into its own slice of
+/// the page
+fn draw_page_untimed(
+    raw: &RawFrames,
+    layout: &AtlasLayout,
+    placement: &Placement,
+    page: u32,
+) -> Result<RgbaImage, ImageError> {
+    let per_page = placement.columns * placement.rows;
+    let cell_width = raw.width() + layout.padding * 2;
+    let cell_height = raw.height() + layout.padding * 2;
+    let first = page * per_page;
+    let on_page = per_page.min(raw.len() as u32 - first);
+
+    let mut image = RgbaImage::new(placement.page_width, placement.page_height);
+    let row_bytes = placement.page_width as usize * cell_height as usize * 4;
+    image
+        .par_chunks_mut(row_bytes.max(1))
+        .take(on_page.div_ceil(placement.columns) as usize)
+        .enumerate()
+        .try_for_each(|(row, pixels)| {
+            let height = (pixels.len() / (placement.page_width as usize * 4)) as u32;
+            let mut band =
+                ImageBuffer::<Rgba<u8>, _>::from_raw(placement.page_width, height, pixels)
+                    .expect("bands are whole rows of pixels");
+            let row = row as u32;
+            for column in 0..placement.columns {
+                let i = row * placement.columns + column;
+                if i >= on_page {
+                    break;
+                }
+                let Some(frame) = raw.frame((first + i) as usize) else {
+                    break;
+                };
+                let x = cell_width * column;
+                if layout.bleed && layout.padding > 0 {
+                    bleed_into(&mut band, &frame, x, 0, layout.padding);
+                } else {
+                    band.copy_from(&frame, x + layout.padding, layout.padding)?;
+                }
+            }
+            Ok::<_, ImageError>(())
+        })?;
+    Ok(image)
+}
+
+/// draws `frame` inside its padding at `x`, `y`, with the padding filled by
+/// the closest edge pixel
+fn bleed_into(
+    page: &mut impl GenericImage<Pixel = Rgba<u8>>,
+    frame: &impl GenericImageView<Pixel = Rgba<u8>>,
+    x: u32,
+    y: u32,
+    padding: u32,
+) {
+    let (width, height) = frame.dimensions();
+    if width == 0 || height == 0 {
+        return;
+    }
+    for dy in 0..height + padding * 2 {
+        let src_y = dy.saturating_sub(padding).min(height - 1);
+        for dx in 0..width + padding * 2 {
+            let src_x = dx.saturating_sub(padding).min(width - 1);
+            page.put_pixel(x + dx, y + dy, frame.get_pixel(src_x, src_y));
+        }
+    }
+}
+
+/// a single atlas page
+#[derive(Clone)]
+pub struct AtlasPage(pub EncodedImage);
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used, reason = "bruh this is a tests module")]
+
+    use std::sync::Arc;
+
+    use image::{Rgba, RgbaImage};
+
+    use super::{AtlasLayout, AtlasMode, AtlasTexture};
+    use crate::emote::{frame::RawFrames, scheduler::DecodeScheduler};
+
+    fn frames(count: usize) -> Arc<RawFrames> {
+        let frames: Vec<_> = (0..count)
+            .map(|i| RgbaImage::from_pixel(10, 6, Rgba([i as u8, 0, 0, 255])))
+            .collect();
+        Arc::new(RawFrames::new(10, 6, frames.iter()).unwrap())
+    }
+
+    fn atlas(count: usize, layout: AtlasLayout) -> AtlasTexture {
+        AtlasTexture::new(&frames(count), &layout)
+    }
+
+    #[test]
+    fn layouts() {
+        let grid = atlas(7, AtlasLayout::default());
+        assert_eq!((grid.x_size, grid.y_size), (3, 3));
+        assert_eq!((grid.page_width, grid.page_height), (30, 18));
+        assert_eq!(grid.pages.len(), 1);
+
+        let pot = atlas(
+            7,
+            AtlasLayout {
+                mode: AtlasMode::PowerOfTwo,
+                padding: 1,
+                ..Default::default()
+            },
         );
+        assert!(pot.page_width.is_power_of_two() && pot.page_height.is_power_of_two());
+        assert!(pot.x_size * 12 <= pot.page_width && pot.y_size * 8 <= pot.page_height);
+        assert!(pot.x_size * pot.y_size >= 7);
 
-        resp.headers_mut()
-            .insert(CACHE_CONTROL, CACHE_HEADER.clone());
-        resp
+        let horizontal = atlas(
+            7,
+            AtlasLayout {
+                mode: AtlasMode::Horizontal,
+                ..Default::default()
+            },
+        );
+        assert_eq!((horizontal.page_width, horizontal.page_height), (70, 6));
+
+        let vertical = atlas(
+            7,
+            AtlasLayout {
+                mode: AtlasMode::Vertical,
+                ..Default::default()
+            },
+        );
+        assert_eq!((vertical.page_width, vertical.page_height), (10, 42));
+    }
+
+    #[test]
+    fn pages() {
+        let layout = AtlasLayout {
+            mode: AtlasMode::Horizontal,
+            max_size: Some(35),
+            ..Default::default()
+        };
+        let atlas = atlas(7, layout);
+        assert_eq!(atlas.x_size, 3);
+        assert_eq!(atlas.pages.len(), 3);
+        assert!(atlas.page_width <= 35);
+
+        let layout = AtlasLayout {
+            mode: AtlasMode::PowerOfTwo,
+            max_size: Some(40),
+            ..Default::default()
+        };
+        let atlas = AtlasTexture::new(&frames(20), &layout);
+        assert!(atlas.page_width <= 32 && atlas.page_height <= 32);
+        assert_eq!(
+            atlas.pages.len() as u32,
+            20u32.div_ceil(atlas.x_size * atlas.y_size)
+        );
+    }
+
+    #[test]
+    fn bleed() {
+        let layout = AtlasLayout {
+            padding: 2,
+            bleed: true,
+            ..Default::default()
+        };
+        let frames = frames(2);
+        let atlas = AtlasTexture::new(&frames, &layout);
+        let page = atlas.pages[0].render().unwrap();
+        // top left corner of the padding of the second frame
+        assert_eq!(page.get_pixel(14, 0), &Rgba([1, 0, 0, 255]));
+
+        let layout = AtlasLayout {
+            padding: 2,
+            ..Default::default()
+        };
+        let atlas = AtlasTexture::new(&frames, &layout);
+        let page = atlas.pages[0].render().unwrap();
+        assert_eq!(page.get_pixel(14, 0)[3], 0);
+        assert_eq!(page.get_pixel(16, 2), &Rgba([1, 0, 0, 255]));
+    }
+
+    #[test]
+    fn parallel() {
+        // power of two pages have space left at the bottom that isn't a whole
+        // row of frames
+        let layout = AtlasLayout {
+            mode: AtlasMode::PowerOfTwo,
+            padding: 1,
+            bleed: true,
+            ..Default::default()
+        };
+        let frames = frames(11);
+        let atlas = AtlasTexture::new(&frames, &layout);
+        let draw =
+            |threads| DecodeScheduler::new(threads).install(|| atlas.pages[0].render().unwrap());
+        let page = draw(4);
+        assert_eq!(page, draw(1));
+        assert!(atlas.y_size > 1 && atlas.page_height > atlas.y_size * 8);
+        let last = (10 % atlas.x_size * 12 + 1, 10 / atlas.x_size * 8 + 1);
+        assert_eq!(page.get_pixel(last.0, last.1), &Rgba([10, 0, 0, 255]));
     }
 }
@@ -0,0 +1,287 @@
+use std::{
+    io::Cursor,
+    sync::{Arc, LazyLock, OnceLock},
+};
+
+use axum::{
+    body::Body,
+    extract::FromRequestParts,
+    response::{IntoResponse, Response},
+};
+use bytes::Bytes;
+use http::{
+    header::{ACCEPT, CACHE_CONTROL, CONTENT_TYPE, VARY},
+    request::Parts,
+    HeaderValue,
+};
+use image::ImageError;
+
+use super::timed;
+
+/// formats frames and atlases can be served in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutputFormat {
+    WebP,
+    Png,
+    Avif,
+    Gif,
+}
+
+impl OutputFormat {
+    /// in order of preference when a client is fine with more than one
+    pub const ALL: [Self; 4] = [Self::WebP, Self::Png, Self::Avif, Self::Gif];
+
+    pub fn image_format(self) -> image::ImageFormat {
+        match self {
+            OutputFormat::WebP => image::ImageFormat::WebP,
+            OutputFormat::Png => image::ImageFormat::Png,
+            OutputFormat::Avif => image::ImageFormat::Avif,
+            OutputFormat::Gif => image::ImageFormat::Gif,
+        }
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        self.image_format().to_mime_type()
+    }
+
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext.to_lowercase().as_str() {
+            "webp" => Some(Self::WebP),
+            "png" => Some(Self::Png),
+            "avif" => Some(Self::Avif),
+            "gif" => Some(Self::Gif),
+            _ => None,
+        }
+    }
+
+    /// picks whatever the `Accept` header likes best, types listed outright
+    /// beat wildcards and ties go by [`Self::ALL`], WebP if nothing fits
+    pub fn negotiate(accept: &str) -> Self {
+        let mut explicit = [None; 4];
+        let mut wildcard: Option<f32> = None;
+
+        for entry in accept.split(',') {
+            let mut params = entry.split(';');
+            let mime = params.next().unwrap_or_default().trim().to_lowercase();
+            let q = params
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            match mime.as_str() {
+                "image/*" | "*/*" => wildcard = Some(wildcard.map_or(q, |w| w.max(q))),
+                mime => {
+                    if let Some(i) = Self::ALL.iter().position(|f| f.mime_type() == mime) {
+                        explicit[i] = Some(q);
+                    }
+                }
+            }
+        }
+
+        Self::ALL
+            .iter()
+            .enumerate()
+            .filter_map(|(i, format)| {
+                let (q, is_explicit) = match explicit[i] {
+                    Some(q) => (q, true),
+                    None => (wildcard?, false),
+                };
+                (q > 0.0).then_some((q, is_explicit, i, *format))
+            })
+            .max_by(|a, b| {
+                a.0.total_cmp(&b.0)
+                    .then(a.1.cmp(&b.1))
+                    // earlier in ALL is better
+                    .then(b.2.cmp(&a.2))
+            })
+            .map(|(.., format)| format)
+            .unwrap_or(Self::WebP)
+    }
+}
+
+/// format picked from the request's `Accept` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcceptFormat(pub OutputFormat);
+
+#[axum::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AcceptFormat {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self(
+            parts
+                .headers
+                .get(ACCEPT)
+                .and_then(|h| h.to_str().ok())
+                .map(OutputFormat::negotiate)
+                .unwrap_or(OutputFormat::WebP),
+        ))
+    }
+}
+
+type Render = dyn Fn() -> Result<image::RgbaImage, ImageError> + Send + Sync;
+
+/// an image that only gets drawn and encoded once someone asks for it, every
+/// format sticks around after the first time
+#[derive(Clone)]
+pub struct EncodedImage {
+    render: Arc<Render>,
+    /// in [`OutputFormat::ALL`] order
+    encoded: Arc<[OnceLock<Bytes>; 4]>,
+}
+
+impl EncodedImage {
+    pub fn new(
+        render: impl Fn() -> Result<image::RgbaImage, ImageError> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            render: Arc::new(render),
+            encoded: Default::default(),
+        }
+    }
+
+    pub fn from_image(image: image::RgbaImage) -> Self {
+        Self::new(move || Ok(image.clone()))
+    }
+
+    /// the pixels, before any encoding
+    pub fn render(&self) -> Result<image::RgbaImage, ImageError> {
+        (self.render)()
+    }
+
+    fn slot(&self, format: OutputFormat) -> &OnceLock<Bytes> {
+        match format {
+            OutputFormat::WebP => &self.encoded[0],
+            OutputFormat::Png => &self.encoded[1],
+            OutputFormat::Avif => &self.encoded[2],
+            OutputFormat::Gif => &self.encoded[3],
+        }
+    }
+
+    /// the image as `format`, without encoding anything, if it's there already
+    pub fn get(&self, format: OutputFormat) -> Option<Bytes> {
+        self.slot(format).get().cloned()
+    }
+
+    /// bytes of every format that's been encoded so far
+    pub fn encoded_size(&self) -> usize {
+        self.encoded
+            .iter()
+            .filter_map(OnceLock::get)
+            .map(Bytes::len)
+            .sum()
+    }
+
+    /// keeps `data` as the image in `format`, unless there's one already
+    pub fn insert(&self, format: OutputFormat, data: Bytes) {
+        let _ = self.slot(format).set(data);
+    }
+
+    /// the image as `format`, encoding it right here if it isn't cached yet
+    pub fn encode(&self, format: OutputFormat) -> Result<Bytes, ImageError> {
+        if let Some(hit) = self.get(format) {
+            return Ok(hit);
+        }
+
+        let image = self.render()?;
+        let span = tracing::debug_span!(
+            "encode_image",
+            ?format,
+            width = image.width(),
+            height = image.height(),
+            millis = tracing::field::Empty
+        );
+        let mut out = Cursor::new(Vec::new());
+        timed(span, || image.write_to(&mut out, format.image_format()))?;
+
+        // if someone else got there first it's the same thing anyway
+        Ok(self
+            .slot(format)
+            .get_or_init(|| out.into_inner().into())
+            .clone())
+    }
+}
+
+/// an image ready to be sent, `negotiated` is whether the format came from
+/// `Accept`, so caches know the response depends on it
+pub struct EncodedResponse {
+    pub data: Bytes,
+    pub format: OutputFormat,
+    pub negotiated: bool,
+}
+
+impl IntoResponse for EncodedResponse {
+    fn into_response(self) -> Response {
+        static CACHE_HEADER: LazyLock<HeaderValue> = LazyLock::new(|| {
+            format!("max-age={}, public", { 60 * 60 * 15 })
+                .try_into()
+                .expect("oh no")
+        });
+
+        let mut resp = Response::new(Body::from(self.data));
+        resp.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static(self.format.mime_type()),
+        );
+        resp.headers_mut()
+            .insert(CACHE_CONTROL, CACHE_HEADER.clone());
+        if self.negotiated {
+            resp.headers_mut()
+                .insert(VARY, HeaderValue::from_static("accept"));
+        }
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used, reason = "bruh this is a tests module")]
+
+    use image::{Rgba, RgbaImage};
+
+    use super::OutputFormat;
+    use crate::emote::{resolution::Resolution, Emote, EmoteOptions};
+
+    #[test]
+    fn negotiate() {
+        let n = OutputFormat::negotiate;
+        assert_eq!(n(""), OutputFormat::WebP);
+        assert_eq!(n("image/png"), OutputFormat::Png);
+        assert_eq!(n("image/avif,image/webp,*/*;q=0.8"), OutputFormat::WebP);
+        assert_eq!(n("image/png, */*"), OutputFormat::Png);
+        assert_eq!(n("image/webp;q=0, image/*;q=0.5"), OutputFormat::Png);
+        assert_eq!(n("image/gif;q=0.9, image/avif"), OutputFormat::Avif);
+        assert_eq!(n("text/html"), OutputFormat::WebP);
+    }
+
+    #[tokio::test]
+    async fn encode() {
+        let mut png = std::io::Cursor::new(Vec::new());
+        RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255]))
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        let emote = Emote::try_new(
+            png.get_ref(),
+            image::ImageFormat::Png,
+            "test",
+            Resolution::default(),
+            None,
+            &EmoteOptions::default(),
+        )
+        .unwrap();
+        let image = emote.frames[0].image();
+
+        // nothing gets encoded until it's asked for
+        assert!(image.get(OutputFormat::WebP).is_none());
+        for format in OutputFormat::ALL {
+            let data = emote.encode(image, format).await.unwrap();
+            if format == OutputFormat::Avif {
+                // image can't sniff AVIF without the decoder
+                assert_eq!(&data[4..12], b"ftypavif");
+            } else {
+                assert_eq!(image::guess_format(&data).unwrap(), format.image_format());
+            }
+            assert!(image.get(format).is_some());
+        }
+    }
+}
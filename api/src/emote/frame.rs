@@ -1,46 +1,111 @@
-use std::{io::Cursor, sync::LazyLock};
+use std::sync::Arc;
 
-use axum::{
-    body::Body,
-    response::{IntoResponse, Response},
+use image::{
+    error::{ParameterError, ParameterErrorKind},
+    ImageBuffer, ImageError, Rgba, RgbaImage,
 };
-use bytes::Bytes;
-use http::{
-    header::{CACHE_CONTROL, CONTENT_TYPE},
-    HeaderValue,
-};
-use image::DynamicImage;
 
-use crate::emote::DEFAULT_IMAGE_FORMAT;
+use crate::emote::format::EncodedImage;
 
-#[derive(Clone)]
-pub struct Frame {
-    pub delay: f64,
-    data: Bytes,
+/// every decoded frame of an emote, back to back in a single buffer, which
+/// is what frames and atlases get drawn from when they're first needed
+pub struct RawFrames {
+    width: u32,
+    height: u32,
+    frame_count: usize,
+    pixels: Box<[u8]>,
+}
+
+impl std::fmt::Debug for RawFrames {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RawFrames")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("frame_count", &self.frame_count)
+            .finish_non_exhaustive()
+    }
 }
 
-impl IntoResponse for Frame {
-    fn into_response(self) -> axum::response::Response {
-        static CACHE_HEADER: LazyLock<HeaderValue> = LazyLock::new(|| {
-            format!("max-age={}, public", { 60 * 60 * 15 })
-                .try_into()
-                .expect("oh no")
-        });
-
-        let mut resp = Response::new(Body::from(self.data));
-        resp.headers_mut().insert(
-            CONTENT_TYPE,
-            DEFAULT_IMAGE_FORMAT
-                .to_mime_type()
-                .try_into()
-                .expect("this should never fail erm"),
-        );
-        resp.headers_mut()
-            .insert(CACHE_CONTROL, CACHE_HEADER.clone());
-        resp
+impl RawFrames {
+    /// every frame has to be `width` x `height`
+    pub fn new<'a>(
+        width: u32,
+        height: u32,
+        frames: impl IntoIterator<Item = &'a RgbaImage>,
+    ) -> Result<Self, ImageError> {
+        let mut pixels = Vec::new();
+        let mut frame_count = 0;
+        for frame in frames {
+            if frame.dimensions() != (width, height) {
+                return Err(ImageError::Parameter(ParameterError::from_kind(
+                    ParameterErrorKind::DimensionMismatch,
+                )));
+            }
+            pixels.extend_from_slice(frame.as_raw());
+            frame_count += 1;
+        }
+
+        Ok(Self {
+            width,
+            height,
+            frame_count,
+            pixels: pixels.into(),
+        })
+    }
+
+    /// frames from a buffer laid out like [`RawFrames::pixels`], which has to
+    /// hold a whole number of them
+    pub fn from_pixels(width: u32, height: u32, pixels: Box<[u8]>) -> Result<Self, ImageError> {
+        let size = width as usize * height as usize * 4;
+        if size == 0 || !pixels.len().is_multiple_of(size) {
+            return Err(ImageError::Parameter(ParameterError::from_kind(
+                ParameterErrorKind::DimensionMismatch,
+            )));
+        }
+
+        Ok(Self {
+            width,
+            height,
+            frame_count: pixels.len() / size,
+            pixels,
+        })
+    }
+
+    /// every frame, RGBA, one after the other
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn len(&self) -> usize {
+        self.frame_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frame_count == 0
+    }
+
+    /// a view into frame `index` without copying it out
+    pub fn frame(&self, index: usize) -> Option<ImageBuffer<Rgba<u8>, &[u8]>> {
+        let size = self.width as usize * self.height as usize * 4;
+        let pixels = self.pixels.get(index * size..(index + 1) * size)?;
+        ImageBuffer::from_raw(self.width, self.height, pixels)
     }
 }
 
+#[derive(Clone)]
+pub struct Frame {
+    pub delay: f64,
+    data: EncodedImage,
+}
+
 impl std::fmt::Debug for Frame {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Frame")
@@ -51,38 +116,36 @@ impl std::fmt::Debug for Frame {
 }
 
 impl Frame {
-    pub fn try_from_iter<'a>(
-        iter: impl IntoIterator<Item = &'a image::Frame>,
-    ) -> Result<Vec<Self>, image::ImageError> {
-        let mut frames = Vec::new();
-        for frame in iter {
-            let mut buf = Cursor::new(Vec::new());
-            frame.buffer().write_to(&mut buf, DEFAULT_IMAGE_FORMAT)?;
-            let buf = buf.into_inner();
-
-            // i love coding
-            let delay = std::time::Duration::from(frame.delay()).as_secs_f64();
-
-            frames.push(Frame {
-                delay,
-                data: buf.into(),
-            });
+    /// frame `index` of `raw`, which doesn't get encoded until it's needed
+    pub fn new(raw: Arc<RawFrames>, index: usize, delay: f64) -> Self {
+        Self {
+            delay,
+            data: EncodedImage::new(move || {
+                let frame = raw.frame(index).ok_or_else(|| {
+                    ImageError::Parameter(ParameterError::from_kind(
+                        ParameterErrorKind::DimensionMismatch,
+                    ))
+                })?;
+                Ok(
+                    RgbaImage::from_raw(frame.width(), frame.height(), frame.into_raw().to_vec())
+                        .expect("same size as the view"),
+                )
+            }),
         }
-        Ok(frames)
     }
-}
-
-impl TryFrom<&DynamicImage> for Frame {
-    type Error = image::ImageError;
 
-    fn try_from(value: &DynamicImage) -> Result<Self, Self::Error> {
-        let mut buf = Cursor::new(Vec::new());
-        value.write_to(&mut buf, DEFAULT_IMAGE_FORMAT)?;
-        let buf = buf.into_inner();
+    /// every frame in `raw`, lasting `delays` seconds each
+    pub fn from_raw(raw: &Arc<RawFrames>, delays: impl IntoIterator<Item = f64>) -> Vec<Self> {
+        delays
+            .into_iter()
+            .take(raw.len())
+            .enumerate()
+            .map(|(i, delay)| Self::new(raw.clone(), i, delay))
+            .collect()
+    }
 
-        Ok(Self {
-            delay: f64::MAX,
-            data: buf.into(),
-        })
+    /// the frame in every format it can be served in
+    pub fn image(&self) -> &EncodedImage {
+        &self.data
     }
 }
@@ -0,0 +1,173 @@
+use std::fmt::Display;
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use super::EmoteError;
+
+/// how much a single emote is allowed to cost, so a malicious upload on some
+/// platform can't eat all the memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DecodeLimits {
+    /// what the platform sends, before decoding
+    pub max_download_bytes: u64,
+    /// widest or tallest an emote can be, in pixels
+    pub max_dimension: u32,
+    pub max_frames: u32,
+    /// every frame decoded together, at 4 bytes per pixel
+    pub max_decoded_bytes: u64,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_download_bytes: 16 * 1024 * 1024,
+            max_dimension: 4096,
+            max_frames: 1024,
+            max_decoded_bytes: 512 * 1024 * 1024,
+        }
+    }
+}
+
+/// which of the [`DecodeLimits`] an emote went over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Limit {
+    DownloadSize,
+    Dimensions,
+    FrameCount,
+    DecodedSize,
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Limit::DownloadSize => "download size",
+            Limit::Dimensions => "dimension",
+            Limit::FrameCount => "frame count",
+            Limit::DecodedSize => "decoded size",
+        })
+    }
+}
+
+impl DecodeLimits {
+    /// the same limits for image's own decoders
+    pub fn image_limits(&self) -> image::Limits {
+        let mut limits = image::Limits::default();
+        limits.max_image_width = Some(self.max_dimension);
+        limits.max_image_height = Some(self.max_dimension);
+        limits.max_alloc = Some(self.max_decoded_bytes);
+        limits
+    }
+
+    /// reads the whole body, giving up as soon as it gets too big instead of
+    /// buffering all of it first
+    pub async fn read_body(&self, mut resp: reqwest::Response) -> Result<Bytes, EmoteError> {
+        let too_big = || EmoteError::LimitExceeded(Limit::DownloadSize);
+        if resp
+            .content_length()
+            .is_some_and(|len| len > self.max_download_bytes)
+        {
+            return Err(too_big());
+        }
+
+        let mut body = Vec::new();
+        while let Some(chunk) = resp.chunk().await? {
+            if (body.len() + chunk.len()) as u64 > self.max_download_bytes {
+                return Err(too_big());
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body.into())
+    }
+
+    /// collects decoded frames, stopping at the first one that goes over the
+    /// frame count or decoded size
+    pub fn collect_frames(
+        &self,
+        frames: image::Frames,
+        mut map: impl FnMut(image::Frame) -> image::Frame,
+    ) -> Result<Vec<image::Frame>, EmoteError> {
+        let mut collected = Vec::new();
+        let mut decoded_bytes = 0u64;
+        for frame in frames {
+            let frame = frame?;
+            if collected.len() as u32 >= self.max_frames {
+                return Err(EmoteError::LimitExceeded(Limit::FrameCount));
+            }
+            let (width, height) = frame.buffer().dimensions();
+            if width.max(height) > self.max_dimension {
+                return Err(EmoteError::LimitExceeded(Limit::Dimensions));
+            }
+            decoded_bytes += frame.buffer().as_raw().len() as u64;
+            if decoded_bytes > self.max_decoded_bytes {
+                return Err(EmoteError::LimitExceeded(Limit::DecodedSize));
+            }
+            collected.push(map(frame));
+        }
+        Ok(collected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used, reason = "bruh this is a tests module")]
+
+    use std::io::Cursor;
+
+    use image::{codecs::gif::GifDecoder, AnimationDecoder};
+
+    use super::{DecodeLimits, Limit};
+    use crate::emote::{resolution::Resolution, test_gif, Emote, EmoteError, EmoteOptions};
+
+    fn limit(result: Result<Emote, EmoteError>) -> Option<Limit> {
+        match result {
+            Err(EmoteError::LimitExceeded(limit)) => Some(limit),
+            _ => None,
+        }
+    }
+
+    fn decode(data: &[u8], limits: DecodeLimits) -> Result<Emote, EmoteError> {
+        Emote::try_new(
+            data,
+            image::ImageFormat::Gif,
+            "test",
+            Resolution::default(),
+            None,
+            &EmoteOptions {
+                limits,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn limits() {
+        let data = test_gif(5, 8, 8);
+        assert!(decode(&data, DecodeLimits::default()).is_ok());
+
+        let frames = DecodeLimits {
+            max_frames: 4,
+            ..Default::default()
+        };
+        assert_eq!(limit(decode(&data, frames)), Some(Limit::FrameCount));
+
+        let dimensions = DecodeLimits {
+            max_dimension: 7,
+            ..Default::default()
+        };
+        assert_eq!(limit(decode(&data, dimensions)), Some(Limit::Dimensions));
+
+        // 4 frames fit, the 5th doesn't
+        let decoded = DecodeLimits {
+            max_decoded_bytes: 8 * 8 * 4 * 4,
+            ..Default::default()
+        };
+        assert_eq!(limit(decode(&data, decoded)), Some(Limit::DecodedSize));
+
+        let frames = GifDecoder::new(Cursor::new(&data)).unwrap().into_frames();
+        let collected = DecodeLimits::default()
+            .collect_frames(frames, |f| f)
+            .unwrap();
+        assert_eq!(collected.len(), 5);
+    }
+}
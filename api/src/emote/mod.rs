@@ -1,22 +1,59 @@
-use std::{io::Cursor, sync::Arc};
+use std::{
+    io::Cursor,
+    panic::AssertUnwindSafe,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use atlas::AtlasTexture;
-use frame::Frame;
+use animated::{AnimatedCache, AnimatedFormat};
+use atlas::{AtlasLayout, AtlasMode, AtlasTexture};
+use bytes::Bytes;
+use format::{EncodedImage, OutputFormat};
+use frame::{Frame, RawFrames};
 use http::HeaderValue;
-use image::AnimationDecoder;
+use image::{error::LimitErrorKind, imageops::FilterType, AnimationDecoder, Delay, ImageDecoder};
+use limits::{DecodeLimits, Limit};
+use resolution::{Downscale, Resolution};
+use scheduler::DecodeScheduler;
 use serde::Serialize;
+use worker::{Decoder, WorkerError};
 
-use crate::platforms::{channel::ChannelEmote, Platform};
+use crate::{
+    cache::MemorySize,
+    platforms::{channel::ChannelEmote, twitch::TwitchEmoteMetadata, Platform},
+};
 
+pub mod animated;
 pub mod atlas;
+pub mod format;
 pub mod frame;
+pub mod limits;
+pub mod resolution;
+pub mod scheduler;
+pub mod worker;
 
 pub const DEFAULT_IMAGE_FORMAT: image::ImageFormat = image::ImageFormat::WebP;
 
+/// everything about how emotes get turned into frames and atlases
+#[derive(Debug, Clone, Default)]
+pub struct EmoteOptions {
+    pub atlas: AtlasLayout,
+    pub limits: DecodeLimits,
+    /// merge consecutive frames that look exactly the same, lots of GIFs
+    /// pad out pauses like that
+    pub dedup_frames: bool,
+    pub decoder: Decoder,
+    /// shared by everything that decodes or encodes, so it's the only limit
+    /// on how much of that runs at once
+    pub scheduler: DecodeScheduler,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum EmoteError {
     #[error(transparent)]
-    ImageError(#[from] image::ImageError),
+    ImageError(image::ImageError),
+    #[error("emote is over the {0} limit")]
+    LimitExceeded(Limit),
     #[error(transparent)]
     RequestError(#[from] reqwest::Error),
     #[error("the provided url was not valid")]
@@ -25,28 +62,180 @@ pub enum EmoteError {
     WrongMimeType(HeaderValue),
     #[error("request did not contain proper headers or wasn't a valid image")]
     UnableToDetermineFormat,
+    #[error("emote doesn't have any frames or pixels")]
+    Empty,
+    #[error("something panicked while working on the emote")]
+    Panicked,
+    #[error("decode worker {0}")]
+    Worker(#[from] WorkerError),
+}
+
+impl From<image::ImageError> for EmoteError {
+    /// image's own limits count as ours too
+    fn from(value: image::ImageError) -> Self {
+        match value {
+            image::ImageError::Limits(e) => match e.kind() {
+                LimitErrorKind::DimensionError => Self::LimitExceeded(Limit::Dimensions),
+                _ => Self::LimitExceeded(Limit::DecodedSize),
+            },
+            e => Self::ImageError(e),
+        }
+    }
+}
+
+/// runs `f` inside `span`, recording how long it took in its `millis` field
+pub(crate) fn timed<T>(span: tracing::Span, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let out = span.in_scope(f);
+    span.record("millis", start.elapsed().as_secs_f64() * 1000.0);
+    out
+}
+
+/// what's left of an emote after decoding, before anything gets encoded
+#[derive(Debug)]
+pub struct Decoded {
+    pub raw: RawFrames,
+    /// in seconds, one for every frame
+    pub delays: Vec<f64>,
+    pub animated: bool,
 }
 
 // AWFUL code
 // TODO: make it less awful
-fn atlas_and_frames_from_iter(
+fn decode_frames(
     frames: image::Frames,
-) -> Result<(AtlasTexture, Vec<Frame>, u32, u32), EmoteError> {
-    let collected_iter = frames.into_iter().collect_frames()?;
-    let (width, height) = {
-        let first = collected_iter.first().unwrap().buffer();
-        (first.width(), first.height())
-    };
+    options: &EmoteOptions,
+    downscale: Option<Downscale>,
+) -> Result<Decoded, EmoteError> {
+    // shrinking as they come in so the full size ones don't all pile up
+    let mut collected_iter = options.limits.collect_frames(frames, |f| match downscale {
+        Some(downscale) => {
+            let (width, height) = downscale.apply(f.buffer().width(), f.buffer().height());
+            let delay = f.delay();
+            let resized = image::imageops::resize(f.buffer(), width, height, FilterType::Lanczos3);
+            image::Frame::from_parts(resized, 0, 0, delay)
+        }
+        None => f,
+    })?;
+    if options.dedup_frames {
+        collected_iter = dedup_frames(collected_iter);
+    }
+    let (width, height) = collected_iter
+        .first()
+        .map(|f| f.buffer().dimensions())
+        .ok_or(EmoteError::Empty)?;
+    if width == 0 || height == 0 {
+        return Err(EmoteError::Empty);
+    }
+
+    let raw = RawFrames::new(width, height, collected_iter.iter().map(|f| f.buffer()))?;
+    // i love coding
+    let delays = collected_iter
+        .iter()
+        .map(|f| Duration::from(f.delay()).as_secs_f64())
+        .collect();
+
+    Ok(Decoded {
+        raw,
+        delays,
+        animated: true,
+    })
+}
+
+/// a single frame that lasts forever
+fn decode_still(image: &image::DynamicImage) -> Result<Decoded, EmoteError> {
+    let image = image.to_rgba8();
+    if image.width() == 0 || image.height() == 0 {
+        return Err(EmoteError::Empty);
+    }
+    Ok(Decoded {
+        raw: RawFrames::new(image.width(), image.height(), [&image])?,
+        delays: vec![f64::MAX],
+        animated: false,
+    })
+}
 
-    let frames = Frame::try_from_iter(collected_iter.iter())?;
-    let atlas = AtlasTexture::new(
-        collected_iter.iter().map(|f| f.buffer()),
-        width,
-        height,
-        collected_iter.len() as u32,
-    )?;
+/// decodes `data` as `format` and shrinks it if needed, this is the part that
+/// deals with untrusted input and can run in a worker process
+pub fn decode(
+    data: &[u8],
+    format: image::ImageFormat,
+    downscale: Option<Downscale>,
+    options: &EmoteOptions,
+) -> Result<Decoded, EmoteError> {
+    let span = tracing::debug_span!(
+        "decode",
+        ?format,
+        bytes = data.len(),
+        millis = tracing::field::Empty
+    );
+    timed(span, || {
+        // some decoders still panic on broken files instead of erroring
+        std::panic::catch_unwind(AssertUnwindSafe(|| {
+            decode_untimed(data, format, downscale, options)
+        }))
+        .unwrap_or(Err(EmoteError::Panicked))
+    })
+}
+
+fn decode_untimed(
+    data: &[u8],
+    format: image::ImageFormat,
+    downscale: Option<Downscale>,
+    options: &EmoteOptions,
+) -> Result<Decoded, EmoteError> {
+    use image::ImageFormat as Format;
+    let resize = |decoded: image::DynamicImage| match downscale {
+        Some(downscale) => {
+            let (width, height) = downscale.apply(decoded.width(), decoded.height());
+            decoded.resize_exact(width, height, FilterType::Lanczos3)
+        }
+        None => decoded,
+    };
+    let read_still = |format| {
+        let mut reader = image::ImageReader::with_format(Cursor::new(data), format);
+        reader.limits(options.limits.image_limits());
+        reader.decode()
+    };
+    match format {
+        Format::Gif => {
+            let mut decoder = image::codecs::gif::GifDecoder::new(Cursor::new(data))?;
+            decoder.set_limits(options.limits.image_limits())?;
+            decode_frames(decoder.into_frames(), options, downscale)
+        }
+        Format::WebP => {
+            let mut decoder = image::codecs::webp::WebPDecoder::new(Cursor::new(data))?;
+            decoder.set_limits(options.limits.image_limits())?;
+            if decoder.has_animation() {
+                decoder.set_background_color(image::Rgba([0; 4]))?;
+                decode_frames(decoder.into_frames(), options, downscale)
+            } else {
+                decode_still(&resize(read_still(Format::WebP)?))
+            }
+        }
+        f => decode_still(&resize(read_still(f)?)),
+    }
+}
 
-    Ok((atlas, frames, width, height))
+/// merges runs of pixel identical frames into their first one, which then
+/// lasts as long as the whole run did
+fn dedup_frames(frames: Vec<image::Frame>) -> Vec<image::Frame> {
+    let mut deduped: Vec<image::Frame> = Vec::with_capacity(frames.len());
+    for frame in frames {
+        match deduped.last_mut() {
+            Some(last) if last.buffer() == frame.buffer() => {
+                let delay = Duration::from(last.delay()) + Duration::from(frame.delay());
+                *last = image::Frame::from_parts(
+                    std::mem::take(last.buffer_mut()),
+                    last.left(),
+                    last.top(),
+                    Delay::from_saturating_duration(delay),
+                );
+            }
+            _ => deduped.push(frame),
+        }
+    }
+    deduped
 }
 
 #[derive(Debug, Clone)]
@@ -56,6 +245,12 @@ pub struct Emote {
     pub height: u32,
     pub frames: Arc<[Frame]>,
     pub atlas: Option<AtlasTexture>,
+    pub resolution: Resolution,
+    /// every frame's pixels, the frames and atlas pages get drawn from these
+    raw: Arc<RawFrames>,
+    animated: AnimatedCache,
+    /// what encoding happens on, same as for decoding
+    scheduler: DecodeScheduler,
 }
 
 impl Emote {
@@ -63,78 +258,138 @@ impl Emote {
         data: &[u8],
         format: image::ImageFormat,
         id: impl Into<Arc<str>>,
+        resolution: Resolution,
+        downscale: Option<Downscale>,
+        options: &EmoteOptions,
     ) -> Result<Self, EmoteError> {
-        use image::ImageFormat as Format;
-        let (atlas, frames, width, height) = match format {
-            Format::Gif => {
-                let decoder = image::codecs::gif::GifDecoder::new(Cursor::new(data))?;
-                let (atlas, frames, width, height) =
-                    atlas_and_frames_from_iter(decoder.into_frames())?;
-                (Some(atlas), frames, width, height)
-            }
-            Format::WebP => {
-                let mut decoder = image::codecs::webp::WebPDecoder::new(Cursor::new(data))?;
-                if decoder.has_animation() {
-                    decoder.set_background_color(image::Rgba([0; 4]))?;
-                    let (atlas, frames, width, height) =
-                        atlas_and_frames_from_iter(decoder.into_frames())?;
-                    (Some(atlas), frames, width, height)
-                } else {
-                    let decoded = image::load_from_memory_with_format(data, Format::WebP)?;
-                    let frame = Frame::try_from(&decoded)?;
-                    (None, vec![frame], decoded.width(), decoded.height())
-                }
-            }
-            f => {
-                let decoded = image::load_from_memory_with_format(data, f)?;
-                let frame = Frame::try_from(&decoded)?;
-                (None, vec![frame], decoded.width(), decoded.height())
-            }
-        };
+        let decoded = decode(data, format, downscale, options)?;
+        Ok(Self::from_decoded(decoded, id, resolution, options))
+    }
+
+    /// frames and the atlas for something that's been decoded already,
+    /// nothing gets encoded until it's asked for
+    pub fn from_decoded(
+        decoded: Decoded,
+        id: impl Into<Arc<str>>,
+        resolution: Resolution,
+        options: &EmoteOptions,
+    ) -> Self {
+        let raw = Arc::new(decoded.raw);
+        let frames = Frame::from_raw(&raw, decoded.delays);
+        let atlas = decoded
+            .animated
+            .then(|| AtlasTexture::new(&raw, &options.atlas));
 
-        Ok(Self {
+        Self {
             id: id.into(),
-            width,
-            height,
+            width: raw.width(),
+            height: raw.height(),
             frames: frames.into(),
             atlas,
-        })
+            resolution,
+            raw,
+            animated: AnimatedCache::default(),
+            scheduler: options.scheduler.clone(),
+        }
+    }
+
+    /// `image` as `format`, if it's one of this emote's, encoded with the
+    /// emote's scheduler the first time
+    pub async fn encode(
+        &self,
+        image: &EncodedImage,
+        format: OutputFormat,
+    ) -> Result<Bytes, EmoteError> {
+        if let Some(hit) = image.get(format) {
+            return Ok(hit);
+        }
+
+        let image = image.clone();
+        Ok(self
+            .scheduler
+            .run_blocking(move || image.encode(format))
+            .await??)
+    }
+
+    /// every frame put back together into a single animated file, built on a
+    /// blocking thread the first time and cached with the emote after that
+    pub async fn animated(&self, format: AnimatedFormat) -> Result<Bytes, EmoteError> {
+        let slot = self.animated.slot(format);
+        if let Some(hit) = slot.get() {
+            return Ok(hit.clone());
+        }
+
+        let frames = self.frames.clone();
+        let (width, height) = (self.width, self.height);
+        let encoded = self
+            .scheduler
+            .run_blocking(move || animated::encode(&frames, width, height, format))
+            .await??;
+
+        Ok(slot.get_or_init(|| encoded).clone())
     }
 
     pub async fn try_from_response(
         resp: reqwest::Response,
         id: impl Into<Arc<str>>,
+        resolution: Resolution,
+        downscale: Option<Downscale>,
+        options: &EmoteOptions,
     ) -> Result<Self, EmoteError> {
-        let bytes;
-
-        // either take from the headers or guess with magic bytes (because of
-        // fucking OpieOP emote and other weird twitch emotes)
-        let format = resp
+        let header_format = resp
             .headers()
             .get(reqwest::header::CONTENT_TYPE)
-            .and_then(|h| image::ImageFormat::from_mime_type(String::from_utf8_lossy(h.as_bytes())))
-            .or_else({
-                bytes = resp.bytes().await?;
-                || {
-                    image::ImageReader::new(Cursor::new(&bytes))
-                        .with_guessed_format()
-                        .ok()?
-                        .format()
-                }
+            .and_then(|h| {
+                image::ImageFormat::from_mime_type(String::from_utf8_lossy(h.as_bytes()))
             });
+        let bytes = options.limits.read_body(resp).await?;
 
-        if let Some(format) = format {
-            // wow that looks awful
-            let id = Into::<Arc<str>>::into(id);
+        // either take from the headers or guess with magic bytes (because of
+        // fucking OpieOP emote and other weird twitch emotes)
+        let format = header_format.or_else(|| {
+            image::ImageReader::new(Cursor::new(&bytes))
+                .with_guessed_format()
+                .ok()?
+                .format()
+        });
 
-            let emote = tokio::task::spawn_blocking(move || Emote::try_new(&bytes, format, id))
-                .await
-                .expect("what.")?;
+        let Some(format) = format else {
+            return Err(EmoteError::UnableToDetermineFormat);
+        };
 
-            Ok(emote)
-        } else {
-            Err(EmoteError::UnableToDetermineFormat)
-        }
+        let decoded = match &options.decoder {
+            Decoder::InProcess => {
+                let options = options.clone();
+                options
+                    .scheduler
+                    .clone()
+                    .run_blocking(move || decode(&bytes, format, downscale, &options))
+                    .await??
+            }
+            Decoder::Workers(pool) => {
+                options
+                    .scheduler
+                    .run(pool.decode(bytes, format, downscale, options))
+                    .await?
+            }
+        };
+
+        Ok(Self::from_decoded(decoded, id, resolution, options))
+    }
+}
+
+impl MemorySize for Emote {
+    /// the pixels plus everything that's been encoded so far, so it keeps
+    /// growing as more formats get asked for
+    fn memory_size(&self) -> usize {
+        let frames: usize = self.frames.iter().map(|f| f.image().encoded_size()).sum();
+        let pages: usize = self
+            .atlas
+            .iter()
+            .flat_map(|a| a.pages.iter())
+            .map(EncodedImage::encoded_size)
+            .sum();
+        self.raw.pixels().len() + frames + pages + self.animated.size()
     }
 }
 
@@ -148,8 +403,12 @@ pub struct EmoteInfo<'a> {
     platform: Platform,
     frame_count: usize,
     frame_delays: Vec<f64>,
+    /// what was asked for, `width` and `height` are what it ended up being
+    resolution: Resolution,
     #[serde(skip_serializing_if = "Option::is_none")]
     atlas_info: Option<AtlasInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    twitch: Option<&'a TwitchEmoteMetadata>,
 }
 
 impl<'a> EmoteInfo<'a> {
@@ -164,11 +423,19 @@ impl<'a> EmoteInfo<'a> {
             platform: channel_info.platform,
             frame_count: emote.frames.len(),
             frame_delays: emote.frames.iter().map(|f| f.delay).collect(),
+            resolution: emote.resolution,
             atlas_info,
+            twitch: channel_info.twitch.as_ref(),
         }
     }
 
     pub fn new_twitch(emote: &'a Emote) -> Self {
+        Self::new_by_id(Platform::Twitch, emote)
+    }
+
+    /// for emotes requested straight by their id, we don't know their name
+    /// so it's just the id again
+    pub fn new_by_id(platform: Platform, emote: &'a Emote) -> Self {
         let atlas_info = emote.atlas.as_ref().map(AtlasInfo::new);
         Self {
             name: &emote.id,
@@ -176,25 +443,311 @@ impl<'a> EmoteInfo<'a> {
             width: emote.width,
             height: emote.height,
             animated: emote.atlas.is_some(),
-            platform: Platform::Twitch,
+            platform,
             frame_count: emote.frames.len(),
             frame_delays: emote.frames.iter().map(|f| f.delay).collect(),
+            resolution: emote.resolution,
             atlas_info,
+            twitch: None,
         }
     }
 }
 
 #[derive(Debug, Serialize)]
 pub struct AtlasInfo {
+    /// columns on every page
     x_size: u32,
+    /// rows on every page
     y_size: u32,
+    mode: AtlasMode,
+    padding: u32,
+    bleed: bool,
+    pages: Vec<AtlasPageInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AtlasPageInfo {
+    width: u32,
+    height: u32,
+    /// index of the first frame on this page, the rest follow left to right,
+    /// top to bottom
+    first_frame: u32,
+    frame_count: u32,
 }
 
 impl AtlasInfo {
     fn new(atlas: &AtlasTexture) -> Self {
+        let per_page = atlas.x_size * atlas.y_size;
+        let pages = (0..atlas.pages.len() as u32)
+            .map(|page| {
+                let first_frame = page * per_page;
+                AtlasPageInfo {
+                    width: atlas.page_width,
+                    height: atlas.page_height,
+                    first_frame,
+                    frame_count: per_page.min(atlas.frame_count - first_frame),
+                }
+            })
+            .collect();
+
         Self {
             x_size: atlas.x_size,
             y_size: atlas.y_size,
+            mode: atlas.layout.mode,
+            padding: atlas.layout.padding,
+            bleed: atlas.layout.bleed,
+            pages,
+        }
+    }
+}
+
+/// an animated GIF for tests, every frame `width` by `height`, a different
+/// solid colour and 40ms long
+#[cfg(test)]
+pub(crate) fn test_gif(frames: usize, width: u32, height: u32) -> Vec<u8> {
+    use image::{codecs::gif::GifEncoder, Delay, Rgba, RgbaImage};
+
+    let mut gif = Vec::new();
+    {
+        let mut encoder = GifEncoder::new_with_speed(&mut gif, 30);
+        for i in 0..frames {
+            encoder
+                .encode_frame(image::Frame::from_parts(
+                    RgbaImage::from_pixel(width, height, Rgba([(i * 40) as u8, 0, 255, 255])),
+                    0,
+                    0,
+                    Delay::from_numer_denom_ms(40, 1),
+                ))
+                .expect("encoding to memory can't fail");
+        }
+    }
+    gif
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used, reason = "bruh this is a tests module")]
+
+    use std::{io::Cursor, sync::Arc};
+
+    use image::{codecs::gif::GifEncoder, Delay, DynamicImage, ImageFormat, Rgba, RgbaImage};
+    use proptest::prelude::*;
+
+    use super::{
+        animated::{self, AnimatedFormat},
+        format::OutputFormat,
+        frame::{Frame, RawFrames},
+        resolution::Resolution,
+        Emote, EmoteError, EmoteOptions,
+    };
+
+    #[test]
+    fn dedup_frames() {
+        // red for 3 frames, blue, then red again
+        let mut gif = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut gif);
+            for color in [0, 0, 0, 255, 0] {
+                encoder
+                    .encode_frame(image::Frame::from_parts(
+                        RgbaImage::from_pixel(4, 4, Rgba([255 - color, 0, color, 255])),
+                        0,
+                        0,
+                        Delay::from_numer_denom_ms(50, 1),
+                    ))
+                    .unwrap();
+            }
+        }
+
+        let decode = |options: &EmoteOptions| {
+            Emote::try_new(
+                &gif,
+                image::ImageFormat::Gif,
+                "test",
+                Resolution::default(),
+                None,
+                options,
+            )
+            .unwrap()
+        };
+
+        let emote = decode(&EmoteOptions::default());
+        assert_eq!(emote.frames.len(), 5);
+
+        let emote = decode(&EmoteOptions {
+            dedup_frames: true,
+            ..Default::default()
+        });
+        let delays: Vec<_> = emote.frames.iter().map(|f| f.delay).collect();
+        assert_eq!(delays, [0.15, 0.05, 0.05]);
+        assert_eq!(emote.atlas.unwrap().frame_count, 3);
+    }
+
+    #[test]
+    fn lazy() {
+        let mut png = std::io::Cursor::new(Vec::new());
+        RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255]))
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        let emote = Emote::try_new(
+            png.get_ref(),
+            image::ImageFormat::Png,
+            "test",
+            Resolution::default(),
+            None,
+            &EmoteOptions::default(),
+        )
+        .unwrap();
+
+        let frame = emote.frames[0].image();
+        assert!(frame.get(OutputFormat::WebP).is_none());
+        let webp = frame.encode(OutputFormat::WebP).unwrap();
+        assert_eq!(frame.get(OutputFormat::WebP), Some(webp));
+        assert!(frame.get(OutputFormat::Png).is_none());
+    }
+
+    #[test]
+    fn zero_frames() {
+        // 1x1 screen with a color table and a graphic control extension, but
+        // the trailer comes before any image
+        let gif = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\x00\x00\x00\xff\xff\xff\
+            \x21\xf9\x04\x00\x0a\x00\x00\x00\x3b";
+        let result = Emote::try_new(
+            gif,
+            image::ImageFormat::Gif,
+            "test",
+            Resolution::default(),
+            None,
+            &EmoteOptions::default(),
+        );
+        assert!(matches!(result, Err(EmoteError::Empty)), "{result:?}");
+    }
+
+    /// small images in every format image can write, animated ones for the
+    /// formats that can be
+    fn encode_as(format: ImageFormat, frames: &[RgbaImage]) -> Option<Vec<u8>> {
+        let (width, height) = frames[0].dimensions();
+        match format {
+            ImageFormat::Gif => {
+                let mut out = Vec::new();
+                {
+                    let mut encoder = GifEncoder::new_with_speed(&mut out, 30);
+                    for frame in frames {
+                        let frame = image::Frame::from_parts(
+                            frame.clone(),
+                            0,
+                            0,
+                            Delay::from_numer_denom_ms(30, 1),
+                        );
+                        encoder.encode_frame(frame).ok()?;
+                    }
+                }
+                Some(out)
+            }
+            ImageFormat::WebP | ImageFormat::Png => {
+                let raw = Arc::new(RawFrames::new(width, height, frames).ok()?);
+                let frames = Frame::from_raw(&raw, frames.iter().map(|_| 0.03));
+                let format = match format {
+                    ImageFormat::WebP => AnimatedFormat::WebP,
+                    _ => AnimatedFormat::Png,
+                };
+                animated::encode(&frames, width, height, format)
+                    .ok()
+                    .map(|b| b.to_vec())
+            }
+            format => {
+                let mut out = Cursor::new(Vec::new());
+                // plenty of formats can't do alpha or need a different color type
+                let image = DynamicImage::ImageRgba8(frames[0].clone());
+                image
+                    .write_to(&mut out, format)
+                    .or_else(|_| {
+                        DynamicImage::ImageRgb8(image.to_rgb8()).write_to(&mut out, format)
+                    })
+                    .or_else(|_| {
+                        DynamicImage::ImageRgb32F(image.to_rgb32f()).write_to(&mut out, format)
+                    })
+                    .ok()?;
+                Some(out.into_inner())
+            }
+        }
+    }
+
+    fn try_new(data: &[u8], format: ImageFormat) -> Result<Emote, EmoteError> {
+        Emote::try_new(
+            data,
+            format,
+            "test",
+            Resolution::default(),
+            None,
+            &EmoteOptions::default(),
+        )
+    }
+
+    fn frames() -> impl Strategy<Value = Vec<RgbaImage>> {
+        (1..12u32, 1..12u32, 1..4usize).prop_flat_map(|(width, height, count)| {
+            prop::collection::vec(
+                prop::collection::vec(any::<u8>(), (width * height * 4) as usize),
+                count,
+            )
+            .prop_map(move |frames| {
+                frames
+                    .into_iter()
+                    .map(|pixels| RgbaImage::from_raw(width, height, pixels).unwrap())
+                    .collect()
+            })
+        })
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        /// whatever image can write comes back out the same size
+        #[test]
+        fn decodes_every_format(frames in frames()) {
+            for format in ImageFormat::all() {
+                let Some(data) = encode_as(format, &frames) else {
+                    continue;
+                };
+                // no decoder for some of them, but if it works it has to be right
+                if let Ok(emote) = try_new(&data, format) {
+                    prop_assert_eq!((emote.width, emote.height), frames[0].dimensions());
+                    prop_assert!(!emote.frames.is_empty());
+                    for frame in emote.frames.iter() {
+                        prop_assert!(frame.image().encode(OutputFormat::WebP).is_ok());
+                    }
+                }
+            }
+        }
+
+        /// broken versions of real images, as every format, never panic
+        #[test]
+        fn survives_corruption(
+            frames in frames(),
+            edits in prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 1..8),
+            cut in any::<prop::sample::Index>(),
+        ) {
+            for format in ImageFormat::all() {
+                let Some(mut data) = encode_as(format, &frames) else {
+                    continue;
+                };
+                for (i, byte) in &edits {
+                    let i = i.index(data.len());
+                    data[i] = *byte;
+                }
+                let truncated = &data[..cut.index(data.len() + 1)];
+                for decode_as in ImageFormat::all() {
+                    let _ = try_new(&data, decode_as);
+                    let _ = try_new(truncated, decode_as);
+                }
+            }
+        }
+
+        #[test]
+        fn survives_garbage(data in prop::collection::vec(any::<u8>(), 0..512)) {
+            for format in ImageFormat::all() {
+                let _ = try_new(&data, format);
+            }
         }
     }
 }
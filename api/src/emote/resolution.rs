@@ -0,0 +1,171 @@
+use std::{fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// tallest emote we're willing to be asked for, nothing upstream comes close
+const MAX_HEIGHT: u32 = 4096;
+
+/// which size of an emote to serve, `1x` to `4x` like the CDNs have them, or
+/// an exact height in pixels like `48px`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Resolution {
+    Scale(u8),
+    /// width follows the aspect ratio
+    Height(u32),
+}
+
+impl Default for Resolution {
+    /// the biggest there is on every platform
+    fn default() -> Self {
+        Self::Scale(4)
+    }
+}
+
+/// how to shrink an emote that the platform doesn't have in the size asked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Downscale {
+    /// to `.0 / .1` of the downloaded size
+    Ratio(u32, u32),
+    Height(u32),
+}
+
+impl Downscale {
+    /// new size for an image that's `width` x `height`, never any bigger
+    pub fn apply(self, width: u32, height: u32) -> (u32, u32) {
+        if height == 0 {
+            return (width, height);
+        }
+        let new_height = match self {
+            Downscale::Ratio(num, den) => {
+                (u64::from(height) * u64::from(num) / u64::from(den.max(1))) as u32
+            }
+            Downscale::Height(h) => h,
+        }
+        .clamp(1, height);
+        let new_width =
+            (u64::from(width) * u64::from(new_height) + u64::from(height) / 2) / u64::from(height);
+
+        (new_width.max(1) as u32, new_height)
+    }
+}
+
+impl Resolution {
+    /// the same resolution for a platform that has the `available` scales,
+    /// so the ones that end up as the same image share cache entries
+    pub fn normalize(self, available: &[u8]) -> Self {
+        let largest = available.iter().copied().max().unwrap_or(1);
+        match self {
+            Resolution::Scale(scale) => Resolution::Scale(scale.min(largest)),
+            Resolution::Height(_) => self,
+        }
+    }
+
+    /// which of the `available` scales to download, and how to shrink it
+    /// afterwards if the exact size isn't there
+    pub fn pick(self, available: &[u8]) -> (u8, Option<Downscale>) {
+        let largest = available.iter().copied().max().unwrap_or(1);
+        match self.normalize(available) {
+            Resolution::Scale(scale) if available.contains(&scale) => (scale, None),
+            Resolution::Scale(scale) => (
+                largest,
+                Some(Downscale::Ratio(scale.into(), largest.into())),
+            ),
+            Resolution::Height(height) => (largest, Some(Downscale::Height(height))),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("\"{0}\" isn't a resolution, try 1x to 4x or a height like 48px")]
+pub struct BadResolution(String);
+
+impl FromStr for Resolution {
+    type Err = BadResolution;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || BadResolution(s.into());
+        let lower = s.trim().to_lowercase();
+
+        let height = lower.strip_suffix("px").unwrap_or(&lower);
+        if let Some(scale) = height.strip_suffix('x') {
+            match scale.parse::<u8>() {
+                Ok(scale @ 1..=4) => Ok(Self::Scale(scale)),
+                _ => Err(bad()),
+            }
+        } else {
+            match height.parse::<u32>() {
+                Ok(height @ 1..=MAX_HEIGHT) => Ok(Self::Height(height)),
+                _ => Err(bad()),
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for Resolution {
+    type Error = BadResolution;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Resolution> for String {
+    fn from(value: Resolution) -> Self {
+        value.to_string()
+    }
+}
+
+impl Display for Resolution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Resolution::Scale(scale) => write!(f, "{scale}x"),
+            Resolution::Height(height) => write!(f, "{height}px"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used, reason = "bruh this is a tests module")]
+
+    use super::{Downscale, Resolution};
+
+    #[test]
+    fn parse() {
+        assert_eq!("2x".parse::<Resolution>().unwrap(), Resolution::Scale(2));
+        assert_eq!(
+            "48PX".parse::<Resolution>().unwrap(),
+            Resolution::Height(48)
+        );
+        assert_eq!("48".parse::<Resolution>().unwrap(), Resolution::Height(48));
+        assert!("5x".parse::<Resolution>().is_err());
+        assert!("0px".parse::<Resolution>().is_err());
+        assert!("big".parse::<Resolution>().is_err());
+        assert_eq!(Resolution::Height(48).to_string(), "48px");
+    }
+
+    #[test]
+    fn pick() {
+        // FFZ doesn't have 3x
+        let ffz = &[1, 2, 4];
+        assert_eq!(Resolution::Scale(2).pick(ffz), (2, None));
+        assert_eq!(
+            Resolution::Scale(3).pick(ffz),
+            (4, Some(Downscale::Ratio(3, 4)))
+        );
+        // and BTTV doesn't have 4x, so that's just their biggest
+        let bttv = &[1, 2, 3];
+        assert_eq!(Resolution::Scale(4).pick(bttv), (3, None));
+        assert_eq!(Resolution::Scale(4).normalize(bttv), Resolution::Scale(3));
+        assert_eq!(
+            Resolution::Height(20).pick(bttv),
+            (3, Some(Downscale::Height(20)))
+        );
+
+        assert_eq!(Downscale::Ratio(3, 4).apply(128, 64), (96, 48));
+        assert_eq!(Downscale::Height(32).apply(100, 64), (50, 32));
+        // never upscaled
+        assert_eq!(Downscale::Height(200).apply(100, 64), (100, 64));
+    }
+}
@@ -0,0 +1,370 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    sync::{Arc, OnceLock},
+    time::Instant,
+};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+use super::EmoteError;
+
+tokio::task_local! {
+    static PRIORITY: Priority;
+}
+
+/// which decodes go first when there's more of them than slots
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// prefetching and warming caches, only runs when nothing interactive is
+    /// waiting
+    Background,
+    /// someone's waiting on the response
+    #[default]
+    Interactive,
+}
+
+impl Priority {
+    /// whatever the current task was scoped to, interactive otherwise
+    pub fn current() -> Self {
+        PRIORITY.try_with(|p| *p).unwrap_or_default()
+    }
+
+    /// runs `f` with every decode it starts at this priority
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        PRIORITY.scope(self, f).await
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Priority::Interactive => 0,
+            Priority::Background => 1,
+        }
+    }
+}
+
+/// numbers for one priority, the totals count since startup
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct QueueStats {
+    /// waiting for a slot right now
+    pub queued: usize,
+    /// got a slot, whether they had to wait or not
+    pub started: u64,
+    pub total_wait_secs: f64,
+    pub max_wait_secs: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct SchedulerStats {
+    pub concurrency: usize,
+    pub running: usize,
+    pub interactive: QueueStats,
+    pub background: QueueStats,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    running: usize,
+    /// by [`Priority::index`]
+    waiting: [VecDeque<oneshot::Sender<Slot>>; 2],
+    stats: [QueueStats; 2],
+}
+
+#[derive(Debug)]
+struct Inner {
+    concurrency: usize,
+    state: Mutex<State>,
+    /// as many threads as there are slots, started the first time anything
+    /// gets split up
+    threads: OnceLock<rayon::ThreadPool>,
+}
+
+/// a fixed number of decodes at once, handed out to whatever's waiting with
+/// the highest priority first and oldest first after that. work that gets
+/// split up inside one runs on threads of its own, no more than that many
+/// either
+#[derive(Debug, Clone)]
+pub struct DecodeScheduler(Arc<Inner>);
+
+impl Default for DecodeScheduler {
+    /// one decode per core
+    fn default() -> Self {
+        Self::new(std::thread::available_parallelism().map_or(4, |n| n.get()))
+    }
+}
+
+impl Inner {
+    /// hands the slot straight to whoever's next, so nobody can cut in line
+    fn release(self: &Arc<Self>) {
+        let mut state = self.state.lock();
+        for i in 0..state.waiting.len() {
+            while let Some(waiter) = state.waiting[i].pop_front() {
+                match waiter.send(Slot(Some(self.clone()))) {
+                    Ok(()) => return,
+                    // gave up waiting, so it's the next one's
+                    Err(mut slot) => {
+                        slot.0.take();
+                    }
+                }
+            }
+        }
+        state.running -= 1;
+    }
+}
+
+/// a decode running, the next one gets its turn when this is dropped
+#[derive(Debug)]
+struct Slot(Option<Arc<Inner>>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(inner) = self.0.take() {
+            inner.release();
+        }
+    }
+}
+
+impl DecodeScheduler {
+    pub fn new(concurrency: usize) -> Self {
+        Self(Arc::new(Inner {
+            concurrency: concurrency.max(1),
+            state: Mutex::default(),
+            threads: OnceLock::new(),
+        }))
+    }
+
+    /// runs `f` where rayon's parallel iterators use this scheduler's threads
+    pub fn install<R: Send>(&self, f: impl FnOnce() -> R + Send) -> R {
+        self.0
+            .threads
+            .get_or_init(|| {
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(self.0.concurrency)
+                    .thread_name(|i| format!("emote-worker-{i}"))
+                    .build()
+                    .expect("failed to start emote threads")
+            })
+            .install(f)
+    }
+
+    /// [`Self::run`] with `f` on a blocking thread, inside [`Self::install`]
+    /// and the current span. the slot goes with `f`, so it's only given back
+    /// once `f` is done even if whoever was waiting on it gave up
+    pub async fn run_blocking<R: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> R + Send + 'static,
+    ) -> Result<R, EmoteError> {
+        let slot = self.acquire(Priority::current()).await;
+        let scheduler = self.clone();
+        let span = tracing::Span::current();
+        tokio::task::spawn_blocking(move || {
+            let _slot = slot;
+            span.in_scope(|| scheduler.install(f))
+        })
+        .await
+        .map_err(|_| EmoteError::Panicked)
+    }
+
+    /// runs `f` once there's a slot free for [`Priority::current`]
+    pub async fn run<F: Future>(&self, f: F) -> F::Output {
+        let _slot = self.acquire(Priority::current()).await;
+        f.await
+    }
+
+    async fn acquire(&self, priority: Priority) -> Slot {
+        let queued_at = Instant::now();
+        let waiting = {
+            let mut state = self.0.state.lock();
+            let anyone_waiting = state.waiting.iter().any(|w| !w.is_empty());
+            if state.running < self.0.concurrency && !anyone_waiting {
+                state.running += 1;
+                None
+            } else {
+                let (tx, rx) = oneshot::channel();
+                state.waiting[priority.index()].push_back(tx);
+                Some(rx)
+            }
+        };
+
+        let slot = match waiting {
+            Some(rx) => rx.await.expect("slots only get dropped after being sent"),
+            None => Slot(Some(self.0.clone())),
+        };
+
+        let waited = queued_at.elapsed().as_secs_f64();
+        let stats = &mut self.0.state.lock().stats[priority.index()];
+        stats.started += 1;
+        stats.total_wait_secs += waited;
+        stats.max_wait_secs = stats.max_wait_secs.max(waited);
+        slot
+    }
+
+    pub fn stats(&self) -> SchedulerStats {
+        let state = self.0.state.lock();
+        let queue = |priority: Priority| QueueStats {
+            queued: state.waiting[priority.index()]
+                .iter()
+                .filter(|w| !w.is_closed())
+                .count(),
+            ..state.stats[priority.index()]
+        };
+        SchedulerStats {
+            concurrency: self.0.concurrency,
+            running: state.running,
+            interactive: queue(Priority::Interactive),
+            background: queue(Priority::Background),
+        }
+    }
+
+    #[cfg(test)]
+    fn waiting(&self) -> usize {
+        self.stats().interactive.queued + self.stats().background.queued
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used, reason = "bruh this is a tests module")]
+
+    use std::{sync::Arc, time::Duration};
+
+    use parking_lot::Mutex;
+    use tokio::sync::oneshot;
+
+    use super::{DecodeScheduler, Priority};
+
+    #[tokio::test]
+    async fn priorities() {
+        let scheduler = DecodeScheduler::new(1);
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        // hold the only slot until everything else is queued up
+        let (release, held) = oneshot::channel::<()>();
+        let blocker = tokio::spawn({
+            let scheduler = scheduler.clone();
+            async move {
+                scheduler
+                    .run(async {
+                        held.await.unwrap();
+                    })
+                    .await
+            }
+        });
+        while scheduler.stats().running == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let mut tasks = Vec::new();
+        for (i, priority) in [
+            Priority::Background,
+            Priority::Interactive,
+            Priority::Background,
+            Priority::Interactive,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let task = {
+                let scheduler = scheduler.clone();
+                let order = order.clone();
+                priority.scope(async move { scheduler.run(async { order.lock().push(i) }).await })
+            };
+            tasks.push(tokio::spawn(task));
+            while scheduler.waiting() <= i {
+                tokio::task::yield_now().await;
+            }
+        }
+
+        let stats = scheduler.stats();
+        assert_eq!(stats.interactive.queued, 2);
+        assert_eq!(stats.background.queued, 2);
+
+        release.send(()).unwrap();
+        blocker.await.unwrap();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // interactive ones first, oldest first within each
+        assert_eq!(*order.lock(), [1, 3, 0, 2]);
+        let stats = scheduler.stats();
+        assert_eq!(stats.running, 0);
+        assert_eq!(stats.interactive.started, 3);
+        assert_eq!(stats.background.started, 2);
+        assert!(stats.background.max_wait_secs > 0.0);
+    }
+
+    #[tokio::test]
+    async fn cancelled() {
+        let scheduler = DecodeScheduler::new(1);
+        let (release, held) = oneshot::channel::<()>();
+        let blocker = tokio::spawn({
+            let scheduler = scheduler.clone();
+            async move {
+                scheduler
+                    .run(async {
+                        held.await.unwrap();
+                    })
+                    .await
+            }
+        });
+        while scheduler.stats().running == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        // gives up before it ever gets a slot
+        let waiting = scheduler.run(async {});
+        assert!(tokio::time::timeout(Duration::from_millis(20), waiting)
+            .await
+            .is_err());
+        assert_eq!(scheduler.waiting(), 0);
+
+        release.send(()).unwrap();
+        blocker.await.unwrap();
+        // and the slot it never took is free again
+        scheduler.run(async {}).await;
+        assert_eq!(scheduler.stats().running, 0);
+    }
+
+    #[tokio::test]
+    async fn cancelled_while_running() {
+        let scheduler = DecodeScheduler::new(1);
+        let (release, held) = std::sync::mpsc::channel::<()>();
+        let (started, running) = oneshot::channel();
+        let job = tokio::spawn({
+            let scheduler = scheduler.clone();
+            async move {
+                scheduler
+                    .run_blocking(move || {
+                        started.send(()).unwrap();
+                        held.recv().unwrap();
+                    })
+                    .await
+            }
+        });
+        running.await.unwrap();
+
+        // nobody's waiting on it anymore but it's still decoding, so it keeps
+        // its slot
+        job.abort();
+        assert!(job.await.unwrap_err().is_cancelled());
+        assert_eq!(scheduler.stats().running, 1);
+
+        let next = tokio::spawn({
+            let scheduler = scheduler.clone();
+            async move { scheduler.run_blocking(|| ()).await }
+        });
+        while scheduler.waiting() == 0 {
+            tokio::task::yield_now().await;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!next.is_finished());
+
+        release.send(()).unwrap();
+        next.await.unwrap().unwrap();
+        assert_eq!(scheduler.stats().running, 0);
+    }
+}
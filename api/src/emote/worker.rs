@@ -0,0 +1,689 @@
+//! decoding in child processes, so an emote that crashes the decoder or never
+//! finishes only takes its worker down with it instead of the whole server.
+//! on Linux workers are sandboxed too, once they're up they can't do much
+//! more than read jobs, allocate and write back what they decoded
+
+use std::{
+    ffi::OsString,
+    io::{self, Read, Write},
+    path::PathBuf,
+    process::Stdio,
+    sync::Arc,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    process::{Child, ChildStdin, ChildStdout, Command},
+    sync::Semaphore,
+};
+
+use super::{
+    decode,
+    frame::RawFrames,
+    limits::{DecodeLimits, Limit},
+    resolution::Downscale,
+    Decoded, EmoteError, EmoteOptions,
+};
+
+/// set on workers, holds their memory limit in bytes
+pub const WORKER_ENV: &str = "TWITCH_EMOTE_API_DECODE_WORKER";
+
+/// first thing a worker says, anything before it on the same line or the ones
+/// before gets skipped
+const HELLO: &[u8] = b"twitch_emote_api decode worker v1\n";
+
+/// headers are a bit of json, anything bigger than this is a confused worker
+const MAX_HEADER: u32 = 64 * 1024;
+
+/// where emotes get decoded
+#[derive(Debug, Clone, Default)]
+pub enum Decoder {
+    /// on a blocking thread, in the server itself
+    #[default]
+    InProcess,
+    Workers(Arc<WorkerPool>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WorkerConfig {
+    /// most decodes running at once, and so most workers alive at once
+    pub workers: usize,
+    /// for a single decode, starting a worker for it included
+    pub timeout: Duration,
+    /// address space every worker gets, in bytes
+    pub memory_limit: u64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum WorkerError {
+    #[error("couldn't be started: {0}")]
+    Spawn(io::Error),
+    #[error("crashed")]
+    Crashed,
+    #[error("took longer than {0:?}")]
+    TimedOut(Duration),
+    #[error("sent something that makes no sense")]
+    Protocol,
+    #[error("failed: {0}")]
+    Failed(String),
+}
+
+/// the emote itself comes right after
+#[derive(Debug, Serialize, Deserialize)]
+struct Job {
+    /// an extension, image can't serialize formats itself
+    format: String,
+    downscale: Option<Downscale>,
+    dedup_frames: bool,
+    limits: DecodeLimits,
+}
+
+/// every frame's pixels come right after when it worked, nothing otherwise
+#[derive(Debug, Serialize, Deserialize)]
+enum Reply {
+    Decoded {
+        width: u32,
+        height: u32,
+        delays: Vec<f64>,
+        animated: bool,
+    },
+    LimitExceeded(Limit),
+    Empty,
+    Failed(String),
+}
+
+/// messages are a little endian u32 length, a json header, a u64 length and
+/// then that many bytes of body
+fn message_head(header: &impl Serialize, body_len: usize) -> Vec<u8> {
+    let header = serde_json::to_vec(header).expect("headers always serialize");
+    let mut head = Vec::with_capacity(header.len() + 12);
+    head.extend_from_slice(&(header.len() as u32).to_le_bytes());
+    head.extend_from_slice(&header);
+    head.extend_from_slice(&(body_len as u64).to_le_bytes());
+    head
+}
+
+/// whether this process was started as a decode worker
+pub fn is_worker() -> bool {
+    std::env::var_os(WORKER_ENV).is_some()
+}
+
+/// what a worker runs instead of the server, takes jobs from stdin and
+/// answers on stdout until stdin gets closed
+pub fn run_worker() -> io::Result<()> {
+    let memory_limit = std::env::var(WORKER_ENV)
+        .ok()
+        .and_then(|limit| limit.parse().ok())
+        .ok_or_else(|| io::Error::other(format!("{WORKER_ENV} has to be a number of bytes")))?;
+    // the defaults look at the machine, which can't be done once it's
+    // sandboxed
+    let defaults = EmoteOptions::default();
+    restrict(memory_limit)?;
+
+    let mut input = io::stdin().lock();
+    let mut output = io::stdout().lock();
+    output.write_all(HELLO)?;
+    output.flush()?;
+
+    loop {
+        let mut len = [0; 4];
+        match input.read_exact(&mut len) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            r => r?,
+        }
+        let mut header = vec![0; u32::from_le_bytes(len) as usize];
+        input.read_exact(&mut header)?;
+        let mut len = [0; 8];
+        input.read_exact(&mut len)?;
+        let mut data = vec![0; u64::from_le_bytes(len) as usize];
+        input.read_exact(&mut data)?;
+
+        let job: Job = serde_json::from_slice(&header).map_err(io::Error::other)?;
+        let (reply, pixels) = match run_job(&job, &data, &defaults) {
+            Ok(decoded) => (
+                Reply::Decoded {
+                    width: decoded.raw.width(),
+                    height: decoded.raw.height(),
+                    delays: decoded.delays,
+                    animated: decoded.animated,
+                },
+                Some(decoded.raw),
+            ),
+            Err(EmoteError::LimitExceeded(limit)) => (Reply::LimitExceeded(limit), None),
+            Err(EmoteError::Empty) => (Reply::Empty, None),
+            Err(e) => (Reply::Failed(e.to_string()), None),
+        };
+        let body = pixels.as_ref().map_or(&[][..], |raw| raw.pixels());
+        output.write_all(&message_head(&reply, body.len()))?;
+        output.write_all(body)?;
+        output.flush()?;
+    }
+}
+
+fn run_job(job: &Job, data: &[u8], defaults: &EmoteOptions) -> Result<Decoded, EmoteError> {
+    let format = image::ImageFormat::from_extension(&job.format)
+        .ok_or(EmoteError::UnableToDetermineFormat)?;
+    let options = EmoteOptions {
+        limits: job.limits,
+        dedup_frames: job.dedup_frames,
+        ..defaults.clone()
+    };
+    decode(data, format, job.downscale, &options)
+}
+
+/// no more memory than the limit, no core dumps when it crashes anyway, and
+/// no syscalls past what [`ALLOWED_SYSCALLS`] has
+#[cfg(target_os = "linux")]
+fn restrict(memory_limit: u64) -> io::Result<()> {
+    let check = |ret| match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    };
+    let set = |resource, value: u64| {
+        let limit = libc::rlimit {
+            rlim_cur: value,
+            rlim_max: value,
+        };
+        // SAFETY: limit is a valid rlimit that outlives the call
+        check(unsafe { libc::setrlimit(resource, &limit) })
+    };
+    set(libc::RLIMIT_AS, memory_limit)?;
+    set(libc::RLIMIT_CORE, 0)?;
+    // SAFETY: only sets a flag on this process
+    check(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })?;
+    seccomp()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn restrict(_memory_limit: u64) -> io::Result<()> {
+    Ok(())
+}
+
+/// enough to read jobs, allocate, write replies and exit. no opening files,
+/// no sockets, no starting processes or threads, no signalling anyone
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_read,
+    libc::SYS_readv,
+    libc::SYS_write,
+    libc::SYS_writev,
+    libc::SYS_close,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    libc::SYS_futex,
+    libc::SYS_sched_yield,
+    libc::SYS_getrandom,
+    libc::SYS_getcpu,
+    libc::SYS_clock_gettime,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_sigaltstack,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+];
+
+/// what the kernel calls the architecture [`ALLOWED_SYSCALLS`] are numbered
+/// for
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// kills every thread of this process as soon as one makes a syscall that
+/// isn't allowed
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+fn seccomp() -> io::Result<()> {
+    let stmt = |code: u32, k: u32| libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let load = |offset: usize| stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, offset as u32);
+    let ret = |action: u32| stmt(libc::BPF_RET | libc::BPF_K, action);
+    let jump_if = |k: u32, jt: u8, jf: u8| libc::sock_filter {
+        code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k,
+    };
+
+    let mut filter = vec![
+        // syscall numbers mean something else on other architectures
+        load(std::mem::offset_of!(libc::seccomp_data, arch)),
+        jump_if(AUDIT_ARCH, 1, 0),
+        ret(libc::SECCOMP_RET_KILL_PROCESS),
+        load(std::mem::offset_of!(libc::seccomp_data, nr)),
+    ];
+    for &nr in ALLOWED_SYSCALLS {
+        filter.push(jump_if(nr as u32, 0, 1));
+        filter.push(ret(libc::SECCOMP_RET_ALLOW));
+    }
+    filter.push(ret(libc::SECCOMP_RET_KILL_PROCESS));
+
+    let program = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    // SAFETY: program points at filter, which outlives the call, and the
+    // kernel copies it
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &program,
+        )
+    };
+    match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// there's no filter for anything else, so workers refuse to start rather
+/// than run unsandboxed
+#[cfg(all(
+    target_os = "linux",
+    not(any(target_arch = "x86_64", target_arch = "aarch64"))
+))]
+fn seccomp() -> io::Result<()> {
+    Err(io::Error::other(
+        "decode workers can't be sandboxed on this architecture",
+    ))
+}
+
+#[derive(Debug)]
+struct Worker {
+    // only here so it gets killed when the worker gets dropped
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Worker {
+    /// the outer error means the worker is done for, the inner one is just the
+    /// emote being bad
+    async fn run(
+        &mut self,
+        job: &Job,
+        data: &[u8],
+    ) -> Result<Result<Decoded, EmoteError>, WorkerError> {
+        let crashed = |_| WorkerError::Crashed;
+        self.stdin
+            .write_all(&message_head(job, data.len()))
+            .await
+            .map_err(crashed)?;
+        self.stdin.write_all(data).await.map_err(crashed)?;
+        self.stdin.flush().await.map_err(crashed)?;
+
+        let header_len = self.stdout.read_u32_le().await.map_err(crashed)?;
+        if header_len > MAX_HEADER {
+            return Err(WorkerError::Protocol);
+        }
+        let mut header = vec![0; header_len as usize];
+        self.stdout.read_exact(&mut header).await.map_err(crashed)?;
+        let body_len = self.stdout.read_u64_le().await.map_err(crashed)?;
+        // the worker's held to the same limits, so this is plenty
+        if body_len > job.limits.max_decoded_bytes {
+            return Err(WorkerError::Protocol);
+        }
+        let mut body = vec![0; body_len as usize];
+        self.stdout.read_exact(&mut body).await.map_err(crashed)?;
+
+        let reply = serde_json::from_slice(&header).map_err(|_| WorkerError::Protocol)?;
+        Ok(match reply {
+            Reply::Decoded {
+                width,
+                height,
+                delays,
+                animated,
+            } => {
+                let raw = RawFrames::from_pixels(width, height, body.into())
+                    .map_err(|_| WorkerError::Protocol)?;
+                if raw.len() != delays.len() {
+                    return Err(WorkerError::Protocol);
+                }
+                Ok(Decoded {
+                    raw,
+                    delays,
+                    animated,
+                })
+            }
+            Reply::LimitExceeded(limit) => Err(EmoteError::LimitExceeded(limit)),
+            Reply::Empty => Err(EmoteError::Empty),
+            Reply::Failed(e) => Err(WorkerError::Failed(e).into()),
+        })
+    }
+}
+
+/// child processes that decode emotes one at a time, started when there's
+/// no idle one around and killed as soon as anything goes wrong with them
+#[derive(Debug)]
+pub struct WorkerPool {
+    program: PathBuf,
+    args: Vec<OsString>,
+    config: WorkerConfig,
+    idle: Mutex<Vec<Worker>>,
+    permits: Semaphore,
+}
+
+impl WorkerPool {
+    /// workers are this same executable, which has to call [`run_worker`]
+    /// when [`is_worker`]
+    pub fn new(config: WorkerConfig) -> io::Result<Self> {
+        Ok(Self::with_command(
+            std::env::current_exe()?,
+            Vec::<OsString>::new(),
+            config,
+        ))
+    }
+
+    pub fn with_command(
+        program: impl Into<PathBuf>,
+        args: impl IntoIterator<Item = impl Into<OsString>>,
+        config: WorkerConfig,
+    ) -> Self {
+        Self {
+            program: program.into(),
+            args: args.into_iter().map(Into::into).collect(),
+            config,
+            idle: Mutex::default(),
+            permits: Semaphore::new(config.workers.max(1)),
+        }
+    }
+
+    pub fn config(&self) -> &WorkerConfig {
+        &self.config
+    }
+
+    /// same as [`decode`], except a crash or a hang is an error too
+    pub async fn decode(
+        &self,
+        data: Bytes,
+        format: image::ImageFormat,
+        downscale: Option<Downscale>,
+        options: &EmoteOptions,
+    ) -> Result<Decoded, EmoteError> {
+        let job = Job {
+            format: format
+                .extensions_str()
+                .first()
+                .ok_or(EmoteError::UnableToDetermineFormat)?
+                .to_string(),
+            downscale,
+            dedup_frames: options.dedup_frames,
+            limits: options.limits,
+        };
+
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("the semaphore never gets closed");
+        let idle = self.idle.lock().pop();
+        // a worker that fails or times out gets dropped in here, which kills it
+        let run = async {
+            let mut worker = match idle {
+                Some(worker) => worker,
+                None => self.spawn().await?,
+            };
+            let decoded = worker.run(&job, &data).await?;
+            Ok::<_, WorkerError>((worker, decoded))
+        };
+        let (worker, decoded) = tokio::time::timeout(self.config.timeout, run)
+            .await
+            .map_err(|_| WorkerError::TimedOut(self.config.timeout))??;
+
+        self.idle.lock().push(worker);
+        decoded
+    }
+
+    async fn spawn(&self) -> Result<Worker, WorkerError> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .env_clear()
+            .env(WORKER_ENV, self.config.memory_limit.to_string())
+            .current_dir("/")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(WorkerError::Spawn)?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let mut stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+
+        // only the test harness ever prints something first, and without a
+        // newline at the end either
+        let mut line = Vec::new();
+        for _ in 0..64 {
+            line.clear();
+            let read = stdout
+                .read_until(b'\n', &mut line)
+                .await
+                .map_err(|_| WorkerError::Crashed)?;
+            if read == 0 {
+                return Err(WorkerError::Crashed);
+            }
+            if line.ends_with(HELLO) {
+                return Ok(Worker {
+                    _child: child,
+                    stdin,
+                    stdout,
+                });
+            }
+        }
+        Err(WorkerError::Protocol)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used, reason = "bruh this is a tests module")]
+
+    use std::time::Duration;
+
+    use super::{is_worker, run_worker, WorkerConfig, WorkerError, WorkerPool, WORKER_ENV};
+    use crate::emote::{decode, limits::DecodeLimits, test_gif, EmoteError, EmoteOptions};
+
+    /// the test binary gets started again with just this one picked to be a
+    /// worker for the other tests
+    #[test]
+    #[ignore = "worker entry point, started by the other tests"]
+    fn worker_entry() {
+        if is_worker() {
+            run_worker().unwrap();
+            std::process::exit(0);
+        }
+    }
+
+    /// tries to open a file once it's sandboxed, which should get it killed
+    #[test]
+    #[ignore = "started by `sandboxed`"]
+    fn escape_entry() {
+        if is_worker() {
+            super::restrict(1024 * 1024 * 1024).unwrap();
+            let _ = std::fs::File::open("/proc/self/status");
+            std::process::exit(0);
+        }
+    }
+
+    /// the test binary with just `entry` picked, and only that one
+    fn entry_args(entry: &str) -> [&str; 5] {
+        [
+            entry,
+            "--exact",
+            "--ignored",
+            "--nocapture",
+            "--test-threads=1",
+        ]
+    }
+
+    fn config() -> WorkerConfig {
+        WorkerConfig {
+            workers: 2,
+            timeout: Duration::from_secs(30),
+            memory_limit: 1024 * 1024 * 1024,
+        }
+    }
+
+    fn pool(config: WorkerConfig) -> WorkerPool {
+        WorkerPool::with_command(
+            std::env::current_exe().unwrap(),
+            entry_args("emote::worker::tests::worker_entry"),
+            config,
+        )
+    }
+
+    #[tokio::test]
+    async fn decodes() {
+        let pool = pool(config());
+        let options = EmoteOptions::default();
+        let expected = decode(&test_gif(3, 6, 4), image::ImageFormat::Gif, None, &options).unwrap();
+
+        for _ in 0..2 {
+            let decoded = pool
+                .decode(
+                    test_gif(3, 6, 4).into(),
+                    image::ImageFormat::Gif,
+                    None,
+                    &options,
+                )
+                .await
+                .unwrap();
+            assert_eq!(decoded.raw.pixels(), expected.raw.pixels());
+            assert_eq!(decoded.delays, expected.delays);
+            assert!(decoded.animated);
+        }
+        // the same worker did both
+        assert_eq!(pool.idle.lock().len(), 1);
+
+        let limits = DecodeLimits {
+            max_frames: 2,
+            ..Default::default()
+        };
+        let result = pool
+            .decode(
+                test_gif(3, 6, 4).into(),
+                image::ImageFormat::Gif,
+                None,
+                &EmoteOptions {
+                    limits,
+                    ..Default::default()
+                },
+            )
+            .await;
+        assert!(matches!(result, Err(EmoteError::LimitExceeded(_))));
+        // that's the emote's fault, the worker's fine
+        assert_eq!(pool.idle.lock().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn crashes() {
+        // a single pixel on an 8000x8000 canvas, which gets decoded into a
+        // full canvas per frame
+        let mut bomb = b"GIF89a\x40\x1f\x40\x1f\x80\x00\x00\x00\x00\x00\xff\xff\xff".to_vec();
+        bomb.extend_from_slice(b"\x2c\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02\x44\x01\x00\x3b");
+        let options = EmoteOptions {
+            limits: DecodeLimits {
+                max_dimension: 8192,
+                max_decoded_bytes: 4 * 1024 * 1024 * 1024,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let pool = pool(WorkerConfig {
+            memory_limit: 128 * 1024 * 1024,
+            ..config()
+        });
+        let result = pool
+            .decode(bomb.into(), image::ImageFormat::Gif, None, &options)
+            .await;
+        assert!(matches!(
+            result,
+            Err(EmoteError::Worker(WorkerError::Crashed))
+        ));
+        assert!(pool.idle.lock().is_empty());
+
+        // and the next one gets a new worker
+        pool.decode(
+            test_gif(3, 6, 4).into(),
+            image::ImageFormat::Gif,
+            None,
+            &options,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    fn sandboxed() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(entry_args("emote::worker::tests::escape_entry"))
+            .env(WORKER_ENV, "0")
+            .stdout(std::process::Stdio::null())
+            .status()
+            .unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGSYS));
+    }
+
+    #[tokio::test]
+    async fn broken_workers() {
+        let hangs = WorkerPool::with_command(
+            "/bin/sh",
+            ["-c", "sleep 10"],
+            WorkerConfig {
+                timeout: Duration::from_millis(200),
+                ..config()
+            },
+        );
+        let result = hangs
+            .decode(
+                test_gif(3, 6, 4).into(),
+                image::ImageFormat::Gif,
+                None,
+                &EmoteOptions::default(),
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(EmoteError::Worker(WorkerError::TimedOut(_)))
+        ));
+
+        let exits = WorkerPool::with_command("/bin/sh", ["-c", "exit 1"], config());
+        let result = exits
+            .decode(
+                test_gif(3, 6, 4).into(),
+                image::ImageFormat::Gif,
+                None,
+                &EmoteOptions::default(),
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(EmoteError::Worker(WorkerError::Crashed))
+        ));
+    }
+}
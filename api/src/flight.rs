@@ -0,0 +1,120 @@
+//! one fetch for everyone who asks for the same thing while it's running
+
+use std::{future::Future, hash::Hash};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use futures::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+
+/// calls that are running right now, by key. whoever asks for a key that's
+/// already running waits for that one instead of starting their own, and
+/// everyone gets a clone of the same result
+pub struct SingleFlight<K: Hash + Eq, V> {
+    calls: DashMap<K, Shared<BoxFuture<'static, V>>>,
+}
+
+impl<K: Hash + Eq, V> std::fmt::Debug for SingleFlight<K, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SingleFlight")
+            .field("running", &self.calls.len())
+            .finish()
+    }
+}
+
+impl<K: Hash + Eq, V> Default for SingleFlight<K, V> {
+    fn default() -> Self {
+        Self {
+            calls: DashMap::new(),
+        }
+    }
+}
+
+impl<K, V> SingleFlight<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone + Send + Sync + 'static,
+{
+    /// the result of the future `f` makes, or of the one that's already
+    /// running for `key`. it only gets polled while someone's waiting on it,
+    /// if everyone gives up the next one to ask picks it back up
+    pub async fn run<F>(&self, key: K, f: impl FnOnce() -> F) -> V
+    where
+        F: Future<Output = V> + Send + 'static,
+    {
+        let call = match self.calls.entry(key.clone()) {
+            Entry::Occupied(running) => running.get().clone(),
+            Entry::Vacant(slot) => slot.insert(f().boxed().shared()).clone(),
+        };
+
+        let out = call.clone().await;
+        // a new one might've started already if this one finished a while
+        // ago, that one stays
+        self.calls.remove_if(&key, |_, c| c.ptr_eq(&call));
+        out
+    }
+
+    /// how many keys have something running
+    pub fn running(&self) -> usize {
+        self.calls.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used, reason = "bruh this is a tests module")]
+
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::SingleFlight;
+
+    #[tokio::test]
+    async fn coalesces() {
+        let flights = Arc::new(SingleFlight::<&str, Result<u32, Arc<String>>>::default());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let asked = Arc::new(AtomicUsize::new(0));
+        let (release, released) = tokio::sync::watch::channel(false);
+
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let flights = flights.clone();
+                let calls = calls.clone();
+                let asked = asked.clone();
+                let mut released = released.clone();
+                tokio::spawn(async move {
+                    asked.fetch_add(1, Ordering::SeqCst);
+                    flights
+                        .run("sourpls", || async move {
+                            calls.fetch_add(1, Ordering::SeqCst);
+                            released.wait_for(|r| *r).await.unwrap();
+                            Err(Arc::new("upstream's down".to_owned()))
+                        })
+                        .await
+                })
+            })
+            .collect();
+        while asked.load(Ordering::SeqCst) < 8 {
+            tokio::task::yield_now().await;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        release.send(true).unwrap();
+
+        // errors get shared just the same
+        for task in tasks {
+            assert_eq!(*task.await.unwrap().unwrap_err(), "upstream's down");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(flights.running(), 0);
+
+        // and once it's done the next one runs again
+        let again = flights.run("sourpls", || async { Ok(1) }).await;
+        assert_eq!(again, Ok(1));
+    }
+}
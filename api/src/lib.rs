@@ -8,4 +8,7 @@
 pub mod cache;
 pub mod cli;
 pub mod emote;
+pub mod flight;
 pub mod platforms;
+pub mod routes;
+pub mod store;
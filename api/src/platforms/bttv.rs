@@ -1,47 +1,131 @@
 use std::{
     iter::{Chain, Map},
     sync::Arc,
-    time::{Duration, Instant},
+    time::Duration,
 };
 
 use dashmap::DashMap;
-use reqwest::header::ACCEPT;
+use futures::future::ready;
 use serde::Deserialize;
-use tokio::sync::OnceCell;
-use tracing::debug;
-
-use crate::{cache::Cache, emote::Emote, platforms::channel::ChannelEmote};
+use socket::BttvSocket;
+use tokio::sync::broadcast;
+use url::Url;
+
+use crate::{
+    cache::Cache,
+    emote::{resolution::Resolution, Emote},
+    platforms::channel::ChannelEmote,
+};
 
 use super::{
-    cache::platform_cache_evictor, EmotePlatform, PlatformError, EMOTE_CACHE_MAX_AGE,
-    USER_CACHE_MAX_AGE,
+    cache::platform_cache_evictor,
+    check_channel_response, check_response, endpoint,
+    fetcher::EmoteFetcher,
+    globals::{GlobalEmotes, GlobalSet},
+    ChannelUpdate, EmotePlatform, Platform, PlatformError, GLOBAL_CACHE_MAX_AGE,
+    GLOBAL_RETRY_BACKOFF, USER_CACHE_MAX_AGE,
 };
 
+pub mod socket;
+
+/// sizes on the BTTV CDN, there's no 4x
+const EMOTE_SCALES: &[u8] = &[1, 2, 3];
+
+#[derive(Debug, Clone)]
+pub struct BttvUrls {
+    /// REST API, `https://api.betterttv.net/3` upstream
+    pub api: Url,
+    /// image CDN, `https://cdn.betterttv.net` upstream
+    pub cdn: Url,
+    /// live updates websocket, `wss://sockets.betterttv.net/ws` upstream
+    pub socket: Url,
+}
+
+impl Default for BttvUrls {
+    fn default() -> Self {
+        Self {
+            api: Url::parse("https://api.betterttv.net/3").expect("hardcoded url must be valid"),
+            cdn: Url::parse("https://cdn.betterttv.net").expect("hardcoded url must be valid"),
+            socket: Url::parse("wss://sockets.betterttv.net/ws")
+                .expect("hardcoded url must be valid"),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BttvClient {
     client: reqwest::Client,
-    emote_cache: Arc<Cache<String, Emote>>,
+    urls: BttvUrls,
+    fetcher: EmoteFetcher,
+    globals: GlobalEmotes,
     user_cache: Arc<Cache<String, Arc<UserEmotes>>>,
+    socket: Option<Arc<BttvSocket>>,
 }
 
 impl BttvClient {
     pub fn new() -> Self {
-        let emote_cache = Arc::new(Cache::new(EMOTE_CACHE_MAX_AGE));
+        Self::with_urls(BttvUrls::default())
+    }
+
+    pub fn with_urls(urls: BttvUrls) -> Self {
+        let client = reqwest::Client::new();
+        let fetcher = EmoteFetcher::new(
+            Platform::BetterTtv,
+            client.clone(),
+            EMOTE_SCALES,
+            "image/png, image/webp, image/gif",
+        );
         let user_cache = Arc::new(Cache::new(USER_CACHE_MAX_AGE));
 
         tokio::spawn(platform_cache_evictor(
             Arc::downgrade(&user_cache),
             Duration::from_secs(60 * 15),
-            Arc::downgrade(&emote_cache),
+            Arc::downgrade(fetcher.cache()),
             Duration::from_secs(60 * 15),
         ));
 
+        let globals = GlobalEmotes::new(
+            Platform::BetterTtv,
+            GLOBAL_CACHE_MAX_AGE,
+            GLOBAL_RETRY_BACKOFF,
+            {
+                let client = client.clone();
+                let url = endpoint(&urls.api, ["cached", "emotes", "global"]);
+                move || fetch_global_emotes(client.clone(), url.clone())
+            },
+        );
+
         Self {
-            client: reqwest::Client::new(),
-            emote_cache,
+            client,
+            urls,
+            fetcher,
+            globals,
             user_cache,
+            socket: None,
         }
     }
+
+    /// connects to the BTTV websocket in the background, so cached channel
+    /// emotes get patched as soon as they change upstream
+    pub fn enable_socket(&mut self) {
+        if self.socket.is_none() {
+            self.socket = Some(Arc::new(BttvSocket::spawn(
+                self.urls.socket.clone(),
+                Arc::downgrade(&self.user_cache),
+            )));
+        }
+    }
+
+    /// notifications for every channel whose emotes changed, `None` if the
+    /// websocket isn't enabled
+    pub fn updates(&self) -> Option<broadcast::Receiver<ChannelUpdate>> {
+        self.socket.as_ref().map(|s| s.updates())
+    }
+
+    /// where its emotes get decoded, cached and stored
+    pub fn fetcher_mut(&mut self) -> &mut EmoteFetcher {
+        &mut self.fetcher
+    }
 }
 
 impl EmotePlatform for BttvClient {
@@ -54,17 +138,24 @@ impl EmotePlatform for BttvClient {
     where
         for<'a> &'a Self::InternalEmoteType: IntoIterator<Item = super::channel::ChannelEmote>,
     {
+        if let Some(socket) = &self.socket {
+            socket.join(twitch_id);
+        }
+
         if let Some(hit) = self.user_cache.get(twitch_id) {
             return Ok(hit.clone());
         }
 
+        let resp = self
+            .client
+            .get(endpoint(
+                &self.urls.api,
+                ["cached", "users", "twitch", twitch_id],
+            ))
+            .send()
+            .await?;
         let emotes: Arc<UserEmotes> = Arc::new(
-            self.client
-                .get(format!(
-                    "https://api.betterttv.net/3/cached/users/twitch/{twitch_id}"
-                ))
-                .send()
-                .await?
+            check_channel_response(resp, Platform::BetterTtv)?
                 .json()
                 .await
                 .map_err(|e| e.without_url())?,
@@ -74,51 +165,25 @@ impl EmotePlatform for BttvClient {
         Ok(emotes)
     }
 
-    async fn get_emote_by_id(&self, id: &str) -> Result<Emote, PlatformError> {
-        if let Some(hit) = self.emote_cache.get(id) {
-            debug!("cache hit for BTTV emote {id}");
-            return Ok(hit.clone());
-        }
-
-        debug!("requesting BTTV emote {id}");
-        let resp = self
-            .client
-            .get(format!("https://cdn.betterttv.net/emote/{id}/3x"))
-            .header(ACCEPT, "image/png, image/webp, image/gif")
-            .send()
+    async fn get_emote_by_id_sized(
+        &self,
+        id: &str,
+        resolution: Resolution,
+    ) -> Result<Emote, PlatformError> {
+        let cdn = self.urls.cdn.clone();
+        let id_owned = id.to_owned();
+        self.fetcher
+            .get(id, resolution, move |scale| {
+                ready(Ok(endpoint(
+                    &cdn,
+                    ["emote", &id_owned, &format!("{scale}x")],
+                )))
+            })
             .await
-            .map_err(|e| e.without_url())?;
-
-        let emote = Emote::try_from_response(resp, id).await?;
-        self.emote_cache.insert(id.into(), emote.clone());
-        Ok(emote)
     }
 
     async fn get_global_emotes(&self) -> Result<Arc<DashMap<String, ChannelEmote>>, PlatformError> {
-        static BTTV_GLOBALS: OnceCell<(Arc<DashMap<String, ChannelEmote>>, Instant)> =
-            OnceCell::const_new();
-
-        let gotten = BTTV_GLOBALS
-            .get_or_try_init(|| async {
-                let resp = self
-                    .client
-                    .get("https://api.betterttv.net/3/cached/emotes/global")
-                    .send()
-                    .await?
-                    .json::<Vec<BttvEmote>>()
-                    .await?;
-                let emotes = resp
-                    .into_iter()
-                    .map(|e| (e.code.clone(), e.into()))
-                    .collect();
-                Result::<(Arc<DashMap<String, ChannelEmote>>, Instant), PlatformError>::Ok((
-                    Arc::new(emotes),
-                    Instant::now(),
-                ))
-            })
-            .await?;
-
-        Ok(gotten.0.clone())
+        self.globals.get().await
     }
 }
 
@@ -128,7 +193,22 @@ impl Default for BttvClient {
     }
 }
 
-#[derive(Debug, Deserialize)]
+async fn fetch_global_emotes(
+    client: reqwest::Client,
+    url: Url,
+) -> Result<GlobalSet, PlatformError> {
+    let resp = client.get(url).send().await?;
+    let resp = check_response(resp, Platform::BetterTtv)?
+        .json::<Vec<BttvEmote>>()
+        .await?;
+    let emotes = resp
+        .into_iter()
+        .map(|e| (e.code.clone(), e.into()))
+        .collect();
+    Ok(Arc::new(emotes))
+}
+
+#[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UserEmotes {
     pub shared_emotes: Vec<BttvEmote>,
@@ -151,7 +231,7 @@ impl<'a> IntoIterator for &'a UserEmotes {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct BttvEmote {
     pub id: String,
     pub code: String,
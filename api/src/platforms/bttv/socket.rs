@@ -0,0 +1,316 @@
+//! subscriber for the BetterTTV websocket, joins channels as they're requested
+//! and keeps their cached emotes up to date with `emote_create`,
+//! `emote_update` and `emote_delete` events
+
+use std::{
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use futures::{SinkExt, StreamExt};
+use hashbrown::HashSet;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::{broadcast, mpsc};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::{
+    cache::Cache,
+    platforms::{
+        channel::ChannelEmote,
+        websocket::{self, SessionEnd, Socket, Subscriber},
+        ChannelUpdate, Platform,
+    },
+};
+
+use super::{BttvEmote, UserEmotes};
+
+/// channels that haven't been requested in this long get parted
+const CHANNEL_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(60 * 15);
+/// BTTV never talks unless something changes, so we ping it to notice dead
+/// connections
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// how long a ping gets to be answered, the connection's dead if nothing at
+/// all came back by then
+const PONG_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// handle to the background BTTV websocket connection, the connection is
+/// closed once every handle is dropped
+#[derive(Debug)]
+pub struct BttvSocket {
+    channels: Arc<DashMap<String, Instant>>,
+    commands: mpsc::UnboundedSender<String>,
+    updates: broadcast::Sender<ChannelUpdate>,
+}
+
+impl BttvSocket {
+    pub(super) fn spawn(url: Url, user_cache: Weak<Cache<String, Arc<UserEmotes>>>) -> Self {
+        let channels = Arc::new(DashMap::new());
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        let (updates, _) = broadcast::channel(256);
+
+        tokio::spawn(websocket::run(BttvSocketTask {
+            url,
+            channels: channels.clone(),
+            commands: commands_rx,
+            updates: updates.clone(),
+            user_cache,
+        }));
+
+        Self {
+            channels,
+            commands,
+            updates,
+        }
+    }
+
+    /// joins the channel for `twitch_id`, or just marks it as still in use if
+    /// it was already joined
+    pub fn join(&self, twitch_id: &str) {
+        let previous = self.channels.insert(twitch_id.into(), Instant::now());
+        if previous.is_none() {
+            let _ = self.commands.send(twitch_id.into());
+        }
+    }
+
+    pub fn updates(&self) -> broadcast::Receiver<ChannelUpdate> {
+        self.updates.subscribe()
+    }
+}
+
+struct BttvSocketTask {
+    url: Url,
+    channels: Arc<DashMap<String, Instant>>,
+    commands: mpsc::UnboundedReceiver<String>,
+    updates: broadcast::Sender<ChannelUpdate>,
+    user_cache: Weak<Cache<String, Arc<UserEmotes>>>,
+}
+
+impl Subscriber for BttvSocketTask {
+    const NAME: &'static str = "BTTV websocket";
+
+    fn url(&self) -> &Url {
+        &self.url
+    }
+
+    fn is_closed(&self) -> bool {
+        self.commands.is_closed()
+    }
+
+    async fn session(&mut self, socket: Socket) -> SessionEnd {
+        let (mut sink, mut stream) = socket.split();
+        let mut joined = HashSet::new();
+
+        let twitch_ids: Vec<String> = self.channels.iter().map(|c| c.key().clone()).collect();
+        for twitch_id in twitch_ids {
+            if sink
+                .send(channel_message("join_channel", &twitch_id))
+                .await
+                .is_err()
+            {
+                return SessionEnd::Reconnect;
+            }
+            joined.insert(twitch_id);
+        }
+
+        let mut reconcile = tokio::time::interval(RECONCILE_INTERVAL);
+        reconcile.reset();
+        let mut ping = tokio::time::interval(PING_INTERVAL);
+        ping.reset();
+        let mut last_message = Instant::now();
+
+        loop {
+            let deadline = last_message + PING_INTERVAL + PONG_TIMEOUT;
+
+            tokio::select! {
+                msg = stream.next() => {
+                    last_message = Instant::now();
+                    match msg {
+                        Some(Ok(Message::Text(text))) => self.handle_message(&text),
+                        Some(Ok(Message::Close(_))) | None => return SessionEnd::Reconnect,
+                        Some(Err(e)) => {
+                            warn!("BTTV websocket connection failed: {e}");
+                            return SessionEnd::Reconnect;
+                        }
+                        // pongs too
+                        Some(Ok(_)) => (),
+                    }
+                }
+                cmd = self.commands.recv() => match cmd {
+                    Some(twitch_id) => {
+                        if !joined.contains(&twitch_id) {
+                            debug!("joining BTTV channel {twitch_id}");
+                            if sink.send(channel_message("join_channel", &twitch_id)).await.is_err() {
+                                return SessionEnd::Reconnect;
+                            }
+                            joined.insert(twitch_id);
+                        }
+                    }
+                    None => {
+                        let _ = sink.close().await;
+                        return SessionEnd::Shutdown;
+                    }
+                },
+                _ = reconcile.tick() => {
+                    let now = Instant::now();
+                    let mut idle = Vec::new();
+                    self.channels.retain(|twitch_id, last_used| {
+                        let keep = now < *last_used + CHANNEL_IDLE_TIMEOUT;
+                        if !keep {
+                            idle.push(twitch_id.clone());
+                        }
+                        keep
+                    });
+                    for twitch_id in idle {
+                        debug!("parting idle BTTV channel {twitch_id}");
+                        joined.remove(&twitch_id);
+                        if sink.send(channel_message("part_channel", &twitch_id)).await.is_err() {
+                            return SessionEnd::Reconnect;
+                        }
+                    }
+                }
+                _ = ping.tick() => {
+                    if sink.send(Message::Ping(Vec::new())).await.is_err() {
+                        return SessionEnd::Reconnect;
+                    }
+                }
+                _ = tokio::time::sleep_until(deadline.into()) => {
+                    warn!("BTTV websocket stopped answering pings, reconnecting");
+                    return SessionEnd::Reconnect;
+                }
+            }
+        }
+    }
+
+    fn invalidate_all(&self) {
+        let twitch_ids: Vec<String> = self.channels.iter().map(|c| c.key().clone()).collect();
+
+        let cache = self.user_cache.upgrade();
+        for twitch_id in twitch_ids {
+            if let Some(cache) = &cache {
+                cache.remove(&twitch_id);
+            }
+            let _ = self.updates.send(ChannelUpdate {
+                platform: Platform::BetterTtv,
+                twitch_id,
+                emotes: None,
+            });
+        }
+    }
+}
+
+impl BttvSocketTask {
+    fn handle_message(&self, text: &str) {
+        let event = match serde_json::from_str::<Event>(text) {
+            Ok(e) => e,
+            Err(e) => {
+                warn!("bad message from BTTV websocket: {e}");
+                return;
+            }
+        };
+
+        let change = match event.name.as_str() {
+            "emote_create" => EmoteChange::Create,
+            "emote_update" => EmoteChange::Update,
+            "emote_delete" => EmoteChange::Delete,
+            _ => return,
+        };
+
+        let Some(twitch_id) = event
+            .data
+            .get("channel")
+            .and_then(|c| c.as_str())
+            .and_then(|c| c.strip_prefix("twitch:"))
+            .map(String::from)
+        else {
+            return;
+        };
+
+        debug!("BTTV emotes for {twitch_id} were updated");
+
+        let mut emotes = None;
+        if let Some(cache) = self.user_cache.upgrade() {
+            let patched = cache.get_mut(&twitch_id).map(|mut hit| {
+                let user = Arc::make_mut(&mut *hit);
+                patch_user_emotes(user, change, &event.data)
+                    .map(|_| user.into_iter().collect::<Arc<[ChannelEmote]>>())
+            });
+            match patched {
+                Some(Ok(patched)) => emotes = Some(patched),
+                Some(Err(e)) => {
+                    warn!("couldn't apply BTTV emote update, dropping cached emotes: {e}");
+                    cache.remove(&twitch_id);
+                }
+                None => (),
+            }
+        }
+
+        let _ = self.updates.send(ChannelUpdate {
+            platform: Platform::BetterTtv,
+            twitch_id,
+            emotes,
+        });
+    }
+}
+
+fn channel_message(name: &str, twitch_id: &str) -> Message {
+    Message::Text(
+        json!({
+            "name": name,
+            "data": { "name": format!("twitch:{twitch_id}") },
+        })
+        .to_string(),
+    )
+}
+
+#[derive(Debug, Clone, Copy)]
+enum EmoteChange {
+    Create,
+    Update,
+    Delete,
+}
+
+fn patch_user_emotes(
+    user: &mut UserEmotes,
+    change: EmoteChange,
+    data: &serde_json::Value,
+) -> Result<(), serde_json::Error> {
+    match change {
+        EmoteChange::Create => {
+            let emote = BttvEmote::deserialize(&data["emote"])?;
+            user.channel_emotes.retain(|e| e.id != emote.id);
+            user.channel_emotes.push(emote);
+        }
+        EmoteChange::Update => {
+            let update = EmoteRename::deserialize(&data["emote"])?;
+            user.channel_emotes
+                .iter_mut()
+                .chain(user.shared_emotes.iter_mut())
+                .filter(|e| e.id == update.id)
+                .for_each(|e| e.code = update.code.clone());
+        }
+        EmoteChange::Delete => {
+            let id = String::deserialize(&data["emoteId"])?;
+            user.channel_emotes.retain(|e| e.id != id);
+            user.shared_emotes.retain(|e| e.id != id);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct Event {
+    name: String,
+    #[serde(default)]
+    data: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmoteRename {
+    id: String,
+    code: String,
+}
@@ -4,7 +4,7 @@ use super::{
     bttv::BttvEmote,
     ffz::FfzEmote,
     seventv::SevenTvEmote,
-    twitch::{TwitchEmote, TwitchEmoteFormat},
+    twitch::{TwitchEmote, TwitchEmoteFormat, TwitchEmoteMetadata},
     Platform,
 };
 
@@ -14,6 +14,9 @@ pub struct ChannelEmote {
     pub id: String,
     pub name: String,
     pub animated: bool,
+    /// only there for twitch channel emotes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub twitch: Option<TwitchEmoteMetadata>,
 }
 
 impl From<SevenTvEmote> for ChannelEmote {
@@ -23,6 +26,7 @@ impl From<SevenTvEmote> for ChannelEmote {
             id: value.id,
             name: value.name,
             animated: value.data.animated,
+            twitch: None,
         }
     }
 }
@@ -34,6 +38,7 @@ impl From<&SevenTvEmote> for ChannelEmote {
             id: value.id.clone(),
             name: value.name.clone(),
             animated: value.data.animated,
+            twitch: None,
         }
     }
 }
@@ -45,6 +50,7 @@ impl From<BttvEmote> for ChannelEmote {
             id: value.id,
             name: value.code,
             animated: value.animated,
+            twitch: None,
         }
     }
 }
@@ -56,6 +62,7 @@ impl From<&BttvEmote> for ChannelEmote {
             id: value.id.clone(),
             name: value.code.clone(),
             animated: value.animated,
+            twitch: None,
         }
     }
 }
@@ -67,6 +74,7 @@ impl From<FfzEmote> for ChannelEmote {
             id: value.id.map_left(|id| id.to_string()).into_inner(),
             name: value.name,
             animated: value.animated.is_some(),
+            twitch: None,
         }
     }
 }
@@ -78,6 +86,7 @@ impl From<&FfzEmote> for ChannelEmote {
             id: value.id.clone().map_left(|id| id.to_string()).into_inner(),
             name: value.name.clone(),
             animated: value.animated.is_some(),
+            twitch: None,
         }
     }
 }
@@ -86,6 +95,7 @@ impl From<TwitchEmote> for ChannelEmote {
     fn from(value: TwitchEmote) -> Self {
         Self {
             platform: Platform::Twitch,
+            twitch: value.metadata(),
             id: value.id,
             name: value.name,
             animated: value.format.contains(&TwitchEmoteFormat::Animated),
@@ -100,6 +110,7 @@ impl From<&TwitchEmote> for ChannelEmote {
             id: value.id.clone(),
             name: value.name.clone(),
             animated: value.format.contains(&TwitchEmoteFormat::Animated),
+            twitch: value.metadata(),
         }
     }
 }
@@ -0,0 +1,156 @@
+//! single emotes by id, the same for every platform: the in memory cache,
+//! the store under it, the memory budget, and downloading each one only once
+//! however many ask for it at the same time
+
+use std::{future::Future, sync::Arc};
+
+use reqwest::header::ACCEPT;
+use tracing::debug;
+use url::Url;
+
+use crate::{
+    cache::{Cache, MemoryBudget, MemorySize},
+    emote::{resolution::Resolution, Emote, EmoteOptions},
+    store::EmoteStore,
+};
+
+use super::{Flights, Platform, PlatformError, EMOTE_CACHE_MAX_AGE};
+
+pub type EmoteKey = (String, Resolution);
+
+/// a platform's emotes, each client owns one and only says where on its CDN
+/// they are
+#[derive(Debug, Clone)]
+pub struct EmoteFetcher {
+    platform: Platform,
+    client: reqwest::Client,
+    /// sizes the CDN has, anything else gets downscaled from the next one up
+    scales: &'static [u8],
+    /// sent as `Accept` when downloading them
+    accept: &'static str,
+    cache: Arc<Cache<EmoteKey, Emote>>,
+    options: EmoteOptions,
+    store: EmoteStore,
+    budget: Arc<MemoryBudget>,
+    flights: Flights<EmoteKey, Emote>,
+}
+
+impl EmoteFetcher {
+    pub fn new(
+        platform: Platform,
+        client: reqwest::Client,
+        scales: &'static [u8],
+        accept: &'static str,
+    ) -> Self {
+        Self {
+            platform,
+            client,
+            scales,
+            accept,
+            cache: Arc::new(Cache::new(EMOTE_CACHE_MAX_AGE)),
+            options: EmoteOptions::default(),
+            store: EmoteStore::default(),
+            budget: Arc::default(),
+            flights: Arc::default(),
+        }
+    }
+
+    pub fn cache(&self) -> &Arc<Cache<EmoteKey, Emote>> {
+        &self.cache
+    }
+
+    /// how emotes get decoded from now on, ones that are already cached
+    /// stay as they are
+    pub fn set_emote_options(&mut self, options: EmoteOptions) {
+        self.options = options;
+    }
+
+    /// where emotes that aren't in memory get looked for before downloading
+    /// them, and get written to after
+    pub fn set_store(&mut self, store: EmoteStore) {
+        self.store = store;
+    }
+
+    /// counts cached emotes towards `budget`, which throws them out when
+    /// it's over
+    pub fn set_memory_budget(&mut self, budget: Arc<MemoryBudget>) {
+        budget.register(self.platform.name(), &self.cache);
+        self.budget = budget;
+    }
+
+    /// the emote at `resolution`, `url` only gets called with the scale to
+    /// download if it isn't cached or stored
+    pub async fn get<F, Fut>(
+        &self,
+        id: &str,
+        resolution: Resolution,
+        url: F,
+    ) -> Result<Emote, PlatformError>
+    where
+        F: FnOnce(u8) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<Url, PlatformError>> + Send,
+    {
+        let resolution = resolution.normalize(self.scales);
+        let key = (id.to_owned(), resolution);
+        if let Some(hit) = self.cache.get(&key) {
+            debug!("cache hit for {} emote {id}", self.platform);
+            return Ok(hit.clone());
+        }
+
+        let this = self.clone();
+        let id = id.to_owned();
+        self.flights
+            .run(key, || async move {
+                this.fetch(&id, resolution, url).await.map_err(Arc::new)
+            })
+            .await
+            .map_err(PlatformError::unshare)
+    }
+
+    /// [`Self::get`] once nobody else is fetching the same emote, from the
+    /// store or the CDN
+    async fn fetch<F, Fut>(
+        &self,
+        id: &str,
+        resolution: Resolution,
+        url: F,
+    ) -> Result<Emote, PlatformError>
+    where
+        F: FnOnce(u8) -> Fut,
+        Fut: Future<Output = Result<Url, PlatformError>>,
+    {
+        let platform = self.platform;
+        let key = (id.to_owned(), resolution);
+        // whoever fetched it just before might've finished in the meantime
+        if let Some(hit) = self.cache.get(&key) {
+            return Ok(hit.clone());
+        }
+        if let Some(stored) = self
+            .store
+            .load(platform, id, resolution, &self.options)
+            .await
+        {
+            debug!("store hit for {platform} emote {id}");
+            self.cache.insert(key, stored.clone());
+            self.budget.charge(stored.memory_size());
+            return Ok(stored);
+        }
+
+        debug!("requesting {platform} emote {id} at {resolution}");
+        let (scale, downscale) = resolution.pick(self.scales);
+        let resp = self
+            .client
+            .get(url(scale).await?)
+            .header(ACCEPT, self.accept)
+            .send()
+            .await
+            .map_err(|e| e.without_url())?;
+
+        let emote =
+            Emote::try_from_response(resp, id, resolution, downscale, &self.options).await?;
+        self.cache.insert(key, emote.clone());
+        self.budget.charge(emote.memory_size());
+        self.store.spawn_save(platform, &emote);
+        Ok(emote)
+    }
+}
@@ -1,47 +1,102 @@
-use std::{
-    sync::Arc,
-    time::{Duration, Instant},
-};
+use std::{sync::Arc, time::Duration};
 
 use dashmap::DashMap;
 use hashbrown::HashMap;
 use http::StatusCode;
-use reqwest::header::ACCEPT;
 use serde::{de::IgnoredAny, Deserialize};
-use tokio::sync::OnceCell;
+use url::Url;
 
-use crate::{cache::Cache, emote::Emote, platforms::channel::ChannelEmote};
+use crate::{
+    cache::Cache,
+    emote::{resolution::Resolution, Emote},
+    platforms::channel::ChannelEmote,
+};
 
 use super::{
-    cache::platform_cache_evictor, EmotePlatform, PlatformError, EMOTE_CACHE_MAX_AGE,
+    cache::platform_cache_evictor,
+    check_channel_response, check_response, endpoint,
+    fetcher::EmoteFetcher,
+    globals::{GlobalEmotes, GlobalSet},
+    EmotePlatform, Platform, PlatformError, GLOBAL_CACHE_MAX_AGE, GLOBAL_RETRY_BACKOFF,
     USER_CACHE_MAX_AGE,
 };
 
+/// sizes on the FFZ CDN, there's no 3
+const EMOTE_SCALES: &[u8] = &[1, 2, 4];
+
+#[derive(Debug, Clone)]
+pub struct FfzUrls {
+    /// REST API, `https://api.frankerfacez.com/v1` upstream
+    pub api: Url,
+    /// image CDN, `https://cdn.frankerfacez.com` upstream
+    pub cdn: Url,
+}
+
+impl Default for FfzUrls {
+    fn default() -> Self {
+        Self {
+            api: Url::parse("https://api.frankerfacez.com/v1")
+                .expect("hardcoded url must be valid"),
+            cdn: Url::parse("https://cdn.frankerfacez.com").expect("hardcoded url must be valid"),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct FfzClient {
     client: reqwest::Client,
-    emote_cache: Arc<Cache<String, Emote>>,
+    urls: FfzUrls,
+    fetcher: EmoteFetcher,
+    globals: GlobalEmotes,
     user_cache: Arc<Cache<String, Arc<RoomEmotes>>>,
 }
 
 impl FfzClient {
     pub fn new() -> Self {
-        let emote_cache = Arc::new(Cache::new(EMOTE_CACHE_MAX_AGE));
+        Self::with_urls(FfzUrls::default())
+    }
+
+    pub fn with_urls(urls: FfzUrls) -> Self {
+        let client = reqwest::Client::new();
+        let fetcher = EmoteFetcher::new(
+            Platform::FrancerFaceZ,
+            client.clone(),
+            EMOTE_SCALES,
+            "image/webp, image/png, image/gif",
+        );
         let user_cache = Arc::new(Cache::new(USER_CACHE_MAX_AGE));
 
         tokio::spawn(platform_cache_evictor(
             Arc::downgrade(&user_cache),
             Duration::from_secs(60 * 15),
-            Arc::downgrade(&emote_cache),
+            Arc::downgrade(fetcher.cache()),
             Duration::from_secs(60 * 15),
         ));
 
+        let globals = GlobalEmotes::new(
+            Platform::FrancerFaceZ,
+            GLOBAL_CACHE_MAX_AGE,
+            GLOBAL_RETRY_BACKOFF,
+            {
+                let client = client.clone();
+                let url = endpoint(&urls.api, ["set", "global", "ids"]);
+                move || fetch_global_emotes(client.clone(), url.clone())
+            },
+        );
+
         Self {
-            client: reqwest::Client::new(),
-            emote_cache,
+            client,
+            urls,
+            fetcher,
+            globals,
             user_cache,
         }
     }
+
+    /// where its emotes get decoded, cached and stored
+    pub fn fetcher_mut(&mut self) -> &mut EmoteFetcher {
+        &mut self.fetcher
+    }
 }
 
 impl EmotePlatform for FfzClient {
@@ -58,13 +113,13 @@ impl EmotePlatform for FfzClient {
             return Ok(hit.clone());
         }
 
+        let resp = self
+            .client
+            .get(endpoint(&self.urls.api, ["room", "id", twitch_id]))
+            .send()
+            .await?;
         let emotes = Arc::new(
-            self.client
-                .get(format!(
-                    "https://api.frankerfacez.com/v1/room/id/{twitch_id}"
-                ))
-                .send()
-                .await?
+            check_channel_response(resp, Platform::FrancerFaceZ)?
                 .json::<RoomEmotes>()
                 .await?,
         );
@@ -73,79 +128,46 @@ impl EmotePlatform for FfzClient {
         Ok(emotes)
     }
 
-    async fn get_emote_by_id(&self, id: &str) -> Result<Emote, PlatformError> {
-        if let Some(hit) = self.emote_cache.get(id) {
-            return Ok(hit.clone());
-        }
-
-        // TODO: get rid of this query, we already store whether an emote is
-        // animated or not
-        let emote_query = self
-            .client
-            .get(format!("https://api.frankerfacez.com/v1/emote/{id}"))
-            .send()
-            .await
-            .map_err(|e| e.without_url())?;
-
-        if emote_query.status() == StatusCode::NOT_FOUND {
-            return Err(PlatformError::EmoteNotFound);
-        }
-
-        let url = if emote_query
-            .json::<FfzEmoteQuery>()
-            .await?
-            .emote
-            .animated
-            .is_some()
-        {
-            format!("https://cdn.frankerfacez.com/emote/{id}/animated/4")
-        } else {
-            format!("https://cdn.frankerfacez.com/emote/{id}/4")
-        };
+    async fn get_emote_by_id_sized(
+        &self,
+        id: &str,
+        resolution: Resolution,
+    ) -> Result<Emote, PlatformError> {
+        let client = self.client.clone();
+        let urls = self.urls.clone();
+        let id_owned = id.to_owned();
+        self.fetcher
+            .get(id, resolution, move |scale| async move {
+                // TODO: get rid of this query, we already store whether an emote is
+                // animated or not
+                let emote_query = client
+                    .get(endpoint(&urls.api, ["emote", &id_owned]))
+                    .send()
+                    .await
+                    .map_err(|e| e.without_url())?;
+
+                if emote_query.status() == StatusCode::NOT_FOUND {
+                    return Err(PlatformError::EmoteNotFound);
+                }
 
-        let resp = self
-            .client
-            .get(url)
-            .header(ACCEPT, "image/webp, image/png, image/gif")
-            .send()
+                let scale = scale.to_string();
+                let animated = emote_query
+                    .json::<FfzEmoteQuery>()
+                    .await?
+                    .emote
+                    .animated
+                    .is_some();
+                Ok(if animated {
+                    endpoint(&urls.cdn, ["emote", &id_owned, "animated", &scale])
+                } else {
+                    endpoint(&urls.cdn, ["emote", &id_owned, &scale])
+                })
+            })
             .await
-            .map_err(|e| e.without_url())?;
-
-        Ok(Emote::try_from_response(resp, id).await?)
     }
 
     async fn get_global_emotes(&self) -> Result<Arc<DashMap<String, ChannelEmote>>, PlatformError> {
-        static FFZ_GLOBALS: OnceCell<(Arc<DashMap<String, ChannelEmote>>, Instant)> =
-            OnceCell::const_new();
-
-        let gotten = FFZ_GLOBALS
-            .get_or_try_init(|| {
-                async {
-                    let resp = self
-                        .client
-                        .get("https://api.frankerfacez.com/v1/set/global/ids")
-                        .send()
-                        .await?
-                        .json::<DefaultSets>()
-                        .await?;
-
-                    // HOLY MOLY
-                    let emotes: DashMap<String, ChannelEmote> = resp
-                        .sets
-                        .into_values()
-                        .filter(|set| resp.default_sets.contains(&set.id))
-                        .flat_map(|s| s.emoticons)
-                        .map(|e| (e.name.clone(), e.into()))
-                        .collect();
-                    Result::<(Arc<DashMap<String, ChannelEmote>>, Instant), PlatformError>::Ok((
-                        Arc::new(emotes),
-                        Instant::now(),
-                    ))
-                }
-            })
-            .await?;
-
-        Ok(gotten.0.clone())
+        self.globals.get().await
     }
 }
 
@@ -155,6 +177,26 @@ impl Default for FfzClient {
     }
 }
 
+async fn fetch_global_emotes(
+    client: reqwest::Client,
+    url: Url,
+) -> Result<GlobalSet, PlatformError> {
+    let resp = client.get(url).send().await?;
+    let resp = check_response(resp, Platform::FrancerFaceZ)?
+        .json::<DefaultSets>()
+        .await?;
+
+    // HOLY MOLY
+    let emotes: DashMap<String, ChannelEmote> = resp
+        .sets
+        .into_values()
+        .filter(|set| resp.default_sets.contains(&set.id))
+        .flat_map(|s| s.emoticons)
+        .map(|e| (e.name.clone(), e.into()))
+        .collect();
+    Ok(Arc::new(emotes))
+}
+
 #[derive(Debug, Deserialize)]
 pub struct DefaultSets {
     default_sets: Vec<u64>,
@@ -182,7 +224,7 @@ pub struct RoomEmotes {
     pub sets: HashMap<String, FfzSet>,
 }
 
-impl<'a> IntoIterator for &'a RoomEmotes {
+impl IntoIterator for &RoomEmotes {
     type Item = ChannelEmote;
 
     type IntoIter = impl Iterator<Item = ChannelEmote>;
@@ -0,0 +1,309 @@
+//! every platform's global emotes, kept per client and refreshed in the
+//! background once they're old
+
+use std::{
+    future::Future,
+    sync::{Arc, OnceLock, Weak},
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use futures::{future::BoxFuture, FutureExt};
+use parking_lot::Mutex;
+use tracing::{debug, warn};
+
+use super::{channel::ChannelEmote, Flights, Platform, PlatformError};
+
+pub type GlobalSet = Arc<DashMap<String, ChannelEmote>>;
+
+type Fetch = dyn Fn() -> BoxFuture<'static, Result<GlobalSet, PlatformError>> + Send + Sync;
+
+#[derive(Debug, Default)]
+struct State {
+    /// the last ones that worked, and when they were fetched
+    emotes: Option<(GlobalSet, Instant)>,
+    /// in a row since the last time it worked
+    failures: u32,
+    /// why the last one failed, handed out until `retry_at` when there's
+    /// nothing else to hand out
+    last_error: Option<Arc<PlatformError>>,
+    retry_at: Option<Instant>,
+}
+
+struct Inner {
+    platform: Platform,
+    max_age: Duration,
+    /// wait after the first failure, doubling with every one after that up
+    /// to `max_age`
+    backoff: Duration,
+    fetch: Box<Fetch>,
+    state: Mutex<State>,
+    flights: Flights<(), GlobalSet>,
+    /// set once the timer that keeps them fresh is running
+    refresher: OnceLock<()>,
+}
+
+impl std::fmt::Debug for Inner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Inner")
+            .field("platform", &self.platform)
+            .field("max_age", &self.max_age)
+            .field("backoff", &self.backoff)
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+/// a platform's global emotes. after the first time they're asked for they
+/// get fetched again every max age on a timer, and if that fails they're
+/// kept until it works. failures get retried less and less often
+#[derive(Debug, Clone)]
+pub struct GlobalEmotes(Arc<Inner>);
+
+impl State {
+    /// the last error, if it's too soon to try again
+    fn backing_off(&self) -> Option<Arc<PlatformError>> {
+        self.retry_at
+            .filter(|at| Instant::now() < *at)
+            .and(self.last_error.clone())
+    }
+
+    /// when they should be fetched again, once they're old or once it's
+    /// been long enough since the last failure
+    fn refresh_at(&self, max_age: Duration) -> Instant {
+        self.retry_at
+            .or_else(|| self.emotes.as_ref().map(|(_, fetched)| *fetched + max_age))
+            .unwrap_or_else(|| Instant::now() + max_age)
+    }
+}
+
+impl GlobalEmotes {
+    /// `fetch` gets them from upstream. it shouldn't hold on to whatever owns
+    /// these, or the timer keeps them both alive forever
+    pub fn new<F, Fut>(platform: Platform, max_age: Duration, backoff: Duration, fetch: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<GlobalSet, PlatformError>> + Send + 'static,
+    {
+        Self(Arc::new(Inner {
+            platform,
+            max_age,
+            backoff,
+            fetch: Box::new(move || fetch().boxed()),
+            state: Mutex::default(),
+            flights: Arc::default(),
+            refresher: OnceLock::new(),
+        }))
+    }
+
+    /// the global emotes, only fetched here if they're missing. old ones
+    /// come back straight away, in case the timer hasn't gotten to them yet
+    pub async fn get(&self) -> Result<GlobalSet, PlatformError> {
+        let (cached, backing_off) = {
+            let state = self.0.state.lock();
+            (state.emotes.clone(), state.backing_off())
+        };
+
+        match cached {
+            Some((emotes, fetched)) if fetched.elapsed() < self.0.max_age => Ok(emotes),
+            Some((emotes, _)) => {
+                if backing_off.is_none() {
+                    let this = self.clone();
+                    tokio::spawn(async move {
+                        // already logged
+                        let _ = this.refresh().await;
+                    });
+                }
+                Ok(emotes)
+            }
+            None => match backing_off {
+                Some(e) => Err(PlatformError::Shared(e)),
+                None => self.refresh().await,
+            },
+        }
+    }
+
+    /// fetches them now, once for everyone who's waiting
+    async fn refresh(&self) -> Result<GlobalSet, PlatformError> {
+        let inner = self.0.clone();
+        let result = self
+            .0
+            .flights
+            .run((), || async move {
+                debug!("requesting {} global emotes", inner.platform);
+                let result = (inner.fetch)().await.map_err(Arc::new);
+                inner.finished(&result);
+                result
+            })
+            .await
+            .map_err(PlatformError::unshare);
+
+        // they're wanted, so they're kept fresh from now on
+        self.0
+            .refresher
+            .get_or_init(|| drop(tokio::spawn(refresher(Arc::downgrade(&self.0)))));
+        result
+    }
+}
+
+/// fetches them again whenever they're due, until they're dropped. boxed
+/// since it's spawned from [`GlobalEmotes::refresh`] and calls it too
+fn refresher(inner: Weak<Inner>) -> BoxFuture<'static, ()> {
+    async move {
+        loop {
+            let at = match inner.upgrade() {
+                Some(inner) => inner.state.lock().refresh_at(inner.max_age),
+                None => return,
+            };
+            tokio::time::sleep_until(at.into()).await;
+            match inner.upgrade() {
+                // already logged
+                Some(inner) => drop(GlobalEmotes(inner).refresh().await),
+                None => return,
+            }
+        }
+    }
+    .boxed()
+}
+
+impl Inner {
+    fn finished(&self, result: &Result<GlobalSet, Arc<PlatformError>>) {
+        let mut state = self.state.lock();
+        match result {
+            Ok(emotes) => {
+                *state = State {
+                    emotes: Some((emotes.clone(), Instant::now())),
+                    ..Default::default()
+                };
+            }
+            Err(e) => {
+                state.failures += 1;
+                let backoff = self
+                    .backoff
+                    .saturating_mul(1 << (state.failures - 1).min(16))
+                    .min(self.max_age);
+                state.retry_at = Some(Instant::now() + backoff);
+                state.last_error = Some(e.clone());
+                warn!(
+                    "{} global emotes failed {} times in a row, trying again in {backoff:?}: {e}",
+                    self.platform, state.failures
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used, reason = "bruh this is a tests module")]
+
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use dashmap::DashMap;
+
+    use super::GlobalEmotes;
+    use crate::platforms::{channel::ChannelEmote, Platform, PlatformError};
+
+    /// counts its calls, and fails while `failing` is set
+    #[derive(Clone, Default)]
+    struct Upstream {
+        calls: Arc<AtomicUsize>,
+        failing: Arc<parking_lot::Mutex<bool>>,
+    }
+
+    impl Upstream {
+        fn globals(&self, max_age: Duration, backoff: Duration) -> GlobalEmotes {
+            let this = self.clone();
+            GlobalEmotes::new(Platform::SevenTv, max_age, backoff, move || {
+                let call = this.calls.fetch_add(1, Ordering::SeqCst);
+                futures::future::ready(if *this.failing.lock() {
+                    Err(PlatformError::PlatformError(Platform::SevenTv))
+                } else {
+                    let name = format!("call{call}");
+                    let emotes = DashMap::new();
+                    emotes.insert(
+                        name.clone(),
+                        ChannelEmote {
+                            platform: Platform::SevenTv,
+                            id: call.to_string(),
+                            name,
+                            animated: false,
+                            twitch: None,
+                        },
+                    );
+                    Ok(Arc::new(emotes))
+                })
+            })
+        }
+
+        fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+
+        async fn wait_for(&self, calls: usize) {
+            while self.calls() < calls {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn refreshes() {
+        let upstream = Upstream::default();
+        let globals = upstream.globals(Duration::from_millis(50), Duration::from_secs(60));
+
+        let first = globals.get().await.unwrap();
+        assert!(first.contains_key("call0"));
+        globals.get().await.unwrap();
+        assert_eq!(upstream.calls(), 1);
+
+        // fetched again once they're old, without anyone asking
+        upstream.wait_for(2).await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert!(globals.get().await.unwrap().contains_key("call1"));
+
+        // and when that fails they're kept, without asking again right away
+        *upstream.failing.lock() = true;
+        upstream.wait_for(3).await;
+        for _ in 0..3 {
+            let kept = globals.get().await.unwrap();
+            assert!(kept.contains_key("call1"));
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(upstream.calls(), 3);
+    }
+
+    #[tokio::test]
+    async fn stops_when_dropped() {
+        let upstream = Upstream::default();
+        let globals = upstream.globals(Duration::from_millis(20), Duration::from_secs(60));
+        globals.get().await.unwrap();
+        drop(globals);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(upstream.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn backs_off() {
+        let upstream = Upstream::default();
+        *upstream.failing.lock() = true;
+        let globals = upstream.globals(Duration::from_secs(60), Duration::from_millis(50));
+
+        assert!(globals.get().await.is_err());
+        // too soon, the same error comes back without asking
+        assert!(globals.get().await.is_err());
+        assert_eq!(upstream.calls(), 1);
+
+        *upstream.failing.lock() = false;
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(globals.get().await.is_ok());
+        assert_eq!(upstream.calls(), 2);
+    }
+}
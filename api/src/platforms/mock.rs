@@ -0,0 +1,348 @@
+//! fake upstream that serves the recorded responses in `fixtures/upstream`,
+//! so the platform clients and [`EmoteManager`](super::EmoteManager) can be
+//! tested without talking to the real thing
+//!
+//! every platform lives under its own prefix, laid out the same way as the
+//! real hosts, e.g. `/7tv/api/users/twitch/{id}` is `7tv.io/v3/users/twitch/{id}`
+//! and `/bttv/cdn/emote/{id}/3x` is `cdn.betterttv.net/emote/{id}/3x`
+//!
+//! the websocket stand-ins, 7TV's EventAPI at `/7tv/events` and BTTV's at
+//! `/bttv/socket`, record whatever clients send them and forward anything
+//! passed to [`MockUpstream::seventv_dispatch`] and [`MockUpstream::bttv_send`]
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json,
+};
+use dashmap::DashMap;
+use futures::{SinkExt, StreamExt};
+use http::{header::CONTENT_TYPE, StatusCode, Uri};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use tokio::{sync::broadcast, task::JoinHandle};
+use url::Url;
+
+use super::{BttvUrls, FfzUrls, SevenTvUrls, TwitchUrls, UpstreamUrls};
+
+pub const FIXTURES_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/upstream");
+
+/// extensions tried, in order, for request paths that don't have a file
+/// extension of their own, like `/bttv/cdn/emote/{id}/3x`
+const EXTENSIONS: &[&str] = &["json", "webp", "gif", "png", "avif"];
+
+#[derive(Debug, Clone)]
+struct MockSocket {
+    /// sent as soon as a client connects
+    hello: Option<serde_json::Value>,
+    outgoing: broadcast::Sender<String>,
+    received: Arc<Mutex<Vec<serde_json::Value>>>,
+}
+
+impl MockSocket {
+    fn new(hello: Option<serde_json::Value>) -> Self {
+        Self {
+            hello,
+            outgoing: broadcast::channel(64).0,
+            received: Default::default(),
+        }
+    }
+
+    async fn wait_for(&self, what: &str, pred: impl Fn(&serde_json::Value) -> bool) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !self.received.lock().iter().any(&pred) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("never got {what}"));
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MockState {
+    hits: Arc<DashMap<String, usize>>,
+    seventv_events: MockSocket,
+    bttv_socket: MockSocket,
+}
+
+impl Default for MockState {
+    fn default() -> Self {
+        Self {
+            hits: Default::default(),
+            seventv_events: MockSocket::new(Some(serde_json::json!({
+                "op": 1,
+                "d": { "heartbeat_interval": 25000, "session_id": "mock" },
+            }))),
+            bttv_socket: MockSocket::new(None),
+        }
+    }
+}
+
+pub struct MockUpstream {
+    addr: SocketAddr,
+    state: MockState,
+    server: JoinHandle<()>,
+}
+
+impl MockUpstream {
+    pub async fn start() -> Self {
+        let state = MockState::default();
+
+        let app = axum::Router::new()
+            .route("/twitch/oauth2/token", post(twitch_token))
+            .route("/twitch/helix/users", get(twitch_users))
+            .route("/twitch/helix/chat/emotes", get(twitch_channel_emotes))
+            .route("/7tv/events", get(seventv_events))
+            .route("/bttv/socket", get(bttv_socket))
+            .fallback(get(fixture))
+            .layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                count_hits,
+            ))
+            .with_state(state.clone());
+
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .expect("failed to bind mock upstream");
+        let addr = listener
+            .local_addr()
+            .expect("mock upstream has no local address");
+
+        let server = tokio::spawn(async move {
+            axum::serve(listener, app)
+                .await
+                .expect("mock upstream died");
+        });
+
+        Self {
+            addr,
+            state,
+            server,
+        }
+    }
+
+    pub fn url(&self, path: &str) -> Url {
+        Url::parse(&format!("http://{}{path}", self.addr)).expect("mock url must be valid")
+    }
+
+    pub fn ws_url(&self, path: &str) -> Url {
+        Url::parse(&format!("ws://{}{path}", self.addr)).expect("mock url must be valid")
+    }
+
+    pub fn urls(&self) -> UpstreamUrls {
+        UpstreamUrls {
+            twitch: TwitchUrls {
+                oauth: self.url("/twitch/oauth2/token"),
+                helix: self.url("/twitch/helix"),
+                cdn: self.url("/twitch/cdn"),
+            },
+            seventv: SevenTvUrls {
+                api: self.url("/7tv/api"),
+                cdn: self.url("/7tv/cdn"),
+                events: self.ws_url("/7tv/events"),
+            },
+            bttv: BttvUrls {
+                api: self.url("/bttv/api"),
+                cdn: self.url("/bttv/cdn"),
+                socket: self.ws_url("/bttv/socket"),
+            },
+            ffz: FfzUrls {
+                api: self.url("/ffz/api"),
+                cdn: self.url("/ffz/cdn"),
+            },
+        }
+    }
+
+    /// how many times `path` has been requested so far
+    pub fn hits(&self, path: &str) -> usize {
+        self.state.hits.get(path).map(|h| *h).unwrap_or(0)
+    }
+
+    /// sends a dispatch with `body` as its data to every connected 7TV
+    /// EventAPI client
+    pub fn seventv_dispatch(&self, body: serde_json::Value) {
+        let msg = serde_json::json!({ "op": 0, "d": body });
+        let _ = self.state.seventv_events.outgoing.send(msg.to_string());
+    }
+
+    /// waits until a 7TV EventAPI client subscribes to `set_id`
+    pub async fn wait_for_seventv_subscription(&self, set_id: &str) {
+        self.state
+            .seventv_events
+            .wait_for("a 7TV subscription", |msg| {
+                msg["op"] == 35 && msg["d"]["condition"]["object_id"].as_str() == Some(set_id)
+            })
+            .await
+    }
+
+    /// sends an event to every connected BTTV websocket client
+    pub fn bttv_send(&self, name: &str, data: serde_json::Value) {
+        let msg = serde_json::json!({ "name": name, "data": data });
+        let _ = self.state.bttv_socket.outgoing.send(msg.to_string());
+    }
+
+    /// waits until a BTTV websocket client joins the channel for `twitch_id`
+    pub async fn wait_for_bttv_join(&self, twitch_id: &str) {
+        let channel = format!("twitch:{twitch_id}");
+        self.state
+            .bttv_socket
+            .wait_for("a BTTV channel join", |msg| {
+                msg["name"] == "join_channel" && msg["data"]["name"].as_str() == Some(&channel)
+            })
+            .await
+    }
+}
+
+impl Drop for MockUpstream {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+async fn count_hits(
+    State(state): State<MockState>,
+    req: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    *state.hits.entry(req.uri().path().to_string()).or_default() += 1;
+    next.run(req).await
+}
+
+async fn seventv_events(State(state): State<MockState>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(|socket| socket_session(state.seventv_events, socket))
+}
+
+async fn bttv_socket(State(state): State<MockState>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(|socket| socket_session(state.bttv_socket, socket))
+}
+
+async fn socket_session(mock: MockSocket, socket: WebSocket) {
+    let mut outgoing = mock.outgoing.subscribe();
+    let (mut sink, mut stream) = socket.split();
+
+    if let Some(hello) = &mock.hello {
+        if sink.send(Message::Text(hello.to_string())).await.is_err() {
+            return;
+        }
+    }
+
+    loop {
+        tokio::select! {
+            msg = stream.next() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    if let Ok(msg) = serde_json::from_str(&text) {
+                        mock.received.lock().push(msg);
+                    }
+                }
+                Some(Ok(_)) => (),
+                _ => return,
+            },
+            msg = outgoing.recv() => match msg {
+                Ok(msg) => {
+                    if sink.send(Message::Text(msg)).await.is_err() {
+                        return;
+                    }
+                }
+                Err(_) => return,
+            },
+        }
+    }
+}
+
+async fn twitch_token() -> Response {
+    Json(serde_json::json!({
+        "access_token": "mocktoken",
+        "expires_in": 5_000_000,
+        "token_type": "bearer",
+    }))
+    .into_response()
+}
+
+#[derive(Deserialize)]
+struct UsersQuery {
+    login: String,
+}
+
+async fn twitch_users(Query(query): Query<UsersQuery>) -> Response {
+    match find_fixture(&format!("twitch/helix/users/{}", query.login)) {
+        Some(path) => serve_file(&path).await,
+        None => Json(serde_json::json!({ "data": [] })).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct ChannelEmotesQuery {
+    broadcaster_id: String,
+}
+
+async fn twitch_channel_emotes(Query(query): Query<ChannelEmotesQuery>) -> Response {
+    match find_fixture(&format!(
+        "twitch/helix/chat/emotes/{}",
+        query.broadcaster_id
+    )) {
+        Some(path) => serve_file(&path).await,
+        None => Json(serde_json::json!({ "data": [] })).into_response(),
+    }
+}
+
+async fn fixture(uri: Uri) -> Response {
+    match find_fixture(uri.path()) {
+        Some(path) => serve_file(&path).await,
+        None => (StatusCode::NOT_FOUND, ()).into_response(),
+    }
+}
+
+fn find_fixture(path: &str) -> Option<PathBuf> {
+    let path = path.trim_start_matches('/');
+    if path.split('/').any(|s| s == "..") {
+        return None;
+    }
+
+    let exact = Path::new(FIXTURES_DIR).join(path);
+    if exact.is_file() {
+        return Some(exact);
+    }
+
+    EXTENSIONS
+        .iter()
+        .map(|ext| Path::new(FIXTURES_DIR).join(format!("{path}.{ext}")))
+        .find(|p| p.is_file())
+}
+
+async fn serve_file(path: &Path) -> Response {
+    let content_type = match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => mime::APPLICATION_JSON.as_ref(),
+        Some("webp") => "image/webp",
+        Some("gif") => mime::IMAGE_GIF.as_ref(),
+        Some("png") => mime::IMAGE_PNG.as_ref(),
+        Some("avif") => "image/avif",
+        _ => mime::APPLICATION_OCTET_STREAM.as_ref(),
+    };
+
+    match tokio::fs::read(path).await {
+        Ok(data) => {
+            let mut resp = Response::new(Body::from(data));
+            resp.headers_mut().insert(
+                CONTENT_TYPE,
+                content_type
+                    .try_into()
+                    .expect("mime types are valid headers"),
+            );
+            resp
+        }
+        Err(_) => (StatusCode::NOT_FOUND, ()).into_response(),
+    }
+}
@@ -1,32 +1,70 @@
-use std::{fmt::Display, ops::Deref, sync::Arc, time::Duration};
+use std::{
+    fmt::Display,
+    ops::Deref,
+    str::FromStr,
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
 
 use axum::response::IntoResponse;
 use channel::ChannelEmote;
 use dashmap::DashMap;
-use http::StatusCode;
+use hashbrown::HashMap;
+use http::{HeaderName, HeaderValue, StatusCode};
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use tracing::warn;
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+use url::Url;
 
 use crate::{
-    cache::Cache,
-    emote::{Emote, EmoteError},
+    cache::{Cache, MemoryBudget, MemoryStats},
+    emote::{
+        resolution::Resolution,
+        scheduler::{DecodeScheduler, SchedulerStats},
+        Emote, EmoteError, EmoteOptions,
+    },
+    flight::SingleFlight,
+    store::{EmoteStore, StoreConfig, StoreError},
 };
+use warmup::{WarmupConfig, WarmupStats};
 
 pub mod bttv;
 pub mod channel;
+pub mod fetcher;
 pub mod ffz;
+pub mod globals;
 pub mod seventv;
 pub mod twitch;
+pub mod warmup;
+mod websocket;
+
+#[cfg(test)]
+pub(crate) mod mock;
 
-pub use bttv::BttvClient;
-pub use ffz::FfzClient;
-pub use seventv::SevenTvClient;
-pub use twitch::TwitchClient;
+pub use bttv::{BttvClient, BttvUrls};
+pub use ffz::{FfzClient, FfzUrls};
+pub use seventv::{SevenTvClient, SevenTvUrls};
+pub use twitch::{TwitchClient, TwitchUrls};
 
 pub const EMOTE_CACHE_MAX_AGE: Duration = Duration::from_secs(60 * 60 * 8);
 pub const EMOTE_CACHE_EVICTION_INTERVAL: Duration = Duration::from_secs(60 * 15);
 pub const USER_CACHE_MAX_AGE: Duration = Duration::from_secs(60 * 5);
 pub const USER_CACHE_EVICTION_INTERVAL: Duration = Duration::from_secs(60 * 15);
+pub const GLOBAL_CACHE_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+/// how long global emotes that failed to fetch wait before they're tried
+/// again, doubling every time after that
+pub const GLOBAL_RETRY_BACKOFF: Duration = Duration::from_secs(5);
+pub const CHANNEL_CACHE_MAX_AGE: Duration = Duration::from_secs(60 * 15);
+/// how often emotes get thrown out for taking up too much memory, on top of
+/// every time one gets cached
+pub const MEMORY_BUDGET_TRIM_INTERVAL: Duration = Duration::from_secs(60);
+/// channels missing a platform because it failed get retried this soon
+pub const INCOMPLETE_CHANNEL_CACHE_MAX_AGE: Duration = Duration::from_secs(30);
+pub const PLATFORM_STATUS_HEADER: HeaderName = HeaderName::from_static("x-platform-status");
+/// how long a single platform gets to answer before a channel is served
+/// without it
+pub const CHANNEL_PLATFORM_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub trait EmotePlatform {
     type InternalEmoteType;
@@ -38,19 +76,105 @@ pub trait EmotePlatform {
     where
         for<'a> &'a Self::InternalEmoteType: IntoIterator<Item = ChannelEmote>;
 
-    async fn get_emote_by_id(&self, id: &str) -> Result<Emote, PlatformError>;
+    async fn get_emote_by_id(&self, id: &str) -> Result<Emote, PlatformError> {
+        self.get_emote_by_id_sized(id, Resolution::default()).await
+    }
+
+    /// every resolution gets cached on its own
+    async fn get_emote_by_id_sized(
+        &self,
+        id: &str,
+        resolution: Resolution,
+    ) -> Result<Emote, PlatformError>;
 
     async fn get_global_emotes(&self) -> Result<Arc<DashMap<String, ChannelEmote>>, PlatformError>;
 }
 
+/// appends `segments` to the path of `base`, escaping each one so ids coming
+/// from requests can't mess with the rest of the url
+pub(crate) fn endpoint<'a>(base: &Url, segments: impl IntoIterator<Item = &'a str>) -> Url {
+    let mut url = base.clone();
+    url.path_segments_mut()
+        .expect("upstream urls must be able to be a base")
+        .pop_if_empty()
+        .extend(segments);
+    url
+}
+
+/// anything that isn't a success means something's wrong on their end
+pub(crate) fn check_response(
+    resp: reqwest::Response,
+    platform: Platform,
+) -> Result<reqwest::Response, PlatformError> {
+    match resp.status() {
+        s if s.is_success() => Ok(resp),
+        _ => Err(PlatformError::PlatformError(platform)),
+    }
+}
+
+/// like [`check_response`], but 404s mean the channel just doesn't use
+/// `platform`
+pub(crate) fn check_channel_response(
+    resp: reqwest::Response,
+    platform: Platform,
+) -> Result<reqwest::Response, PlatformError> {
+    match resp.status() {
+        StatusCode::NOT_FOUND => Err(PlatformError::ChannelNotFound),
+        _ => check_response(resp, platform),
+    }
+}
+
+/// where every platform client sends its requests, defaults to the real
+/// upstreams
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamUrls {
+    pub twitch: TwitchUrls,
+    pub seventv: SevenTvUrls,
+    pub bttv: BttvUrls,
+    pub ffz: FfzUrls,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EmoteManagerConfig {
+    pub urls: UpstreamUrls,
+    /// keep 7TV emote sets up to date through the EventAPI
+    pub seventv_events: bool,
+    /// keep BTTV channel emotes up to date through their websocket
+    pub bttv_events: bool,
+    /// who wins when channel emotes from different platforms share a name,
+    /// can still be overridden per request
+    pub priority: PlatformPriority,
+    pub emote: EmoteOptions,
+    /// keep emotes on disk too, so they survive restarts
+    pub store: Option<StoreConfig>,
+    /// most bytes cached emotes can take up across every platform, the least
+    /// recently used ones get thrown out past that
+    pub memory_budget: Option<u64>,
+    /// how long after a channel's emotes expire they're still served while
+    /// they get refreshed in the background, and kept around for platforms
+    /// that fail to refresh
+    pub channel_grace: Duration,
+    /// channels to fetch ahead of time, in the background
+    pub warmup: Option<WarmupConfig>,
+}
+
+/// sent by platform clients with live updates whenever the emotes of a
+/// channel change upstream
+#[derive(Debug, Clone)]
+pub struct ChannelUpdate {
+    pub platform: Platform,
+    pub twitch_id: String,
+    /// every emote the channel now has on `platform`, `None` if that isn't
+    /// known and whatever is cached should just be thrown out
+    pub emotes: Option<Arc<[ChannelEmote]>>,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum PlatformError {
     #[error("the requested channel wasn't found")]
     ChannelNotFound,
     #[error("the requested emote wasn't found")]
     EmoteNotFound,
-    #[error("twitch channel emotes should never really be requested")]
-    TwitchChannelEmotes,
     #[error(transparent)]
     RequestFailure(#[from] reqwest::Error),
     #[error("requesting the emote from {0} returned an error")]
@@ -59,33 +183,52 @@ pub enum PlatformError {
     Unauthorized(Platform),
     #[error(transparent)]
     DecodeError(#[from] EmoteError),
+    #[error(transparent)]
+    StoreError(#[from] StoreError),
+    /// the same error went to everyone who was waiting on the same fetch
+    #[error(transparent)]
+    Shared(Arc<PlatformError>),
 }
 
-impl IntoResponse for PlatformError {
-    fn into_response(self) -> axum::response::Response {
+impl PlatformError {
+    /// back out of a result that might've been handed to other waiters too,
+    /// only stays shared if someone else still has it
+    pub fn unshare(e: Arc<PlatformError>) -> Self {
+        Arc::try_unwrap(e).unwrap_or_else(Self::Shared)
+    }
+
+    fn status(&self) -> StatusCode {
         match self {
-            PlatformError::ChannelNotFound => (StatusCode::NOT_FOUND, ()).into_response(),
-            PlatformError::EmoteNotFound => (StatusCode::NOT_FOUND, ()).into_response(),
-            PlatformError::RequestFailure(_) => {
-                (StatusCode::BAD_GATEWAY, self.to_string()).into_response()
-            }
-            PlatformError::PlatformError(_) => {
-                (StatusCode::BAD_GATEWAY, self.to_string()).into_response()
+            PlatformError::ChannelNotFound | PlatformError::EmoteNotFound => StatusCode::NOT_FOUND,
+            PlatformError::RequestFailure(_) | PlatformError::PlatformError(_) => {
+                StatusCode::BAD_GATEWAY
             }
-            PlatformError::Unauthorized(_) => {
-                (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+            PlatformError::Unauthorized(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            PlatformError::DecodeError(EmoteError::LimitExceeded(_)) => {
+                StatusCode::UNPROCESSABLE_ENTITY
             }
-            PlatformError::DecodeError(_) => {
-                (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
-            }
-            PlatformError::TwitchChannelEmotes => {
-                (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+            PlatformError::DecodeError(_) | PlatformError::StoreError(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
             }
+            PlatformError::Shared(e) => e.status(),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+impl IntoResponse for PlatformError {
+    fn into_response(self) -> axum::response::Response {
+        match self.status() {
+            StatusCode::NOT_FOUND => (StatusCode::NOT_FOUND, ()).into_response(),
+            status => (status, self.to_string()).into_response(),
+        }
+    }
+}
+
+/// fetches that are running right now, so they only happen once however many
+/// ask at the same time
+pub(crate) type Flights<K, V> = Arc<SingleFlight<K, Result<V, Arc<PlatformError>>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum Platform {
     Twitch,
@@ -97,6 +240,33 @@ pub enum Platform {
     FrancerFaceZ,
 }
 
+impl Platform {
+    /// the short name used in urls and query strings
+    pub fn name(self) -> &'static str {
+        match self {
+            Platform::Twitch => "twitch",
+            Platform::SevenTv => "7tv",
+            Platform::BetterTtv => "bttv",
+            Platform::FrancerFaceZ => "ffz",
+        }
+    }
+}
+
+impl FromStr for Platform {
+    type Err = PriorityError;
+
+    /// same names as the serde ones
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "twitch" => Ok(Self::Twitch),
+            "7tv" => Ok(Self::SevenTv),
+            "bttv" => Ok(Self::BetterTtv),
+            "ffz" => Ok(Self::FrancerFaceZ),
+            _ => Err(PriorityError::UnknownPlatform(s.trim().into())),
+        }
+    }
+}
+
 impl Display for Platform {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -112,13 +282,307 @@ impl Display for Platform {
     }
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum PriorityError {
+    #[error("unknown platform \"{0}\"")]
+    UnknownPlatform(String),
+    #[error("{0} is listed more than once")]
+    Duplicate(Platform),
+}
+
+/// order in which platforms win name collisions between channel emotes,
+/// highest priority first
+///
+/// parsed from comma separated platform names like `7tv,twitch`, platforms
+/// that are left out keep their default order after the listed ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PlatformPriority([Platform; 4]);
+
+impl PlatformPriority {
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = Platform> + '_ {
+        self.0.iter().copied()
+    }
+}
+
+impl Default for PlatformPriority {
+    /// same as the chat extensions, nothing can override the streamer's own
+    /// emotes and 7TV beats BTTV beats FFZ
+    fn default() -> Self {
+        Self([
+            Platform::Twitch,
+            Platform::SevenTv,
+            Platform::BetterTtv,
+            Platform::FrancerFaceZ,
+        ])
+    }
+}
+
+impl FromStr for PlatformPriority {
+    type Err = PriorityError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut order = Vec::with_capacity(4);
+        for name in s.split(',').filter(|n| !n.trim().is_empty()) {
+            let platform = name.parse::<Platform>()?;
+            if order.contains(&platform) {
+                return Err(PriorityError::Duplicate(platform));
+            }
+            order.push(platform);
+        }
+        for platform in Self::default().iter() {
+            if !order.contains(&platform) {
+                order.push(platform);
+            }
+        }
+
+        Ok(Self(
+            order
+                .try_into()
+                .expect("every platform is in there exactly once"),
+        ))
+    }
+}
+
+impl TryFrom<String> for PlatformPriority {
+    type Error = PriorityError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for PlatformPriority {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, platform) in self.iter().enumerate() {
+            if i != 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", platform.name())?;
+        }
+        Ok(())
+    }
+}
+
+/// how getting a channel's emotes from a platform went
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlatformStatus {
+    Ok,
+    /// the channel just doesn't have any emotes there
+    NotFound,
+    UpstreamError,
+    TimedOut,
+}
+
+impl PlatformStatus {
+    fn from_error(e: &PlatformError) -> Self {
+        match e {
+            PlatformError::ChannelNotFound => Self::NotFound,
+            PlatformError::RequestFailure(e) if e.is_timeout() => Self::TimedOut,
+            PlatformError::Shared(e) => Self::from_error(e),
+            _ => Self::UpstreamError,
+        }
+    }
+
+    /// whether we know every emote the channel has on the platform
+    pub fn is_complete(self) -> bool {
+        matches!(self, Self::Ok | Self::NotFound)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::NotFound => "not_found",
+            Self::UpstreamError => "upstream_error",
+            Self::TimedOut => "timed_out",
+        }
+    }
+}
+
+/// status of every platform for a channel, displayed like
+/// `twitch=ok, 7tv=ok, bttv=not_found, ffz=timed_out`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelStatus(Vec<(Platform, PlatformStatus)>);
+
+impl ChannelStatus {
+    pub fn get(&self, platform: Platform) -> Option<PlatformStatus> {
+        self.0.iter().find(|(p, _)| *p == platform).map(|(_, s)| *s)
+    }
+
+    /// false if any platform failed, so some emotes might be missing
+    pub fn is_complete(&self) -> bool {
+        self.0.iter().all(|(_, s)| s.is_complete())
+    }
+}
+
+impl Display for ChannelStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (platform, status)) in self.0.iter().enumerate() {
+            if i != 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}={}", platform.name(), status.name())?;
+        }
+        Ok(())
+    }
+}
+
+/// channel emotes along with how getting them from each platform went
+#[derive(Debug, Clone)]
+pub struct ChannelEmotes<T> {
+    pub emotes: T,
+    pub status: ChannelStatus,
+}
+
+/// the body stays just the emotes so existing clients don't break, the
+/// status goes in the `x-platform-status` header
+impl<T: Serialize> IntoResponse for ChannelEmotes<T> {
+    fn into_response(self) -> axum::response::Response {
+        let mut resp = axum::Json(self.emotes).into_response();
+        resp.headers_mut().insert(
+            PLATFORM_STATUS_HEADER,
+            HeaderValue::try_from(self.status.to_string())
+                .expect("platform and status names are valid headers"),
+        );
+        resp
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CachedChannel {
+    twitch_id: String,
+    /// each platform's emotes on their own, so one of them can be swapped out
+    /// without having to request the others again, and so they can be merged
+    /// in whatever order a request asks for
+    sets: Vec<(Platform, Arc<[ChannelEmote]>)>,
+    status: ChannelStatus,
+    priority: PlatformPriority,
+    /// `sets` merged with `priority`
+    emotes: Arc<DashMap<String, ChannelEmote>>,
+    /// since when sets of platforms that failed are ones from before
+    kept_since: Option<Instant>,
+}
+
+impl CachedChannel {
+    fn new(
+        twitch_id: String,
+        sets: Vec<(Platform, Arc<[ChannelEmote]>)>,
+        status: ChannelStatus,
+        priority: PlatformPriority,
+    ) -> Self {
+        let emotes = Self::merge(&sets, &priority);
+        Self {
+            twitch_id,
+            sets,
+            status,
+            priority,
+            emotes,
+            kept_since: None,
+        }
+    }
+
+    /// platforms that failed this time get the emotes they had in
+    /// `previous` back, until they've been failing for longer than `grace`.
+    /// their status stays failed, so they get tried again soon
+    fn keep_failed(&mut self, previous: &CachedChannel, grace: Duration) {
+        let kept_since = previous.kept_since.unwrap_or_else(Instant::now);
+        if kept_since.elapsed() > grace {
+            return;
+        }
+
+        let mut kept = false;
+        for (platform, status) in &self.status.0 {
+            if status.is_complete() {
+                continue;
+            }
+            let old = Self::set(&previous.sets, *platform);
+            if let (Some(old), Some((_, set))) =
+                (old, self.sets.iter_mut().find(|(p, _)| p == platform))
+            {
+                *set = old.clone();
+                kept = true;
+            }
+        }
+        if kept {
+            self.kept_since = Some(kept_since);
+            self.emotes = Self::merge(&self.sets, &self.priority);
+        }
+    }
+
+    fn set(
+        sets: &[(Platform, Arc<[ChannelEmote]>)],
+        platform: Platform,
+    ) -> Option<&Arc<[ChannelEmote]>> {
+        sets.iter().find(|(p, _)| *p == platform).map(|(_, s)| s)
+    }
+
+    fn merge(
+        sets: &[(Platform, Arc<[ChannelEmote]>)],
+        priority: &PlatformPriority,
+    ) -> Arc<DashMap<String, ChannelEmote>> {
+        let mut merged = DashMap::new();
+        // lowest priority goes in first so the higher ones overwrite it
+        for platform in priority.iter().rev() {
+            if let Some(set) = Self::set(sets, platform) {
+                merged.extend(set.iter().map(|e| (e.name.clone(), e.clone())));
+            }
+        }
+        Arc::new(merged)
+    }
+
+    fn emotes(&self, priority: Option<&PlatformPriority>) -> Arc<DashMap<String, ChannelEmote>> {
+        match priority {
+            Some(priority) if *priority != self.priority => Self::merge(&self.sets, priority),
+            _ => self.emotes.clone(),
+        }
+    }
+
+    /// every emote sharing a name, the one that wins comes first
+    fn candidates(
+        &self,
+        priority: Option<&PlatformPriority>,
+    ) -> HashMap<String, Vec<ChannelEmote>> {
+        let priority = priority.unwrap_or(&self.priority);
+        let mut candidates: HashMap<String, Vec<ChannelEmote>> = HashMap::new();
+        for platform in priority.iter() {
+            for emote in Self::set(&self.sets, platform)
+                .into_iter()
+                .flat_map(|s| s.iter())
+            {
+                candidates
+                    .entry_ref(emote.name.as_str())
+                    .or_default()
+                    .push(emote.clone());
+            }
+        }
+        candidates
+    }
+
+    fn replace_set(&mut self, platform: Platform, emotes: Arc<[ChannelEmote]>) {
+        if let Some((_, set)) = self.sets.iter_mut().find(|(p, _)| *p == platform) {
+            *set = emotes;
+            if let Some((_, status)) = self.status.0.iter_mut().find(|(p, _)| *p == platform) {
+                *status = PlatformStatus::Ok;
+            }
+            self.emotes = Self::merge(&self.sets, &self.priority);
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct EmoteManager {
     twitch: TwitchClient,
     seventv: SevenTvClient,
     ffz: FfzClient,
     bttv: BttvClient,
-    channel_emotes: Arc<Cache<String, Arc<DashMap<String, ChannelEmote>>>>,
+    channel_emotes: Arc<Cache<String, CachedChannel>>,
+    channel_flights: Flights<String, CachedChannel>,
+    channel_grace: Duration,
+    priority: PlatformPriority,
+    scheduler: DecodeScheduler,
+    budget: Arc<MemoryBudget>,
+    warmup: Arc<Mutex<WarmupStats>>,
 }
 
 impl EmoteManager {
@@ -126,21 +590,119 @@ impl EmoteManager {
         twitch_client_id: impl Into<Box<str>>,
         twitch_client_secret: impl Into<Box<str>>,
     ) -> Result<Self, PlatformError> {
-        Ok(Self {
-            twitch: TwitchClient::new(twitch_client_id.into(), twitch_client_secret.into()).await?,
-            seventv: Default::default(),
-            ffz: Default::default(),
-            bttv: Default::default(),
-            channel_emotes: Arc::new(Cache::new(Duration::from_secs(60 * 15))),
-        })
+        Self::with_config(
+            twitch_client_id,
+            twitch_client_secret,
+            EmoteManagerConfig::default(),
+        )
+        .await
+    }
+
+    pub async fn with_config(
+        twitch_client_id: impl Into<Box<str>>,
+        twitch_client_secret: impl Into<Box<str>>,
+        config: EmoteManagerConfig,
+    ) -> Result<Self, PlatformError> {
+        let urls = config.urls;
+        let channel_emotes = Arc::new(Cache::with_grace(
+            CHANNEL_CACHE_MAX_AGE,
+            config.channel_grace,
+        ));
+
+        let mut twitch = TwitchClient::with_urls(
+            twitch_client_id.into(),
+            twitch_client_secret.into(),
+            urls.twitch,
+        )
+        .await?;
+        let store = match config.store {
+            Some(store) => EmoteStore::open(store).await?,
+            None => EmoteStore::default(),
+        };
+        let budget = Arc::new(MemoryBudget::new(config.memory_budget));
+        tokio::spawn(cache::budget_trimmer(
+            Arc::downgrade(&budget),
+            MEMORY_BUDGET_TRIM_INTERVAL,
+        ));
+
+        let mut seventv = SevenTvClient::with_urls(urls.seventv);
+        if config.seventv_events {
+            seventv.enable_event_api();
+        }
+        let mut bttv = BttvClient::with_urls(urls.bttv);
+        if config.bttv_events {
+            bttv.enable_socket();
+        }
+
+        for updates in [seventv.updates(), bttv.updates()].into_iter().flatten() {
+            tokio::spawn(channel_update_listener(
+                Arc::downgrade(&channel_emotes),
+                updates,
+            ));
+        }
+
+        let mut ffz = FfzClient::with_urls(urls.ffz);
+        for fetcher in [
+            twitch.fetcher_mut(),
+            seventv.fetcher_mut(),
+            bttv.fetcher_mut(),
+            ffz.fetcher_mut(),
+        ] {
+            fetcher.set_emote_options(config.emote.clone());
+            fetcher.set_store(store.clone());
+            fetcher.set_memory_budget(budget.clone());
+        }
+
+        let manager = Self {
+            twitch,
+            seventv,
+            ffz,
+            bttv,
+            channel_emotes,
+            channel_flights: Arc::default(),
+            channel_grace: config.channel_grace,
+            priority: config.priority,
+            scheduler: config.emote.scheduler,
+            budget,
+            warmup: Arc::default(),
+        };
+        if let Some(warmup) = config.warmup {
+            tokio::spawn(warmup::run(manager.clone(), warmup, manager.warmup.clone()));
+        }
+        Ok(manager)
+    }
+
+    /// how far warming up the configured channels got
+    pub fn warmup_stats(&self) -> WarmupStats {
+        self.warmup.lock().clone()
+    }
+
+    /// how busy decoding is, for monitoring
+    pub fn decode_stats(&self) -> SchedulerStats {
+        self.scheduler.stats()
+    }
+
+    /// how much memory cached emotes are taking up
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.budget.stats()
     }
 
     pub async fn get_emote(&self, platform: Platform, id: &str) -> Result<Emote, PlatformError> {
+        self.get_emote_sized(platform, id, Resolution::default())
+            .await
+    }
+
+    pub async fn get_emote_sized(
+        &self,
+        platform: Platform,
+        id: &str,
+        resolution: Resolution,
+    ) -> Result<Emote, PlatformError> {
         match platform {
-            Platform::Twitch => self.twitch.get_emote_by_id(id).await,
-            Platform::SevenTv => self.seventv.get_emote_by_id(id).await,
-            Platform::BetterTtv => self.bttv.get_emote_by_id(id).await,
-            Platform::FrancerFaceZ => self.ffz.get_emote_by_id(id).await,
+            Platform::Twitch => self.twitch.get_emote_by_id_sized(id, resolution).await,
+            Platform::SevenTv => self.seventv.get_emote_by_id_sized(id, resolution).await,
+            Platform::BetterTtv => self.bttv.get_emote_by_id_sized(id, resolution).await,
+            Platform::FrancerFaceZ => self.ffz.get_emote_by_id_sized(id, resolution).await,
         }
     }
 
@@ -148,37 +710,140 @@ impl EmoteManager {
         &self,
         channel: &str,
     ) -> Result<Arc<DashMap<String, ChannelEmote>>, PlatformError> {
-        match self.channel_emotes.get(channel).map(|i| i.clone()) {
-            Some(emotes) => Ok(emotes),
-            None => {
-                let mut emotes = DashMap::<String, ChannelEmote>::new();
+        Ok(self
+            .get_channel_emotes_with_priority(channel, None)
+            .await?
+            .emotes)
+    }
 
-                let user_id = self.twitch.get_channel_id(channel).await?;
+    /// like [`Self::get_channel_emotes`] but name collisions get resolved
+    /// with `priority` instead of the configured one
+    pub async fn get_channel_emotes_with_priority(
+        &self,
+        channel: &str,
+        priority: Option<&PlatformPriority>,
+    ) -> Result<ChannelEmotes<Arc<DashMap<String, ChannelEmote>>>, PlatformError> {
+        let cached = self.get_cached_channel(channel).await?;
+        Ok(ChannelEmotes {
+            emotes: cached.emotes(priority),
+            status: cached.status,
+        })
+    }
 
-                let (seventv_resp, bttv_resp, ffz_resp) = futures::join!(
-                    self.seventv.get_channel_emotes(&user_id),
-                    self.bttv.get_channel_emotes(&user_id),
-                    self.ffz.get_channel_emotes(&user_id)
-                );
+    /// every channel emote by name, including the ones that lose name
+    /// collisions, ordered by `priority` or the configured one
+    pub async fn get_channel_emote_candidates(
+        &self,
+        channel: &str,
+        priority: Option<&PlatformPriority>,
+    ) -> Result<ChannelEmotes<HashMap<String, Vec<ChannelEmote>>>, PlatformError> {
+        let cached = self.get_cached_channel(channel).await?;
+        Ok(ChannelEmotes {
+            emotes: cached.candidates(priority),
+            status: cached.status,
+        })
+    }
 
-                match seventv_resp {
-                    Ok(resp) => emotes.extend(resp.into_iter().map(|e| (e.name.clone(), e))),
-                    Err(e) => warn!("{e} from 7tv"),
-                }
-                match bttv_resp {
-                    Ok(resp) => emotes.extend(resp.into_iter().map(|e| (e.name.clone(), e))),
-                    Err(e) => warn!("{e} from bttv"),
-                }
-                match ffz_resp {
-                    Ok(resp) => emotes.extend(resp.into_iter().map(|e| (e.name.clone(), e))),
-                    Err(e) => warn!("{e} from ffz"),
-                }
+    async fn get_cached_channel(&self, channel: &str) -> Result<CachedChannel, PlatformError> {
+        let channel = channel.to_lowercase();
+        let cached = self
+            .channel_emotes
+            .get_stale(&channel)
+            .map(|(c, stale)| (c.clone(), stale));
+        let stale = match cached {
+            Some((cached, false)) => return Ok(cached),
+            Some((cached, true)) => cached,
+            None => return self.refresh_channel(channel, None).await,
+        };
 
-                let emotes: Arc<DashMap<String, ChannelEmote>> = emotes.into();
-                self.channel_emotes.insert(channel.into(), emotes.clone());
-                Ok(emotes)
+        // whoever asked doesn't have to wait for the refresh
+        debug!("serving stale emotes for {channel} while they're refreshed");
+        let this = self.clone();
+        let previous = stale.clone();
+        tokio::spawn(async move {
+            if let Err(e) = this.refresh_channel(channel.clone(), Some(previous)).await {
+                warn!("refreshing emotes for {channel} failed, keeping the old ones: {e}");
             }
+        });
+        Ok(stale)
+    }
+
+    /// [`Self::fetch_channel`], once for everyone asking at the same time
+    async fn refresh_channel(
+        &self,
+        channel: String,
+        previous: Option<CachedChannel>,
+    ) -> Result<CachedChannel, PlatformError> {
+        let this = self.clone();
+        self.channel_flights
+            .run(channel.clone(), || async move {
+                this.fetch_channel(channel, previous)
+                    .await
+                    .map_err(Arc::new)
+            })
+            .await
+            .map_err(PlatformError::unshare)
+    }
+
+    /// every platform's emotes for `channel`, merged and cached. platforms
+    /// that fail keep what they had in `previous` for a while
+    async fn fetch_channel(
+        &self,
+        channel: String,
+        previous: Option<CachedChannel>,
+    ) -> Result<CachedChannel, PlatformError> {
+        // whoever fetched it just before might've finished in the meantime
+        if let Some(cached) = self.channel_emotes.get(&channel).map(|c| c.clone()) {
+            return Ok(cached);
         }
+
+        let user_id = self.twitch.get_channel_id(&channel).await?;
+
+        let (twitch, seventv, bttv, ffz) = futures::join!(
+            tokio::time::timeout(
+                CHANNEL_PLATFORM_TIMEOUT,
+                self.twitch.get_channel_emotes(&user_id)
+            ),
+            tokio::time::timeout(
+                CHANNEL_PLATFORM_TIMEOUT,
+                self.seventv.get_channel_emotes(&user_id)
+            ),
+            tokio::time::timeout(
+                CHANNEL_PLATFORM_TIMEOUT,
+                self.bttv.get_channel_emotes(&user_id)
+            ),
+            tokio::time::timeout(
+                CHANNEL_PLATFORM_TIMEOUT,
+                self.ffz.get_channel_emotes(&user_id)
+            )
+        );
+
+        let (sets, status) = [
+            channel_set(Platform::Twitch, twitch),
+            channel_set(Platform::SevenTv, seventv),
+            channel_set(Platform::BetterTtv, bttv),
+            channel_set(Platform::FrancerFaceZ, ffz),
+        ]
+        .into_iter()
+        .map(|(platform, set, status)| ((platform, set), (platform, status)))
+        .unzip();
+
+        let mut cached = CachedChannel::new(user_id, sets, ChannelStatus(status), self.priority);
+        if let Some(previous) = &previous {
+            cached.keep_failed(previous, self.channel_grace);
+        }
+        if cached.status.is_complete() {
+            self.channel_emotes.insert(channel, cached.clone());
+        } else {
+            // try the platforms that failed again soon, the ones that
+            // worked are still in their own caches
+            self.channel_emotes.insert_with_max_age(
+                channel,
+                cached.clone(),
+                INCOMPLETE_CHANNEL_CACHE_MAX_AGE,
+            );
+        }
+        Ok(cached)
     }
 
     pub async fn get_global_emotes(
@@ -189,7 +854,77 @@ impl EmoteManager {
             Platform::SevenTv => self.seventv.get_global_emotes().await,
             Platform::BetterTtv => self.bttv.get_global_emotes().await,
             Platform::FrancerFaceZ => self.ffz.get_global_emotes().await,
-            Platform::Twitch => todo!(),
+            Platform::Twitch => self.twitch.get_global_emotes().await,
+        }
+    }
+}
+
+/// what a platform answered for a channel, failures end up as an empty set
+fn channel_set<T>(
+    platform: Platform,
+    resp: Result<Result<T, PlatformError>, tokio::time::error::Elapsed>,
+) -> (Platform, Arc<[ChannelEmote]>, PlatformStatus)
+where
+    T: Deref,
+    for<'a> &'a T::Target: IntoIterator<Item = ChannelEmote>,
+{
+    match resp {
+        Ok(Ok(emotes)) => (
+            platform,
+            (&*emotes).into_iter().collect(),
+            PlatformStatus::Ok,
+        ),
+        Ok(Err(e)) => {
+            let status = PlatformStatus::from_error(&e);
+            if status == PlatformStatus::NotFound {
+                debug!("no channel emotes on {platform}");
+            } else {
+                warn!("{e} from {platform}");
+            }
+            (platform, Arc::new([]), status)
+        }
+        Err(_) => {
+            warn!("{platform} took too long to answer");
+            (platform, Arc::new([]), PlatformStatus::TimedOut)
+        }
+    }
+}
+
+/// patches merged channel sets as soon as one of their platforms reports a
+/// change, or drops them if the new emotes aren't known so they get merged
+/// again on the next request
+async fn channel_update_listener(
+    channel_emotes: Weak<Cache<String, CachedChannel>>,
+    mut updates: broadcast::Receiver<ChannelUpdate>,
+) {
+    loop {
+        let update = updates.recv().await;
+        let Some(cache) = channel_emotes.upgrade() else {
+            return;
+        };
+
+        match update {
+            Ok(update) => {
+                debug!(
+                    "{} emotes changed for {}",
+                    update.platform, update.twitch_id
+                );
+                cache.retain(|_, c| {
+                    if c.twitch_id != update.twitch_id {
+                        return true;
+                    }
+                    match &update.emotes {
+                        Some(emotes) => {
+                            c.replace_set(update.platform, emotes.clone());
+                            true
+                        }
+                        None => false,
+                    }
+                })
+            }
+            // no clue what we missed, so everything goes
+            Err(broadcast::error::RecvError::Lagged(_)) => cache.retain(|_, _| false),
+            Err(broadcast::error::RecvError::Closed) => return,
         }
     }
 }
@@ -200,7 +935,35 @@ mod cache {
     use futures::FutureExt;
     use tokio::time::MissedTickBehavior;
 
-    use crate::cache::Cache;
+    use crate::cache::{Cache, MemoryBudget};
+
+    pub async fn cache_evictor<K: Hash + Eq, V>(cache: Weak<Cache<K, V>>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            match cache.upgrade() {
+                Some(cache) => cache.evict_stale(),
+                None => return,
+            }
+        }
+    }
+
+    /// emotes grow as they get encoded to more formats, so the budget needs
+    /// checking every once in a while too
+    pub async fn budget_trimmer(budget: Weak<MemoryBudget>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            match budget.upgrade() {
+                Some(budget) => budget.trim(),
+                None => return,
+            }
+        }
+    }
 
     pub async fn platform_cache_evictor<K1: Hash + Eq, V1, K2: Hash + Eq, V2>(
         user_cache: Weak<Cache<K1, V1>>,
@@ -239,15 +1002,47 @@ mod tests {
     //! TuCuando
     #![allow(clippy::unwrap_used, reason = "bruh this is a tests module")]
 
+    use std::time::Duration;
+
     use crate::platforms::{
-        bttv::BttvClient, ffz::FfzClient, seventv::SevenTvClient, EmotePlatform,
+        bttv::{BttvClient, BttvUrls},
+        ffz::FfzClient,
+        mock::MockUpstream,
+        seventv::SevenTvClient,
+        warmup::WarmupConfig,
+        EmoteManager, EmoteManagerConfig, EmotePlatform, Platform, PlatformError, PlatformPriority,
+        PlatformStatus,
     };
 
+    use axum::response::IntoResponse;
+
     use super::TwitchClient;
+    use crate::{
+        emote::{
+            limits::{DecodeLimits, Limit},
+            resolution::Resolution,
+            EmoteError, EmoteOptions,
+        },
+        store::{EmoteStore, StoreConfig},
+    };
 
     // id for PSP1G (he has tons of emotes in all platforms)
     const TWITCH_ID: &str = "104391402";
 
+    /// the defaults, talking to `mock` instead of the real upstreams
+    fn config(mock: &MockUpstream) -> EmoteManagerConfig {
+        EmoteManagerConfig {
+            urls: mock.urls(),
+            ..Default::default()
+        }
+    }
+
+    async fn manager(config: EmoteManagerConfig) -> EmoteManager {
+        EmoteManager::with_config("client_id", "client_secret", config)
+            .await
+            .unwrap()
+    }
+
     #[tokio::test]
     async fn seventv_test() {
         // OMEGALUL
@@ -255,13 +1050,22 @@ mod tests {
         // DIESOFCRINGE
         const ANIMATED_EMOTE_ID: &str = "01FCX95ZG80009QXBMY7YYTVBJ";
 
-        let client = SevenTvClient::new();
+        let mock = MockUpstream::start().await;
+        let client = SevenTvClient::with_urls(mock.urls().seventv);
         client.get_channel_emotes(TWITCH_ID).await.unwrap();
 
-        client.get_emote_by_id(STATIC_EMOTE_ID).await.unwrap();
-        client.get_emote_by_id(ANIMATED_EMOTE_ID).await.unwrap();
+        let emote = client.get_emote_by_id(STATIC_EMOTE_ID).await.unwrap();
+        assert!(emote.atlas.is_none());
+        let emote = client.get_emote_by_id(ANIMATED_EMOTE_ID).await.unwrap();
+        assert_eq!(emote.frames.len(), 6);
 
         client.get_global_emotes().await.unwrap();
+        client.get_global_emotes().await.unwrap();
+        assert_eq!(mock.hits("/7tv/api/emote-sets/global"), 1);
+        // every client has its own
+        let other = SevenTvClient::with_urls(mock.urls().seventv);
+        other.get_global_emotes().await.unwrap();
+        assert_eq!(mock.hits("/7tv/api/emote-sets/global"), 2);
     }
 
     #[tokio::test]
@@ -270,13 +1074,32 @@ mod tests {
         const STATIC_EMOTE_ID: &str = "54fab7d2633595ca4c713abf";
         const ANIMATED_EMOTE_ID: &str = "566ca38765dbbdab32ec0560";
 
-        let client = BttvClient::new();
+        let mock = MockUpstream::start().await;
+        let client = BttvClient::with_urls(mock.urls().bttv);
         client.get_channel_emotes(TWITCH_ID).await.unwrap();
+        assert_eq!(mock.hits("/bttv/api/cached/users/twitch/104391402"), 1);
 
-        client.get_emote_by_id(STATIC_EMOTE_ID).await.unwrap();
-        client.get_emote_by_id(ANIMATED_EMOTE_ID).await.unwrap();
+        let emote = client.get_emote_by_id(STATIC_EMOTE_ID).await.unwrap();
+        assert!(emote.atlas.is_none());
+        let emote = client.get_emote_by_id(ANIMATED_EMOTE_ID).await.unwrap();
+        assert_eq!(emote.frames.len(), 8);
+        assert_eq!(
+            mock.hits(&format!("/bttv/cdn/emote/{ANIMATED_EMOTE_ID}/3x")),
+            1
+        );
 
         client.get_global_emotes().await.unwrap();
+
+        // an error page isn't parsed as emotes
+        let broken = BttvClient::with_urls(BttvUrls {
+            api: mock.url("/nowhere"),
+            ..mock.urls().bttv
+        });
+        let err = broken.get_global_emotes().await.unwrap_err();
+        assert!(matches!(
+            err,
+            PlatformError::Shared(e) if matches!(*e, PlatformError::PlatformError(Platform::BetterTtv))
+        ));
     }
 
     #[tokio::test]
@@ -285,23 +1108,642 @@ mod tests {
         const STATIC_EMOTE_ID: &str = "28136";
         const ANIMATED_EMOTE_ID: &str = "725695";
 
-        let client = FfzClient::new();
+        let mock = MockUpstream::start().await;
+        let client = FfzClient::with_urls(mock.urls().ffz);
         client.get_channel_emotes(TWITCH_ID).await.unwrap();
+        assert_eq!(mock.hits("/ffz/api/room/id/104391402"), 1);
 
-        client.get_emote_by_id(STATIC_EMOTE_ID).await.unwrap();
-        client.get_emote_by_id(ANIMATED_EMOTE_ID).await.unwrap();
+        let emote = client.get_emote_by_id(STATIC_EMOTE_ID).await.unwrap();
+        assert!(emote.atlas.is_none());
+        let emote = client.get_emote_by_id(ANIMATED_EMOTE_ID).await.unwrap();
+        assert_eq!(emote.frames.len(), 4);
+        assert_eq!(
+            mock.hits(&format!("/ffz/cdn/emote/{ANIMATED_EMOTE_ID}/animated/4")),
+            1
+        );
 
         client.get_global_emotes().await.unwrap();
     }
 
     #[tokio::test]
-    #[cfg_attr(on_ci, should_panic)]
     async fn twitch_test() {
-        let client_id = dotenvy::var("TWITCH_CLIENT_ID").unwrap();
-        let client_secret = dotenvy::var("TWITCH_CLIENT_SECRET").unwrap();
-
-        let client = TwitchClient::new(client_id, client_secret).await.unwrap();
+        let mock = MockUpstream::start().await;
+        let client = TwitchClient::with_urls("client_id", "client_secret", mock.urls().twitch)
+            .await
+            .unwrap();
 
         assert_eq!(client.get_channel_id("twitch").await.unwrap(), "12826");
+        assert!(client.get_channel_id("nobody").await.is_err());
+
+        let emotes = client.get_channel_emotes(TWITCH_ID).await.unwrap();
+        let sub = emotes.emotes.iter().find(|e| e.name == "psp1gHi").unwrap();
+        let meta = sub.metadata().unwrap();
+        assert_eq!(meta.emote_type, "subscriptions");
+        assert_eq!(meta.tier.as_deref(), Some("1000"));
+        assert_eq!(meta.emote_set_id, "301590448");
+        let follower = emotes
+            .emotes
+            .iter()
+            .find(|e| e.name == "psp1gDance")
+            .unwrap();
+        assert_eq!(follower.metadata().unwrap().tier, None);
+
+        // Kappa
+        let emote = client.get_emote_by_id("25").await.unwrap();
+        assert!(emote.atlas.is_none());
+    }
+
+    #[tokio::test]
+    async fn manager_test() {
+        let mock = MockUpstream::start().await;
+        let manager = manager(config(&mock)).await;
+
+        let emotes = manager.get_channel_emotes("psp1g").await.unwrap();
+        for name in [
+            "OMEGALUL",
+            "DIESOFCRINGE",
+            "bttvNice",
+            "SourPls",
+            "LilZ",
+            "psp1gHi",
+            "psp1gDance",
+        ] {
+            let info = emotes.get(name).unwrap();
+            let emote = manager.get_emote(info.platform, &info.id).await.unwrap();
+            assert_eq!(emote.atlas.is_some(), info.animated, "{name}");
+        }
+        assert_eq!(emotes.get("psp1gHi").unwrap().platform, Platform::Twitch);
+
+        // second time around everything should come from the caches
+        manager.get_channel_emotes("psp1g").await.unwrap();
+        assert_eq!(mock.hits("/twitch/helix/users"), 1);
+        assert_eq!(mock.hits("/7tv/api/users/twitch/104391402"), 1);
+
+        for platform in [
+            Platform::Twitch,
+            Platform::SevenTv,
+            Platform::BetterTtv,
+            Platform::FrancerFaceZ,
+        ] {
+            assert!(!manager
+                .get_global_emotes(platform)
+                .await
+                .unwrap()
+                .is_empty());
+        }
+        assert_eq!(mock.hits("/twitch/helix/chat/emotes/global"), 1);
+
+        // what /emote/globals/twitch/Kappa goes through
+        let globals = manager.get_global_emotes(Platform::Twitch).await.unwrap();
+        let kappa = globals.get("Kappa").unwrap();
+        assert_eq!(kappa.platform, Platform::Twitch);
+        assert_eq!(kappa.id, "25");
+        let emote = manager.get_emote(kappa.platform, &kappa.id).await.unwrap();
+        assert!(emote.atlas.is_none());
+    }
+
+    #[tokio::test]
+    async fn memory_budget_test() {
+        let mock = MockUpstream::start().await;
+        let unlimited = manager(config(&mock)).await;
+        let ids = [
+            (Platform::SevenTv, "01F00Z3A9G0007E4VV006YKSK9"),
+            (Platform::SevenTv, "01FCX95ZG80009QXBMY7YYTVBJ"),
+            (Platform::BetterTtv, "566ca38765dbbdab32ec0560"),
+        ];
+        for (platform, id) in ids {
+            unlimited.get_emote(platform, id).await.unwrap();
+        }
+        let memory = unlimited.memory_stats();
+        assert_eq!(memory.entries, 3);
+        assert_eq!(memory.caches["7tv"].entries, 2);
+        assert_eq!(memory.evicted, 0);
+
+        // not enough room for all of them, the oldest ones go
+        let max_bytes = memory.used_bytes - 1;
+        let limited = manager(EmoteManagerConfig {
+            memory_budget: Some(max_bytes),
+            ..config(&mock)
+        })
+        .await;
+        for (platform, id) in ids {
+            limited.get_emote(platform, id).await.unwrap();
+        }
+        let memory = limited.memory_stats();
+        assert!(memory.used_bytes <= max_bytes);
+        assert!(memory.evicted > 0);
+        assert!(memory.entries < 3);
+    }
+
+    #[test]
+    fn priority_test() {
+        let priority = " ffz,7tv ".parse::<PlatformPriority>().unwrap();
+        assert_eq!(
+            priority.iter().collect::<Vec<_>>(),
+            [
+                Platform::FrancerFaceZ,
+                Platform::SevenTv,
+                Platform::Twitch,
+                Platform::BetterTtv
+            ]
+        );
+        assert_eq!(priority.to_string(), "ffz,7tv,twitch,bttv");
+        assert_eq!(
+            "".parse::<PlatformPriority>().unwrap(),
+            PlatformPriority::default()
+        );
+        assert!("7tv,kick".parse::<PlatformPriority>().is_err());
+        assert!("bttv,twitch,bttv".parse::<PlatformPriority>().is_err());
+    }
+
+    #[tokio::test]
+    async fn priority_manager_test() {
+        let mock = MockUpstream::start().await;
+        let manager = manager(config(&mock)).await;
+
+        // Joel is in both the 7TV and FFZ sets
+        let emotes = manager.get_channel_emotes("psp1g").await.unwrap();
+        assert_eq!(emotes.get("Joel").unwrap().platform, Platform::SevenTv);
+
+        let ffz_first = "ffz".parse().unwrap();
+        let emotes = manager
+            .get_channel_emotes_with_priority("psp1g", Some(&ffz_first))
+            .await
+            .unwrap()
+            .emotes;
+        assert_eq!(emotes.get("Joel").unwrap().platform, Platform::FrancerFaceZ);
+        assert_eq!(emotes.get("psp1gHi").unwrap().platform, Platform::Twitch);
+
+        let candidates = manager
+            .get_channel_emote_candidates("psp1g", None)
+            .await
+            .unwrap()
+            .emotes;
+        let joels: Vec<_> = candidates["Joel"].iter().map(|e| e.platform).collect();
+        assert_eq!(joels, [Platform::SevenTv, Platform::FrancerFaceZ]);
+        assert_eq!(candidates["LilZ"].len(), 1);
+
+        // none of that should've needed another request
+        assert_eq!(mock.hits("/7tv/api/users/twitch/104391402"), 1);
+        assert_eq!(mock.hits("/ffz/api/room/id/104391402"), 1);
+    }
+
+    #[tokio::test]
+    async fn channel_status_test() {
+        let mock = MockUpstream::start().await;
+        let mut urls = mock.urls();
+        // nothing listens there
+        urls.ffz.api = url::Url::parse("http://127.0.0.1:1/v1").unwrap();
+        let manager = manager(EmoteManagerConfig {
+            urls,
+            ..Default::default()
+        })
+        .await;
+
+        let channel = manager
+            .get_channel_emotes_with_priority("psp1g", None)
+            .await
+            .unwrap();
+        assert_eq!(
+            channel.status.get(Platform::SevenTv),
+            Some(PlatformStatus::Ok)
+        );
+        assert_eq!(
+            channel.status.get(Platform::FrancerFaceZ),
+            Some(PlatformStatus::UpstreamError)
+        );
+        assert!(!channel.status.is_complete());
+        assert!(channel.emotes.contains_key("OMEGALUL"));
+        assert!(!channel.emotes.contains_key("LilZ"));
+
+        // twitch's own channel has no third party emotes at all, which is fine
+        let channel = manager
+            .get_channel_emotes_with_priority("twitch", None)
+            .await
+            .unwrap();
+        assert_eq!(
+            channel.status.get(Platform::BetterTtv),
+            Some(PlatformStatus::NotFound)
+        );
+        assert_eq!(
+            channel.status.to_string(),
+            "twitch=ok, 7tv=not_found, bttv=not_found, ffz=upstream_error"
+        );
+    }
+
+    #[tokio::test]
+    async fn stale_channel_test() {
+        let mock = MockUpstream::start().await;
+        let with_urls = |urls| {
+            manager(EmoteManagerConfig {
+                urls,
+                channel_grace: Duration::from_secs(60),
+                ..Default::default()
+            })
+        };
+        let expire = |manager: &EmoteManager| {
+            let cached = manager.channel_emotes.get("psp1g").unwrap().clone();
+            manager
+                .channel_emotes
+                .insert_with_max_age("psp1g".to_owned(), cached, Duration::ZERO);
+        };
+        let refreshed = |manager: &EmoteManager| manager.channel_emotes.get("psp1g").is_some();
+
+        let healthy = with_urls(mock.urls()).await;
+        healthy.get_channel_emotes("psp1g").await.unwrap();
+        expire(&healthy);
+
+        // the expired one comes back straight away and gets refreshed behind
+        // the scenes
+        let channel = healthy.get_channel_emotes("psp1g").await.unwrap();
+        assert!(channel.contains_key("OMEGALUL"));
+        while !refreshed(&healthy) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // FFZ going down during a refresh doesn't lose its emotes
+        let mut urls = mock.urls();
+        urls.ffz.api = url::Url::parse("http://127.0.0.1:1/v1").unwrap();
+        let degraded = with_urls(urls).await;
+        let last_good = healthy.channel_emotes.get("psp1g").unwrap().clone();
+        degraded
+            .channel_emotes
+            .insert_with_max_age("psp1g".to_owned(), last_good, Duration::ZERO);
+        degraded.get_channel_emotes("psp1g").await.unwrap();
+        while !refreshed(&degraded) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let channel = degraded
+            .get_channel_emotes_with_priority("psp1g", None)
+            .await
+            .unwrap();
+        assert_eq!(
+            channel.status.get(Platform::FrancerFaceZ),
+            Some(PlatformStatus::UpstreamError)
+        );
+        assert!(channel.emotes.contains_key("LilZ"));
+        assert!(channel.emotes.contains_key("OMEGALUL"));
+    }
+
+    #[tokio::test]
+    async fn warmup_test() {
+        let mock = MockUpstream::start().await;
+        let manager = manager(EmoteManagerConfig {
+            warmup: Some(WarmupConfig {
+                channels: vec!["psp1g".to_owned(), "doesnt_exist".to_owned()],
+                every: None,
+                decode: true,
+                delay: Duration::from_millis(1),
+            }),
+            ..config(&mock)
+        })
+        .await;
+
+        while manager.warmup_stats().runs == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let stats = manager.warmup_stats();
+        assert!(!stats.running);
+        assert_eq!(stats.channels, 2);
+        assert_eq!(stats.channels_warmed, 1);
+        assert_eq!(stats.channels_failed, 1);
+        assert!(stats.emotes_warmed > 0);
+
+        // everything's already there by the time someone asks
+        let hits = mock.hits("/7tv/cdn/emote/01F00Z3A9G0007E4VV006YKSK9/4x.webp");
+        assert_eq!(hits, 1);
+        manager.get_channel_emotes("psp1g").await.unwrap();
+        manager
+            .get_emote(Platform::SevenTv, "01F00Z3A9G0007E4VV006YKSK9")
+            .await
+            .unwrap();
+        assert_eq!(
+            mock.hits("/7tv/cdn/emote/01F00Z3A9G0007E4VV006YKSK9/4x.webp"),
+            1
+        );
+        assert_eq!(mock.hits("/7tv/api/users/twitch/104391402"), 1);
+    }
+
+    #[tokio::test]
+    async fn download_limit_test() {
+        let mock = MockUpstream::start().await;
+        let manager = manager(EmoteManagerConfig {
+            emote: EmoteOptions {
+                limits: DecodeLimits {
+                    max_download_bytes: 64,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            ..config(&mock)
+        })
+        .await;
+
+        let err = manager
+            .get_emote(Platform::SevenTv, "01F00Z3A9G0007E4VV006YKSK9")
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            PlatformError::DecodeError(EmoteError::LimitExceeded(Limit::DownloadSize))
+        ));
+        assert_eq!(
+            err.into_response().status(),
+            http::StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+
+    #[tokio::test]
+    async fn resolution_test() {
+        let mock = MockUpstream::start().await;
+        let manager = manager(config(&mock)).await;
+
+        // OMEGALUL, 7TV has every size
+        let small = manager
+            .get_emote_sized(
+                Platform::SevenTv,
+                "01F00Z3A9G0007E4VV006YKSK9",
+                Resolution::Scale(1),
+            )
+            .await
+            .unwrap();
+        assert_eq!((small.width, small.height), (16, 16));
+        let big = manager
+            .get_emote(Platform::SevenTv, "01F00Z3A9G0007E4VV006YKSK9")
+            .await
+            .unwrap();
+        assert_eq!((big.width, big.height), (64, 64));
+        assert_eq!(
+            mock.hits("/7tv/cdn/emote/01F00Z3A9G0007E4VV006YKSK9/1x.webp"),
+            1
+        );
+        assert_eq!(
+            mock.hits("/7tv/cdn/emote/01F00Z3A9G0007E4VV006YKSK9/4x.webp"),
+            1
+        );
+
+        // LilZ, FFZ has no 3x so it's shrunk down from 4x
+        let lilz = manager
+            .get_emote_sized(Platform::FrancerFaceZ, "28136", Resolution::Scale(3))
+            .await
+            .unwrap();
+        assert_eq!((lilz.width, lilz.height), (48, 48));
+        assert_eq!(lilz.resolution, Resolution::Scale(3));
+        assert_eq!(mock.hits("/ffz/cdn/emote/28136/4"), 1);
+
+        // SourPls, BTTV's 4x is just their 3x
+        let sourpls = manager
+            .get_emote_sized(
+                Platform::BetterTtv,
+                "566ca38765dbbdab32ec0560",
+                Resolution::Height(32),
+            )
+            .await
+            .unwrap();
+        assert_eq!(sourpls.height, 32);
+        assert_eq!(sourpls.frames.len(), 8);
+        assert_eq!(sourpls.atlas.unwrap().frame_count, 8);
+        for resolution in [Resolution::Scale(3), Resolution::Scale(4)] {
+            manager
+                .get_emote_sized(Platform::BetterTtv, "566ca38765dbbdab32ec0560", resolution)
+                .await
+                .unwrap();
+        }
+        assert_eq!(mock.hits("/bttv/cdn/emote/566ca38765dbbdab32ec0560/3x"), 2);
+    }
+
+    #[tokio::test]
+    async fn coalescing_test() {
+        // DIESOFCRINGE
+        const EMOTE_ID: &str = "01FCX95ZG80009QXBMY7YYTVBJ";
+
+        let mock = MockUpstream::start().await;
+        let manager = manager(config(&mock)).await;
+
+        // a bunch of overlays opening at once
+        let channels =
+            futures::future::join_all((0..8).map(|_| manager.get_channel_emotes("psp1g"))).await;
+        let emotes = futures::future::join_all(
+            (0..8).map(|_| manager.get_emote(Platform::SevenTv, EMOTE_ID)),
+        )
+        .await;
+        assert!(channels.iter().all(|c| c.is_ok()));
+        assert!(emotes.iter().all(|e| e.is_ok()));
+        assert_eq!(mock.hits("/twitch/helix/users"), 1);
+        assert_eq!(mock.hits("/7tv/api/users/twitch/104391402"), 1);
+        assert_eq!(mock.hits(&format!("/7tv/cdn/emote/{EMOTE_ID}/4x.webp")), 1);
+
+        // errors too, and they still come out as what they were
+        let missing = futures::future::join_all(
+            (0..4).map(|_| manager.get_emote(Platform::FrancerFaceZ, "0")),
+        )
+        .await;
+        assert_eq!(mock.hits("/ffz/api/emote/0"), 1);
+        for err in missing {
+            assert_eq!(
+                err.unwrap_err().into_response().status(),
+                http::StatusCode::NOT_FOUND
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn store_test() {
+        // DIESOFCRINGE
+        const EMOTE_ID: &str = "01FCX95ZG80009QXBMY7YYTVBJ";
+
+        let path = std::env::temp_dir().join(format!("store-test-{}.db", std::process::id()));
+        let store_config = StoreConfig {
+            path: path.clone(),
+            max_bytes: 64 * 1024 * 1024,
+            max_age: Duration::from_secs(60 * 60),
+        };
+        let mock = MockUpstream::start().await;
+        let with_store = |store: StoreConfig| {
+            manager(EmoteManagerConfig {
+                store: Some(store),
+                ..config(&mock)
+            })
+        };
+
+        let first = with_store(store_config.clone()).await;
+        let emote = first.get_emote(Platform::SevenTv, EMOTE_ID).await.unwrap();
+        // it gets written down in the background
+        let store = EmoteStore::open(store_config.clone()).await.unwrap();
+        let options = EmoteOptions::default();
+        while store
+            .load(Platform::SevenTv, EMOTE_ID, Resolution::default(), &options)
+            .await
+            .is_none()
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        drop(first);
+
+        // a restarted server has it without going to the CDN
+        let second = with_store(store_config).await;
+        let stored = second.get_emote(Platform::SevenTv, EMOTE_ID).await.unwrap();
+        assert_eq!(stored.frames.len(), emote.frames.len());
+        assert!(stored.atlas.is_some());
+        assert_eq!(mock.hits(&format!("/7tv/cdn/emote/{EMOTE_ID}/4x.webp")), 1);
+
+        for suffix in ["", "-wal", "-shm"] {
+            let mut path = path.clone().into_os_string();
+            path.push(suffix);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[tokio::test]
+    async fn seventv_events_test() {
+        const SET_ID: &str = "01G6G1G1XG000F7C0QJ3QSJ4FS";
+        const USER_ID: &str = "01G6G1FTT8000F7C0QJ3QS8F2S";
+
+        let mock = MockUpstream::start().await;
+        let manager = manager(EmoteManagerConfig {
+            seventv_events: true,
+            ..config(&mock)
+        })
+        .await;
+
+        let emotes = manager.get_channel_emotes("psp1g").await.unwrap();
+        assert!(emotes.contains_key("OMEGALUL"));
+        mock.wait_for_seventv_subscription(SET_ID).await;
+
+        mock.seventv_dispatch(serde_json::json!({
+            "type": "emote_set.update",
+            "body": {
+                "id": SET_ID,
+                "pulled": [{
+                    "key": "emotes",
+                    "index": 0,
+                    "old_value": { "id": "01F00Z3A9G0007E4VV006YKSK9", "name": "OMEGALUL" },
+                }],
+                "pushed": [{
+                    "key": "emotes",
+                    "index": 3,
+                    "value": {
+                        "id": "01FCX95ZG80009QXBMY7YYTVBJ",
+                        "name": "CRINGE",
+                        "data": { "listed": true, "animated": true },
+                    },
+                }],
+            },
+        }));
+
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            loop {
+                let emotes = manager.get_channel_emotes("psp1g").await.unwrap();
+                if !emotes.contains_key("OMEGALUL") && emotes.contains_key("CRINGE") {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // the cached set got patched instead of being requested again
+        assert_eq!(mock.hits("/7tv/api/users/twitch/104391402"), 1);
+        assert!(manager
+            .get_channel_emotes("psp1g")
+            .await
+            .unwrap()
+            .contains_key("Joel"));
+
+        // switching to another set gets the channel requested again
+        mock.wait_for_seventv_subscription(USER_ID).await;
+        mock.seventv_dispatch(serde_json::json!({
+            "type": "user.update",
+            "body": {
+                "id": USER_ID,
+                "updated": [{
+                    "key": "connections",
+                    "index": 0,
+                    "nested": true,
+                    "value": [{
+                        "key": "emote_set",
+                        "old_value": { "id": SET_ID },
+                        "value": { "id": "01HKQT8EWR000ESSWF3625XCS4" },
+                    }],
+                }],
+            },
+        }));
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while mock.hits("/7tv/api/users/twitch/104391402") < 2 {
+                manager.get_channel_emotes("psp1g").await.unwrap();
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn bttv_events_test() {
+        let mock = MockUpstream::start().await;
+        let manager = manager(EmoteManagerConfig {
+            bttv_events: true,
+            ..config(&mock)
+        })
+        .await;
+
+        let emotes = manager.get_channel_emotes("psp1g").await.unwrap();
+        assert!(emotes.contains_key("SourPls"));
+        mock.wait_for_bttv_join(TWITCH_ID).await;
+
+        let channel = format!("twitch:{TWITCH_ID}");
+        mock.bttv_send(
+            "emote_update",
+            serde_json::json!({
+                "channel": channel,
+                "emote": { "id": "566ca38765dbbdab32ec0560", "code": "SourPlsDance" },
+            }),
+        );
+        mock.bttv_send(
+            "emote_delete",
+            serde_json::json!({ "channel": channel, "emoteId": "54fab7d2633595ca4c713abf" }),
+        );
+        mock.bttv_send(
+            "emote_create",
+            serde_json::json!({
+                "channel": channel,
+                "emote": {
+                    "id": "54fab7d2633595ca4c713abf",
+                    "code": "OMEGALUL",
+                    "imageType": "png",
+                    "animated": false,
+                },
+            }),
+        );
+
+        // 7TV has its own OMEGALUL that wins by default
+        let bttv_first = "bttv".parse().unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            loop {
+                let emotes = manager
+                    .get_channel_emotes_with_priority("psp1g", Some(&bttv_first))
+                    .await
+                    .unwrap()
+                    .emotes;
+                let done = !emotes.contains_key("SourPls")
+                    && emotes.contains_key("SourPlsDance")
+                    && !emotes.contains_key("bttvNice")
+                    && emotes
+                        .get("OMEGALUL")
+                        .is_some_and(|e| e.platform == Platform::BetterTtv);
+                if done {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // patched in place, nothing had to be requested again
+        assert_eq!(mock.hits("/bttv/api/cached/users/twitch/104391402"), 1);
+        assert_eq!(mock.hits("/7tv/api/users/twitch/104391402"), 1);
+        assert_eq!(mock.hits("/ffz/api/room/id/104391402"), 1);
     }
 }
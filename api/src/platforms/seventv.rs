@@ -1,99 +1,151 @@
-use std::{
-    iter::Map,
-    ops::Deref,
-    sync::Arc,
-    time::{Duration, Instant},
-};
+use std::{iter::Map, ops::Deref, sync::Arc, time::Duration};
 
 use dashmap::DashMap;
-use reqwest::header::ACCEPT;
+use events::EventApi;
+use futures::future::ready;
 use serde::Deserialize;
-use tokio::sync::OnceCell;
+use tokio::sync::broadcast;
 use tracing::debug;
+use url::Url;
 
-use crate::{cache::Cache, emote::Emote};
+use crate::{
+    cache::Cache,
+    emote::{resolution::Resolution, Emote},
+};
 
 use super::{
-    cache::platform_cache_evictor, channel::ChannelEmote, EmotePlatform, PlatformError,
-    EMOTE_CACHE_MAX_AGE, USER_CACHE_MAX_AGE,
+    cache::platform_cache_evictor,
+    channel::ChannelEmote,
+    check_channel_response, check_response, endpoint,
+    fetcher::EmoteFetcher,
+    globals::{GlobalEmotes, GlobalSet},
+    ChannelUpdate, EmotePlatform, Platform, PlatformError, GLOBAL_CACHE_MAX_AGE,
+    GLOBAL_RETRY_BACKOFF, USER_CACHE_MAX_AGE,
 };
 
+pub mod events;
+
+/// sizes on the 7TV CDN, `1x.webp` to `4x.webp`
+const EMOTE_SCALES: &[u8] = &[1, 2, 3, 4];
+
+#[derive(Debug, Clone)]
+pub struct SevenTvUrls {
+    /// REST API, `https://7tv.io/v3` upstream
+    pub api: Url,
+    /// image CDN, `https://cdn.7tv.app` upstream
+    pub cdn: Url,
+    /// EventAPI websocket, `wss://events.7tv.io/v3` upstream
+    pub events: Url,
+}
+
+impl Default for SevenTvUrls {
+    fn default() -> Self {
+        Self {
+            api: Url::parse("https://7tv.io/v3").expect("hardcoded url must be valid"),
+            cdn: Url::parse("https://cdn.7tv.app").expect("hardcoded url must be valid"),
+            events: Url::parse("wss://events.7tv.io/v3").expect("hardcoded url must be valid"),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SevenTvClient {
     client: reqwest::Client,
-    emote_cache: Arc<Cache<String, Emote>>,
+    urls: SevenTvUrls,
+    fetcher: EmoteFetcher,
+    globals: GlobalEmotes,
     user_cache: Arc<Cache<String, Arc<UserEmotes>>>,
+    events: Option<Arc<EventApi>>,
 }
 
 impl SevenTvClient {
     pub fn new() -> Self {
-        let emote_cache = Arc::new(Cache::new(EMOTE_CACHE_MAX_AGE));
+        Self::with_urls(SevenTvUrls::default())
+    }
+
+    pub fn with_urls(urls: SevenTvUrls) -> Self {
+        let client = reqwest::Client::new();
+        let fetcher = EmoteFetcher::new(
+            Platform::SevenTv,
+            client.clone(),
+            EMOTE_SCALES,
+            "image/png, image/webp, image/gif",
+        );
         let user_cache = Arc::new(Cache::new(USER_CACHE_MAX_AGE));
 
         tokio::spawn(platform_cache_evictor(
             Arc::downgrade(&user_cache),
             Duration::from_secs(60 * 15),
-            Arc::downgrade(&emote_cache),
+            Arc::downgrade(fetcher.cache()),
             Duration::from_secs(60 * 15),
         ));
 
+        let globals = GlobalEmotes::new(
+            Platform::SevenTv,
+            GLOBAL_CACHE_MAX_AGE,
+            GLOBAL_RETRY_BACKOFF,
+            {
+                let client = client.clone();
+                let url = endpoint(&urls.api, ["emote-sets", "global"]);
+                move || fetch_global_emotes(client.clone(), url.clone())
+            },
+        );
+
         Self {
-            client: reqwest::Client::new(),
-            emote_cache,
+            client,
+            urls,
+            fetcher,
+            globals,
             user_cache,
+            events: None,
         }
     }
+
+    /// connects to the 7TV EventAPI in the background, so cached emote sets
+    /// get patched as soon as they change upstream
+    pub fn enable_event_api(&mut self) {
+        if self.events.is_none() {
+            self.events = Some(Arc::new(EventApi::spawn(
+                self.urls.events.clone(),
+                Arc::downgrade(&self.user_cache),
+            )));
+        }
+    }
+
+    /// notifications for every channel whose emote set changed, `None` if
+    /// the EventAPI isn't enabled
+    pub fn updates(&self) -> Option<broadcast::Receiver<ChannelUpdate>> {
+        self.events.as_ref().map(|e| e.updates())
+    }
+
+    /// where its emotes get decoded, cached and stored
+    pub fn fetcher_mut(&mut self) -> &mut EmoteFetcher {
+        &mut self.fetcher
+    }
 }
 
 impl EmotePlatform for SevenTvClient {
     type InternalEmoteType = UserEmotes;
 
-    async fn get_emote_by_id(&self, id: &str) -> Result<Emote, PlatformError> {
-        if let Some(hit) = self.emote_cache.get(id) {
-            debug!("cache hit for 7TV emote {id}");
-
-            return Ok(hit.clone());
-        }
-
-        debug!("requesting 7TV emote {id}");
-        let resp = self
-            .client
-            .get(format!("https://cdn.7tv.app/emote/{id}/4x.webp"))
-            .header(
-                ACCEPT,
-                "image/png, imErr(PlatformError::ChannelNotFound)age/webp, image/gif",
-            )
-            .send()
+    async fn get_emote_by_id_sized(
+        &self,
+        id: &str,
+        resolution: Resolution,
+    ) -> Result<Emote, PlatformError> {
+        let cdn = self.urls.cdn.clone();
+        let id_owned = id.to_owned();
+        self.fetcher
+            .get(id, resolution, move |scale| {
+                ready(Ok(endpoint(
+                    &cdn,
+                    ["emote", &id_owned, &format!("{scale}x.webp")],
+                )))
+            })
             .await
-            .map_err(|e| e.without_url())?;
-
-        let emote = Emote::try_from_response(resp, id).await?;
-        self.emote_cache.insert(id.into(), emote.clone());
-        Ok(emote)
     }
 
     async fn get_global_emotes(&self) -> Result<Arc<DashMap<String, ChannelEmote>>, PlatformError> {
-        static SEVENTV_GLOBALS: OnceCell<(Arc<DashMap<String, ChannelEmote>>, Instant)> =
-            OnceCell::const_new();
-
-        let gotten = SEVENTV_GLOBALS
-            .get_or_try_init(|| async {
-                let resp = self
-                    .client
-                    .get("https://7tv.io/v3/emote-sets/global")
-                    .send()
-                    .await?
-                    .json::<EmoteSet>()
-                    .await?;
-                let emotes = resp.emotes.into_iter().map(|e| (e.name.clone(), e.into()));
-                Result::<(Arc<DashMap<String, ChannelEmote>>, Instant), PlatformError>::Ok((
-                    Arc::new(emotes.collect()),
-                    Instant::now(),
-                ))
-            })
-            .await?;
-
-        Ok(gotten.0.clone())
+        self.globals.get().await
     }
 
     async fn get_channel_emotes(
@@ -105,21 +157,29 @@ impl EmotePlatform for SevenTvClient {
     {
         if let Some(hit) = self.user_cache.get(twitch_id) {
             debug!("7TV channel emotes cache hit for {twitch_id}");
+            if let Some(events) = &self.events {
+                events.subscribe(twitch_id, &hit);
+            }
             return Ok(hit.clone());
         }
 
         debug!("requesting 7TV channel emotes for {twitch_id}");
 
+        let resp = self
+            .client
+            .get(endpoint(&self.urls.api, ["users", "twitch", twitch_id]))
+            .send()
+            .await?;
         let emotes: Arc<UserEmotes> = Arc::new(
-            self.client
-                .get(format!("https://7tv.io/v3/users/twitch/{twitch_id}"))
-                .send()
-                .await?
+            check_channel_response(resp, Platform::SevenTv)?
                 .json()
                 .await
                 .map_err(|e| e.without_url())?,
         );
 
+        if let Some(events) = &self.events {
+            events.subscribe(twitch_id, &emotes);
+        }
         self.user_cache.insert(twitch_id.into(), emotes.clone());
         Ok(emotes)
     }
@@ -131,9 +191,29 @@ impl Default for SevenTvClient {
     }
 }
 
+async fn fetch_global_emotes(
+    client: reqwest::Client,
+    url: Url,
+) -> Result<GlobalSet, PlatformError> {
+    let resp = client.get(url).send().await?;
+    let resp = check_response(resp, Platform::SevenTv)?
+        .json::<EmoteSet>()
+        .await?;
+    let emotes = resp.emotes.into_iter().map(|e| (e.name.clone(), e.into()));
+    Ok(Arc::new(emotes.collect()))
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct UserEmotes {
     pub emote_set: EmoteSet,
+    /// the 7TV account the channel's linked to
+    #[serde(default)]
+    pub user: Option<SevenTvUser>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SevenTvUser {
+    pub id: String,
 }
 
 impl<'a> IntoIterator for &'a UserEmotes {
@@ -148,6 +228,7 @@ impl<'a> IntoIterator for &'a UserEmotes {
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct EmoteSet {
+    pub id: String,
     pub emotes: Vec<SevenTvEmote>,
 }
 
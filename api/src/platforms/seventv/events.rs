@@ -0,0 +1,603 @@
+//! subscriber for the 7TV EventAPI, keeps the cached emote sets of channels
+//! being served up to date with `emote_set.update` events, and notices
+//! channels switching to another set with `user.update` ones
+//!
+//! <https://github.com/SevenTV/EventAPI>
+
+use std::{
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use futures::{SinkExt, StreamExt};
+use hashbrown::HashSet;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::{broadcast, mpsc};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::{
+    cache::Cache,
+    platforms::{
+        channel::ChannelEmote,
+        websocket::{self, SessionEnd, Socket, Subscriber},
+        ChannelUpdate, Platform,
+    },
+};
+
+use super::{EmoteSet, SevenTvEmote, UserEmotes};
+
+/// sets that haven't been requested in this long get unsubscribed from
+const SUBSCRIPTION_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(60 * 15);
+/// used until the server tells us its own interval in the hello message
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// something that can be subscribed to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Topic {
+    EmoteSet(String),
+    /// by 7TV user id
+    User(String),
+}
+
+impl Topic {
+    fn kind(&self) -> &'static str {
+        match self {
+            Topic::EmoteSet(_) => "emote_set.update",
+            Topic::User(_) => "user.update",
+        }
+    }
+
+    fn id(&self) -> &str {
+        match self {
+            Topic::EmoteSet(id) | Topic::User(id) => id,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Channel {
+    set_id: String,
+    user_id: Option<String>,
+    last_used: Instant,
+}
+
+/// every channel being kept up to date, and who's using what
+#[derive(Debug, Default)]
+struct Subscriptions {
+    /// by twitch id
+    channels: DashMap<String, Channel>,
+    /// twitch ids of every channel using a set, by set id. more than one
+    /// channel can share a set
+    sets: DashMap<String, HashSet<String>>,
+    /// twitch id of every 7TV user, by 7TV user id
+    users: DashMap<String, String>,
+}
+
+impl Subscriptions {
+    /// marks `twitch_id` as using `set_id`, and gives back the topics that
+    /// weren't subscribed to yet
+    fn add(&self, twitch_id: &str, set_id: &str, user_id: Option<&str>) -> Vec<Topic> {
+        let mut new = Vec::new();
+        let previous = self.channels.insert(
+            twitch_id.into(),
+            Channel {
+                set_id: set_id.into(),
+                user_id: user_id.map(Into::into),
+                last_used: Instant::now(),
+            },
+        );
+        match previous {
+            Some(previous) if previous.set_id == set_id => (),
+            previous => {
+                if let Some(previous) = previous {
+                    self.leave_set(&previous.set_id, twitch_id);
+                }
+                let mut users = self.sets.entry(set_id.into()).or_insert_with(|| {
+                    new.push(Topic::EmoteSet(set_id.into()));
+                    HashSet::new()
+                });
+                users.insert(twitch_id.into());
+            }
+        }
+        if let Some(user_id) = user_id {
+            if self
+                .users
+                .insert(user_id.into(), twitch_id.into())
+                .is_none()
+            {
+                new.push(Topic::User(user_id.into()));
+            }
+        }
+        new
+    }
+
+    fn leave_set(&self, set_id: &str, twitch_id: &str) {
+        self.sets.remove_if_mut(set_id, |_, users| {
+            users.remove(twitch_id);
+            users.is_empty()
+        });
+    }
+
+    /// forgets the channel, its topics get unsubscribed from on the next
+    /// reconcile if nobody else needs them
+    fn remove(&self, twitch_id: &str) {
+        if let Some((_, channel)) = self.channels.remove(twitch_id) {
+            self.leave_set(&channel.set_id, twitch_id);
+            if let Some(user_id) = channel.user_id {
+                self.users.remove(&user_id);
+            }
+        }
+    }
+
+    /// forgets every channel that hasn't been requested in a while
+    fn remove_idle(&self) {
+        let now = Instant::now();
+        let idle: Vec<String> = self
+            .channels
+            .iter()
+            .filter(|c| now >= c.last_used + SUBSCRIPTION_IDLE_TIMEOUT)
+            .map(|c| c.key().clone())
+            .collect();
+        for twitch_id in idle {
+            self.remove(&twitch_id);
+        }
+    }
+
+    fn topics(&self) -> HashSet<Topic> {
+        let sets = self.sets.iter().map(|s| Topic::EmoteSet(s.key().clone()));
+        let users = self.users.iter().map(|u| Topic::User(u.key().clone()));
+        sets.chain(users).collect()
+    }
+
+    fn using_set(&self, set_id: &str) -> Vec<String> {
+        self.sets
+            .get(set_id)
+            .map(|users| users.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// handle to the background EventAPI connection, the connection is closed
+/// once every handle is dropped
+#[derive(Debug)]
+pub struct EventApi {
+    subscriptions: Arc<Subscriptions>,
+    commands: mpsc::UnboundedSender<Topic>,
+    updates: broadcast::Sender<ChannelUpdate>,
+}
+
+impl EventApi {
+    pub(super) fn spawn(url: Url, user_cache: Weak<Cache<String, Arc<UserEmotes>>>) -> Self {
+        let subscriptions = Arc::new(Subscriptions::default());
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        let (updates, _) = broadcast::channel(256);
+
+        tokio::spawn(websocket::run(EventApiTask {
+            url,
+            subscriptions: subscriptions.clone(),
+            commands: commands_rx,
+            updates: updates.clone(),
+            user_cache,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+        }));
+
+        Self {
+            subscriptions,
+            commands,
+            updates,
+        }
+    }
+
+    /// subscribes to updates for the channel's set and its 7TV user, or
+    /// just marks them as still in use if they already were subscribed to
+    pub fn subscribe(&self, twitch_id: &str, emotes: &UserEmotes) {
+        let user_id = emotes.user.as_ref().map(|u| u.id.as_str());
+        for topic in self
+            .subscriptions
+            .add(twitch_id, &emotes.emote_set.id, user_id)
+        {
+            let _ = self.commands.send(topic);
+        }
+    }
+
+    pub fn updates(&self) -> broadcast::Receiver<ChannelUpdate> {
+        self.updates.subscribe()
+    }
+}
+
+struct EventApiTask {
+    url: Url,
+    subscriptions: Arc<Subscriptions>,
+    commands: mpsc::UnboundedReceiver<Topic>,
+    updates: broadcast::Sender<ChannelUpdate>,
+    user_cache: Weak<Cache<String, Arc<UserEmotes>>>,
+    heartbeat_interval: Duration,
+}
+
+impl Subscriber for EventApiTask {
+    const NAME: &'static str = "7TV EventAPI";
+
+    fn url(&self) -> &Url {
+        &self.url
+    }
+
+    fn is_closed(&self) -> bool {
+        self.commands.is_closed()
+    }
+
+    async fn session(&mut self, socket: Socket) -> SessionEnd {
+        let (mut sink, mut stream) = socket.split();
+        let mut active = HashSet::new();
+
+        for topic in self.subscriptions.topics() {
+            if sink.send(subscribe_message(35, &topic)).await.is_err() {
+                return SessionEnd::Reconnect;
+            }
+            active.insert(topic);
+        }
+
+        let mut reconcile = tokio::time::interval(RECONCILE_INTERVAL);
+        reconcile.reset();
+        let mut last_message = Instant::now();
+
+        loop {
+            let deadline = last_message + self.heartbeat_interval * 3;
+
+            tokio::select! {
+                msg = stream.next() => {
+                    last_message = Instant::now();
+                    match msg {
+                        Some(Ok(Message::Text(text))) => {
+                            if let Some(end) = self.handle_message(&text) {
+                                return end;
+                            }
+                        }
+                        Some(Ok(Message::Close(_))) | None => return SessionEnd::Reconnect,
+                        Some(Err(e)) => {
+                            warn!("7TV EventAPI connection failed: {e}");
+                            return SessionEnd::Reconnect;
+                        }
+                        Some(Ok(_)) => (),
+                    }
+                }
+                cmd = self.commands.recv() => match cmd {
+                    Some(topic) => {
+                        if !active.contains(&topic) {
+                            debug!("subscribing to 7TV {} {}", topic.kind(), topic.id());
+                            if sink.send(subscribe_message(35, &topic)).await.is_err() {
+                                return SessionEnd::Reconnect;
+                            }
+                            active.insert(topic);
+                        }
+                    }
+                    None => {
+                        let _ = sink.close().await;
+                        return SessionEnd::Shutdown;
+                    }
+                },
+                _ = reconcile.tick() => {
+                    self.subscriptions.remove_idle();
+                    let wanted = self.subscriptions.topics();
+                    let unused: Vec<Topic> = active.difference(&wanted).cloned().collect();
+                    for topic in unused {
+                        debug!("unsubscribing from unused 7TV {} {}", topic.kind(), topic.id());
+                        active.remove(&topic);
+                        if sink.send(subscribe_message(36, &topic)).await.is_err() {
+                            return SessionEnd::Reconnect;
+                        }
+                    }
+                }
+                _ = tokio::time::sleep_until(deadline.into()) => {
+                    warn!("7TV EventAPI stopped sending heartbeats, reconnecting");
+                    return SessionEnd::Reconnect;
+                }
+            }
+        }
+    }
+
+    fn invalidate_all(&self) {
+        let twitch_ids: Vec<String> = self
+            .subscriptions
+            .channels
+            .iter()
+            .map(|c| c.key().clone())
+            .collect();
+        for twitch_id in twitch_ids {
+            self.invalidate(twitch_id);
+        }
+    }
+}
+
+impl EventApiTask {
+    fn handle_message(&mut self, text: &str) -> Option<SessionEnd> {
+        let payload = match serde_json::from_str::<Payload>(text) {
+            Ok(p) => p,
+            Err(e) => {
+                warn!("bad message from 7TV EventAPI: {e}");
+                return None;
+            }
+        };
+
+        match payload.op {
+            // dispatch
+            0 => match serde_json::from_value::<Dispatch>(payload.d) {
+                Ok(dispatch) if dispatch.kind == "emote_set.update" => {
+                    self.apply_emote_set_update(dispatch.body)
+                }
+                Ok(dispatch) if dispatch.kind == "user.update" => {
+                    self.apply_user_update(dispatch.body)
+                }
+                Ok(_) => (),
+                Err(e) => warn!("bad dispatch from 7TV EventAPI: {e}"),
+            },
+            // hello
+            1 => {
+                if let Some(interval) = payload.d.get("heartbeat_interval").and_then(|i| i.as_u64())
+                {
+                    self.heartbeat_interval = Duration::from_millis(interval);
+                }
+            }
+            // reconnect, end of stream
+            4 | 7 => return Some(SessionEnd::Reconnect),
+            // error
+            6 => warn!("7TV EventAPI returned an error: {}", payload.d),
+            // heartbeat, ack
+            _ => (),
+        }
+
+        None
+    }
+
+    fn apply_emote_set_update(&self, changes: ChangeMap) {
+        for twitch_id in self.subscriptions.using_set(&changes.id) {
+            debug!("7TV emote set {} for {twitch_id} was updated", changes.id);
+
+            let mut emotes = None;
+            if let Some(cache) = self.user_cache.upgrade() {
+                let patched = cache.get_mut(&twitch_id).and_then(|mut hit| {
+                    // it's moved on to another set, it'll hear about that one
+                    if hit.emote_set.id != changes.id {
+                        return None;
+                    }
+                    let user = Arc::make_mut(&mut *hit);
+                    Some(
+                        patch_emote_set(&mut user.emote_set, &changes)
+                            .map(|_| user.into_iter().collect::<Arc<[ChannelEmote]>>()),
+                    )
+                });
+                match patched {
+                    Some(Ok(patched)) => emotes = Some(patched),
+                    Some(Err(e)) => {
+                        warn!("couldn't apply 7TV emote set update, dropping cached set: {e}");
+                        cache.remove(&twitch_id);
+                    }
+                    None => (),
+                }
+            }
+
+            let _ = self.updates.send(ChannelUpdate {
+                platform: Platform::SevenTv,
+                twitch_id,
+                emotes,
+            });
+        }
+    }
+
+    /// a channel switching sets just gets its emotes requested again, which
+    /// subscribes to the new set
+    fn apply_user_update(&self, changes: ChangeMap) {
+        if !switches_emote_set(&changes) {
+            return;
+        }
+        let Some(twitch_id) = self.subscriptions.users.get(&changes.id).map(|t| t.clone()) else {
+            return;
+        };
+
+        debug!(
+            "7TV user {} for {twitch_id} switched emote sets",
+            changes.id
+        );
+        self.subscriptions.remove(&twitch_id);
+        self.invalidate(twitch_id);
+    }
+
+    fn invalidate(&self, twitch_id: String) {
+        if let Some(cache) = self.user_cache.upgrade() {
+            cache.remove(&twitch_id);
+        }
+        let _ = self.updates.send(ChannelUpdate {
+            platform: Platform::SevenTv,
+            twitch_id,
+            emotes: None,
+        });
+    }
+}
+
+fn subscribe_message(op: u8, topic: &Topic) -> Message {
+    Message::Text(
+        json!({
+            "op": op,
+            "d": {
+                "type": topic.kind(),
+                "condition": { "object_id": topic.id() },
+            },
+        })
+        .to_string(),
+    )
+}
+
+/// whether a `user.update` changed the emote set of one of the user's
+/// connections
+fn switches_emote_set(changes: &ChangeMap) -> bool {
+    changes
+        .updated
+        .iter()
+        .filter(|c| c.key == "connections")
+        .any(|c| {
+            Vec::<ChangeField>::deserialize(&c.value).is_ok_and(|nested| {
+                nested
+                    .iter()
+                    .any(|n| n.key == "emote_set" || n.key == "emote_set_id")
+            })
+        })
+}
+
+fn patch_emote_set(set: &mut EmoteSet, changes: &ChangeMap) -> Result<(), serde_json::Error> {
+    fn emote_changes(changes: &[ChangeField]) -> impl Iterator<Item = &ChangeField> {
+        changes.iter().filter(|c| c.key == "emotes")
+    }
+
+    for change in emote_changes(&changes.pulled) {
+        let old = EmoteRef::deserialize(&change.old_value)?;
+        set.emotes
+            .retain(|e| !(e.id == old.id && e.name == old.name));
+    }
+
+    for change in emote_changes(&changes.updated) {
+        let old = EmoteRef::deserialize(&change.old_value)?;
+        let new = SevenTvEmote::deserialize(&change.value)?;
+        match set
+            .emotes
+            .iter_mut()
+            .find(|e| e.id == old.id && e.name == old.name)
+        {
+            Some(emote) => *emote = new,
+            None => set.emotes.push(new),
+        }
+    }
+
+    for change in emote_changes(&changes.pushed) {
+        let new = SevenTvEmote::deserialize(&change.value)?;
+        set.emotes.push(new);
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct Payload {
+    op: u8,
+    #[serde(default)]
+    d: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct Dispatch {
+    #[serde(rename = "type")]
+    kind: String,
+    body: ChangeMap,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChangeMap {
+    id: String,
+    #[serde(default)]
+    pushed: Vec<ChangeField>,
+    #[serde(default)]
+    pulled: Vec<ChangeField>,
+    #[serde(default)]
+    updated: Vec<ChangeField>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChangeField {
+    key: String,
+    #[serde(default)]
+    value: serde_json::Value,
+    #[serde(default)]
+    old_value: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmoteRef {
+    id: String,
+    name: String,
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used, reason = "bruh this is a tests module")]
+
+    use std::{sync::Arc, time::Duration};
+
+    use serde_json::json;
+    use tokio::sync::{broadcast, mpsc};
+
+    use super::{ChangeMap, EventApiTask, Topic, DEFAULT_HEARTBEAT_INTERVAL};
+    use crate::{cache::Cache, platforms::seventv::UserEmotes};
+
+    fn user(set_id: &str, user_id: &str) -> Arc<UserEmotes> {
+        Arc::new(
+            serde_json::from_value(json!({
+                "emote_set": { "id": set_id, "emotes": [] },
+                "user": { "id": user_id },
+            }))
+            .unwrap(),
+        )
+    }
+
+    fn pushed(set_id: &str, name: &str) -> ChangeMap {
+        serde_json::from_value(json!({
+            "id": set_id,
+            "pushed": [{
+                "key": "emotes",
+                "value": {
+                    "id": "01F00Z3A9G0007E4VV006YKSK9",
+                    "name": name,
+                    "data": { "listed": true, "animated": false },
+                },
+            }],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn shared_set() {
+        let cache = Arc::new(Cache::new(Duration::from_secs(60)));
+        let (updates, mut received) = broadcast::channel(16);
+        let task = EventApiTask {
+            url: "wss://events.invalid".parse().unwrap(),
+            subscriptions: Arc::default(),
+            commands: mpsc::unbounded_channel().1,
+            updates,
+            user_cache: Arc::downgrade(&cache),
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+        };
+        let subscribe = |twitch_id: &str, set_id: &str, user_id: &str| {
+            cache.insert(twitch_id.into(), user(set_id, user_id));
+            task.subscriptions.add(twitch_id, set_id, Some(user_id))
+        };
+
+        assert_eq!(
+            subscribe("1", "set", "a"),
+            [Topic::EmoteSet("set".into()), Topic::User("a".into())]
+        );
+        // the set's subscribed to already
+        assert_eq!(subscribe("2", "set", "b"), [Topic::User("b".into())]);
+
+        // both channels get it
+        task.apply_emote_set_update(pushed("set", "OMEGALUL"));
+        let mut updated = Vec::new();
+        while let Ok(update) = received.try_recv() {
+            assert_eq!(update.emotes.unwrap().len(), 1);
+            updated.push(update.twitch_id);
+        }
+        updated.sort();
+        assert_eq!(updated, ["1", "2"]);
+
+        // and once one's moved on to another set, only the other one does
+        assert_eq!(
+            subscribe("2", "other", "b"),
+            [Topic::EmoteSet("other".into())]
+        );
+        task.apply_emote_set_update(pushed("set", "Joel"));
+        assert_eq!(received.try_recv().unwrap().twitch_id, "1");
+        assert!(received.try_recv().is_err());
+        assert_eq!(cache.get("2").unwrap().emote_set.emotes.len(), 0);
+    }
+}
@@ -1,32 +1,67 @@
 use std::{
-    sync::{Arc, LazyLock},
+    iter::Map,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
 use dashmap::DashMap;
-use http::{header::ACCEPT, HeaderName, HeaderValue};
+use futures::future::ready;
+use http::{HeaderName, HeaderValue};
 use parking_lot::RwLock;
 use reqwest::StatusCode;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tinyvec::TinyVec;
 use tracing::debug;
+use url::Url;
 
 use crate::{
     cache::Cache,
-    emote::Emote,
-    platforms::{cache::platform_cache_evictor, Platform, EMOTE_CACHE_MAX_AGE},
+    emote::resolution::Resolution,
+    platforms::{
+        cache::{cache_evictor, platform_cache_evictor},
+        Platform, GLOBAL_CACHE_MAX_AGE, GLOBAL_RETRY_BACKOFF, USER_CACHE_EVICTION_INTERVAL,
+        USER_CACHE_MAX_AGE,
+    },
 };
 
-use super::{channel::ChannelEmote, EmotePlatform, PlatformError};
+use super::{
+    channel::ChannelEmote,
+    endpoint,
+    fetcher::EmoteFetcher,
+    globals::{GlobalEmotes, GlobalSet},
+    EmotePlatform, PlatformError,
+};
 
 const ID_CACHE_MAX_AGE: Duration = Duration::from_secs(60 * 60 * 8);
 
-#[allow(clippy::unwrap_used, reason = "if this breaks i'll kms")]
-static OAUTH_URL: LazyLock<url::Url> =
-    LazyLock::new(|| url::Url::parse("https://id.twitch.tv/oauth2/token").unwrap());
+/// sizes on the Twitch CDN, `1.0` to `3.0`
+const EMOTE_SCALES: &[u8] = &[1, 2, 3];
+
+#[derive(Debug, Clone)]
+pub struct TwitchUrls {
+    /// full url of the app access token endpoint,
+    /// `https://id.twitch.tv/oauth2/token` upstream
+    pub oauth: Url,
+    /// Helix API, `https://api.twitch.tv/helix` upstream
+    pub helix: Url,
+    /// image CDN, `https://static-cdn.jtvnw.net` upstream
+    pub cdn: Url,
+}
+
+impl Default for TwitchUrls {
+    fn default() -> Self {
+        Self {
+            oauth: Url::parse("https://id.twitch.tv/oauth2/token")
+                .expect("hardcoded url must be valid"),
+            helix: Url::parse("https://api.twitch.tv/helix").expect("hardcoded url must be valid"),
+            cdn: Url::parse("https://static-cdn.jtvnw.net").expect("hardcoded url must be valid"),
+        }
+    }
+}
 
 struct TwitchRefreshingToken {
     http_client: reqwest::Client,
+    oauth_url: Url,
     client_id: Box<str>,
     client_secret: Box<str>,
     token: RwLock<String>,
@@ -37,6 +72,7 @@ impl Clone for TwitchRefreshingToken {
     fn clone(&self) -> Self {
         Self {
             http_client: self.http_client.clone(),
+            oauth_url: self.oauth_url.clone(),
             client_id: self.client_id.clone(),
             client_secret: self.client_secret.clone(),
             token: RwLock::new(self.token.read().clone()),
@@ -49,6 +85,7 @@ impl std::fmt::Debug for TwitchRefreshingToken {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("TwitchRefreshingToken")
             .field("http_client", &self.http_client)
+            .field("oauth_url", &self.oauth_url)
             .field("client_id", &self.client_id)
             .field("client_secret", &"[no snooping]")
             .field("token", &"[no snooping]")
@@ -60,6 +97,7 @@ impl std::fmt::Debug for TwitchRefreshingToken {
 impl TwitchRefreshingToken {
     pub async fn new(
         http_client: reqwest::Client,
+        oauth_url: Url,
         client_id: impl Into<Box<str>>,
         client_secret: impl Into<Box<str>>,
     ) -> Result<Self, PlatformError> {
@@ -73,7 +111,7 @@ impl TwitchRefreshingToken {
         ];
 
         let resp = http_client
-            .post(OAUTH_URL.clone())
+            .post(oauth_url.clone())
             .form(&params)
             .send()
             .await?;
@@ -92,6 +130,7 @@ impl TwitchRefreshingToken {
 
         Ok(Self {
             http_client,
+            oauth_url,
             client_id,
             client_secret,
             token: RwLock::new(token.access_token),
@@ -108,7 +147,7 @@ impl TwitchRefreshingToken {
 
         let resp = self
             .http_client
-            .post(OAUTH_URL.clone())
+            .post(self.oauth_url.clone())
             .form(&params)
             .send()
             .await?;
@@ -143,15 +182,26 @@ impl TwitchRefreshingToken {
 #[derive(Debug, Clone)]
 pub struct TwitchClient {
     client: reqwest::Client,
+    urls: TwitchUrls,
     token: TwitchRefreshingToken,
     user_id_cache: Arc<Cache<String, String>>,
-    emote_cache: Arc<Cache<String, Emote>>,
+    user_cache: Arc<Cache<String, Arc<UserEmotes>>>,
+    fetcher: EmoteFetcher,
+    globals: GlobalEmotes,
 }
 
 impl TwitchClient {
     pub async fn new(
         client_id: impl Into<Box<str>>,
         client_secret: impl Into<Box<str>>,
+    ) -> Result<Self, PlatformError> {
+        Self::with_urls(client_id, client_secret, TwitchUrls::default()).await
+    }
+
+    pub async fn with_urls(
+        client_id: impl Into<Box<str>>,
+        client_secret: impl Into<Box<str>>,
+        urls: TwitchUrls,
     ) -> Result<Self, PlatformError> {
         let client_id = client_id.into();
         let client_secret = client_secret.into();
@@ -165,39 +215,67 @@ impl TwitchClient {
             )]))
             .build()?;
 
-        let token = TwitchRefreshingToken::new(client.clone(), client_id, client_secret).await?;
-
-        let user_cache = Arc::new(Cache::new(ID_CACHE_MAX_AGE));
-        let emote_cache = Arc::new(Cache::new(EMOTE_CACHE_MAX_AGE));
+        let token = TwitchRefreshingToken::new(
+            client.clone(),
+            urls.oauth.clone(),
+            client_id,
+            client_secret,
+        )
+        .await?;
+
+        let user_id_cache = Arc::new(Cache::new(ID_CACHE_MAX_AGE));
+        let user_cache = Arc::new(Cache::new(USER_CACHE_MAX_AGE));
+        let fetcher = EmoteFetcher::new(
+            Platform::Twitch,
+            client.clone(),
+            EMOTE_SCALES,
+            "image/png, image/webp, image/gif",
+        );
 
         // task that clears out the cache every once in a while
         tokio::spawn(platform_cache_evictor(
-            Arc::downgrade(&user_cache),
+            Arc::downgrade(&user_id_cache),
             Duration::from_secs(60 * 15),
-            Arc::downgrade(&emote_cache),
+            Arc::downgrade(fetcher.cache()),
             Duration::from_secs(60 * 15),
         ));
+        tokio::spawn(cache_evictor(
+            Arc::downgrade(&user_cache),
+            USER_CACHE_EVICTION_INTERVAL,
+        ));
+
+        let globals = GlobalEmotes::new(
+            Platform::Twitch,
+            GLOBAL_CACHE_MAX_AGE,
+            GLOBAL_RETRY_BACKOFF,
+            {
+                let client = client.clone();
+                let url = endpoint(&urls.helix, ["chat", "emotes", "global"]);
+                // its own copy, so it doesn't keep the client alive
+                let token = Arc::new(token.clone());
+                move || fetch_global_emotes(client.clone(), url.clone(), token.clone())
+            },
+        );
 
         Ok(Self {
             client,
+            urls,
             token,
-            user_id_cache: user_cache,
-            emote_cache,
+            user_id_cache,
+            user_cache,
+            fetcher,
+            globals,
         })
     }
 
-    pub async fn get_channel_id<'a>(&'a self, channel: &str) -> Result<String, PlatformError> {
-        #[allow(clippy::unwrap_used, reason = "if this breaks i'll kms")]
-        static USERS_ENDPOINT: LazyLock<url::Url> =
-            LazyLock::new(|| url::Url::parse("https://api.twitch.tv/helix/users").unwrap());
-
+    pub async fn get_channel_id(&self, channel: &str) -> Result<String, PlatformError> {
         if let Some(hit) = self.user_id_cache.get(channel) {
             debug!("twitch id cache hit for {channel}");
             return Ok(hit.clone());
         }
 
         debug!("requesting user id for {channel}");
-        let mut url = USERS_ENDPOINT.clone();
+        let mut url = endpoint(&self.urls.helix, ["users"]);
         url.query_pairs_mut().append_pair("login", channel).finish();
 
         let resp = self
@@ -225,77 +303,166 @@ impl TwitchClient {
             _ => Err(PlatformError::PlatformError(Platform::Twitch)),
         }
     }
+
+    /// where its emotes get decoded, cached and stored
+    pub fn fetcher_mut(&mut self) -> &mut EmoteFetcher {
+        &mut self.fetcher
+    }
 }
 
 impl EmotePlatform for TwitchClient {
-    type InternalEmoteType = Vec<TwitchEmote>;
+    type InternalEmoteType = UserEmotes;
 
-    /// this is useless p much, emotes will only ever be requested by id
+    /// subscriber, follower and bits emotes of the channel
     async fn get_channel_emotes(
         &self,
-        _twitch_id: &str,
+        twitch_id: &str,
     ) -> Result<impl std::ops::Deref<Target = Self::InternalEmoteType>, PlatformError>
     where
         for<'a> &'a Self::InternalEmoteType: IntoIterator<Item = super::channel::ChannelEmote>,
     {
-        Err::<Arc<Self::InternalEmoteType>, PlatformError>(PlatformError::TwitchChannelEmotes)
-    }
-
-    async fn get_emote_by_id(&self, id: &str) -> Result<crate::emote::Emote, PlatformError> {
-        if let Some(hit) = self.emote_cache.get(id) {
+        if let Some(hit) = self.user_cache.get(twitch_id) {
+            debug!("twitch channel emotes cache hit for {twitch_id}");
             return Ok(hit.clone());
         }
 
-        let url = format!(
-            "https://static-cdn.jtvnw.net/emoticons/v2/{}/default/dark/3.0",
-            id
-        );
+        debug!("requesting twitch channel emotes for {twitch_id}");
+        let mut url = endpoint(&self.urls.helix, ["chat", "emotes"]);
+        url.query_pairs_mut()
+            .append_pair("broadcaster_id", twitch_id)
+            .finish();
 
         let resp = self
             .client
-            .get(&url)
-            .header(ACCEPT, "image/png, image/webp, image/gif")
+            .get(url)
+            .bearer_auth(self.token.get_token().await?)
             .send()
-            .await
-            .map_err(|e| e.without_url())?;
-
-        let emote = Emote::try_from_response(resp, id).await?;
-
-        self.emote_cache.insert(id.into(), emote.clone());
+            .await?;
 
-        Ok(emote)
+        match resp.status() {
+            StatusCode::OK => {
+                let emotes = Arc::new(UserEmotes {
+                    emotes: resp
+                        .json::<HelixResponse<Vec<TwitchEmote>>>()
+                        .await
+                        .map_err(|e| e.without_url())?
+                        .data,
+                });
+                self.user_cache.insert(twitch_id.into(), emotes.clone());
+                Ok(emotes)
+            }
+            StatusCode::UNAUTHORIZED => Err(PlatformError::Unauthorized(Platform::Twitch)),
+            StatusCode::BAD_REQUEST | StatusCode::NOT_FOUND => Err(PlatformError::ChannelNotFound),
+            _ => Err(PlatformError::PlatformError(Platform::Twitch)),
+        }
     }
 
-    async fn get_global_emotes(
+    async fn get_emote_by_id_sized(
         &self,
-    ) -> Result<Arc<dashmap::DashMap<String, ChannelEmote>>, PlatformError> {
-        let globals = self
-            .client
-            .get("https://api.twitch.tv/helix/chat/emotes/global")
-            .bearer_auth(&self.token.get_token().await?)
-            .send()
-            .await?
-            .json::<HelixResponse<Vec<TwitchEmote>>>()
-            .await?
-            .data;
-
-        let mut map = DashMap::new();
-        map.extend(globals.into_iter().map(|e| (e.name.clone(), e.into())));
+        id: &str,
+        resolution: Resolution,
+    ) -> Result<crate::emote::Emote, PlatformError> {
+        let cdn = self.urls.cdn.clone();
+        let id_owned = id.to_owned();
+        self.fetcher
+            .get(id, resolution, move |scale| {
+                ready(Ok(endpoint(
+                    &cdn,
+                    [
+                        "emoticons",
+                        "v2",
+                        &id_owned,
+                        "default",
+                        "dark",
+                        &format!("{scale}.0"),
+                    ],
+                )))
+            })
+            .await
+    }
 
-        Ok(Arc::new(map))
+    async fn get_global_emotes(&self) -> Result<Arc<DashMap<String, ChannelEmote>>, PlatformError> {
+        self.globals.get().await
     }
 }
 
+async fn fetch_global_emotes(
+    client: reqwest::Client,
+    url: Url,
+    token: Arc<TwitchRefreshingToken>,
+) -> Result<GlobalSet, PlatformError> {
+    let resp = client
+        .get(url)
+        .bearer_auth(&token.get_token().await?)
+        .send()
+        .await?;
+
+    let globals = match resp.status() {
+        StatusCode::OK => {
+            resp.json::<HelixResponse<Vec<TwitchEmote>>>()
+                .await
+                .map_err(|e| e.without_url())?
+                .data
+        }
+        StatusCode::UNAUTHORIZED => return Err(PlatformError::Unauthorized(Platform::Twitch)),
+        _ => return Err(PlatformError::PlatformError(Platform::Twitch)),
+    };
+
+    let mut map = DashMap::new();
+    map.extend(globals.into_iter().map(|e| (e.name.clone(), e.into())));
+    Ok(Arc::new(map))
+}
+
 #[derive(Debug, Deserialize)]
 pub struct HelixResponse<T> {
     data: T,
 }
 
+#[derive(Debug, Clone)]
+pub struct UserEmotes {
+    pub emotes: Vec<TwitchEmote>,
+}
+
+impl<'a> IntoIterator for &'a UserEmotes {
+    type Item = ChannelEmote;
+
+    type IntoIter = Map<std::slice::Iter<'a, TwitchEmote>, fn(&TwitchEmote) -> ChannelEmote>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.emotes.iter().map(|e| e.into())
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct TwitchEmote {
     pub id: String,
     pub name: String,
     pub format: TinyVec<[TwitchEmoteFormat; 2]>,
+    /// only channel emotes have these
+    pub emote_type: Option<String>,
+    pub tier: Option<String>,
+    pub emote_set_id: Option<String>,
+}
+
+impl TwitchEmote {
+    pub fn metadata(&self) -> Option<TwitchEmoteMetadata> {
+        Some(TwitchEmoteMetadata {
+            emote_type: self.emote_type.clone()?,
+            tier: self.tier.clone().filter(|t| !t.is_empty()),
+            emote_set_id: self.emote_set_id.clone()?,
+        })
+    }
+}
+
+/// what kind of channel emote a twitch emote is and what unlocks it
+#[derive(Debug, Clone, Serialize)]
+pub struct TwitchEmoteMetadata {
+    /// `subscriptions`, `follower` or `bitstier`
+    pub emote_type: String,
+    /// `1000`, `2000` or `3000` for subscriber emotes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tier: Option<String>,
+    pub emote_set_id: String,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
@@ -0,0 +1,139 @@
+//! fetches the emotes of channels we know will be asked for before anyone
+//! asks, so the first viewers after a restart don't have to wait on them
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, info, warn};
+
+use super::EmoteManager;
+use crate::emote::scheduler::Priority;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WarmupConfig {
+    /// twitch usernames
+    pub channels: Vec<String>,
+    /// warm everything up again this often, only once at startup if it's
+    /// missing
+    pub every: Option<Duration>,
+    /// decode every emote the channels have too, not just their lists
+    pub decode: bool,
+    /// at least this long between every channel or emote that's fetched
+    pub delay: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct WarmupStats {
+    /// finished since startup
+    pub runs: u64,
+    pub running: bool,
+    /// all of these are for the run that's going on, or the last one if
+    /// there isn't one
+    pub channels: usize,
+    pub channels_warmed: usize,
+    pub channels_failed: usize,
+    pub emotes_warmed: usize,
+    pub emotes_failed: usize,
+    pub last_run_secs: Option<f64>,
+}
+
+/// runs forever if there's an interval, at background priority so anyone
+/// actually waiting on a decode goes first
+pub(super) async fn run(
+    manager: EmoteManager,
+    config: WarmupConfig,
+    stats: Arc<Mutex<WarmupStats>>,
+) {
+    let mut every = config.every.map(|every| {
+        let mut interval = tokio::time::interval(every);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        interval
+    });
+
+    loop {
+        if let Some(every) = &mut every {
+            every.tick().await;
+        }
+        Priority::Background
+            .scope(warm_up(&manager, &config, &stats))
+            .await;
+        if every.is_none() {
+            return;
+        }
+    }
+}
+
+async fn warm_up(manager: &EmoteManager, config: &WarmupConfig, stats: &Mutex<WarmupStats>) {
+    let started = Instant::now();
+    {
+        let mut stats = stats.lock();
+        *stats = WarmupStats {
+            running: true,
+            channels: config.channels.len(),
+            runs: stats.runs,
+            ..Default::default()
+        };
+    }
+    info!("warming up {} channels", config.channels.len());
+
+    let mut limit = tokio::time::interval(config.delay.max(Duration::from_millis(1)));
+    limit.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    for (i, channel) in config.channels.iter().enumerate() {
+        limit.tick().await;
+        let emotes = match manager.get_channel_emotes(channel).await {
+            Ok(emotes) => emotes,
+            Err(e) => {
+                warn!("couldn't warm up {channel}: {e}");
+                stats.lock().channels_failed += 1;
+                continue;
+            }
+        };
+
+        let (mut warmed, mut failed) = (0, 0);
+        if config.decode {
+            // collected first so the map isn't held onto across awaits
+            let emotes: Vec<_> = emotes
+                .iter()
+                .map(|e| (e.platform, e.id.clone(), e.name.clone()))
+                .collect();
+            for (platform, id, name) in emotes {
+                limit.tick().await;
+                match manager.get_emote(platform, &id).await {
+                    Ok(_) => {
+                        warmed += 1;
+                        stats.lock().emotes_warmed += 1;
+                    }
+                    Err(e) => {
+                        debug!("couldn't warm up {name} from {platform} in {channel}: {e}");
+                        failed += 1;
+                        stats.lock().emotes_failed += 1;
+                    }
+                }
+            }
+        }
+
+        stats.lock().channels_warmed += 1;
+        info!(
+            "warmed up {channel} ({}/{}), {} emotes, {warmed} decoded, {failed} failed",
+            i + 1,
+            config.channels.len(),
+            emotes.len()
+        );
+    }
+
+    let elapsed = started.elapsed().as_secs_f64();
+    let mut stats = stats.lock();
+    stats.running = false;
+    stats.runs += 1;
+    stats.last_run_secs = Some(elapsed);
+    info!(
+        "warm up done in {elapsed:.1}s, {} channels failed, {} emotes failed",
+        stats.channels_failed, stats.emotes_failed
+    );
+}
@@ -0,0 +1,68 @@
+//! the reconnect loop shared by the websockets that keep cached channel
+//! emotes up to date, the BTTV one and 7TV's EventAPI
+
+use std::time::Duration;
+
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tracing::{info, warn};
+use url::Url;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+pub(super) type Socket = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+pub(super) enum SessionEnd {
+    Reconnect,
+    Shutdown,
+}
+
+/// one end of a websocket that gets reconnected to whenever it drops
+pub(super) trait Subscriber {
+    /// what it's connected to, for logs
+    const NAME: &'static str;
+
+    fn url(&self) -> &Url;
+
+    /// whether everyone who could still ask for anything is gone
+    fn is_closed(&self) -> bool;
+
+    /// runs until the connection drops or has to be dropped
+    async fn session(&mut self, socket: Socket) -> SessionEnd;
+
+    /// drops whatever it's been keeping up to date
+    fn invalidate_all(&self);
+}
+
+/// connects `subscriber` and reconnects it every time its session ends,
+/// backing off while connecting fails, until it shuts down
+pub(super) async fn run<S: Subscriber>(mut subscriber: S) {
+    let mut backoff = MIN_BACKOFF;
+    let mut first_connection = true;
+
+    while !subscriber.is_closed() {
+        match tokio_tungstenite::connect_async(subscriber.url().as_str()).await {
+            Ok((socket, _)) => {
+                info!("connected to {}", S::NAME);
+                // whatever happened while we were gone is lost, so
+                // everything we know might be outdated
+                if !first_connection {
+                    subscriber.invalidate_all();
+                }
+                first_connection = false;
+                backoff = MIN_BACKOFF;
+
+                match subscriber.session(socket).await {
+                    SessionEnd::Reconnect => (),
+                    SessionEnd::Shutdown => return,
+                }
+            }
+            Err(e) => {
+                warn!("failed to connect to {}: {e}", S::NAME);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+
+        tokio::time::sleep(backoff).await;
+    }
+}
@@ -0,0 +1,444 @@
+//! the HTTP API, everything served by [`router`] needs an
+//! [`EmoteManager`] extension layered on top
+
+use std::sync::LazyLock;
+
+use axum::{
+    body::Body,
+    extract::{Extension, Path, Query},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json,
+};
+use http::{header::CACHE_CONTROL, HeaderValue, StatusCode};
+use serde::Deserialize;
+
+use crate::{
+    cache::MemoryStats,
+    emote::{
+        animated::AnimatedFormat,
+        format::{AcceptFormat, EncodedImage, EncodedResponse, OutputFormat},
+        resolution::Resolution,
+        scheduler::SchedulerStats,
+        Emote, EmoteInfo,
+    },
+    platforms::{warmup::WarmupStats, EmoteManager, Platform, PlatformError, PlatformPriority},
+};
+
+pub fn router() -> axum::Router {
+    axum::Router::new()
+        .route("/user/:username", get(emotes_by_username))
+        .route("/emote/:channel/:name/:frame", get(channel_emote_frame))
+        .route("/emote/:channel/:name", get(channel_emote_info))
+        .route(
+            "/emote/:channel/:name/atlas/:page",
+            get(channel_emote_atlas_page),
+        )
+        .route("/emote/twitch/:id", get(twitch_emote_info))
+        .route(
+            "/emote/twitch/:id/atlas/:page",
+            get(twitch_emote_atlas_page),
+        )
+        .route("/emote/twitch/:id/:frame", get(twitch_emote_frame))
+        .route("/emote/id/:platform/:id", get(id_emote_info))
+        .route(
+            "/emote/id/:platform/:id/atlas/:page",
+            get(id_emote_atlas_page),
+        )
+        .route("/emote/id/:platform/:id/:frame", get(id_emote_frame))
+        .route("/emote/globals/:platform", get(platform_global_emotes))
+        .route(
+            "/emote/globals/:platform/:name",
+            get(platform_global_emote_info),
+        )
+        .route(
+            "/emote/globals/:platform/:name/:frame",
+            get(platform_global_emote_frame),
+        )
+        .route(
+            "/emote/globals/:platform/:name/atlas/:page",
+            get(platform_global_emote_atlas_page),
+        )
+        .route("/stats/decode", get(decode_stats))
+        .route("/stats/memory", get(memory_stats))
+        .route("/stats/warmup", get(warmup_stats))
+}
+
+#[derive(Deserialize)]
+struct ChannelQuery {
+    /// overrides which platform wins name collisions, e.g. `?priority=7tv,twitch`
+    priority: Option<PlatformPriority>,
+    /// return every emote sharing a name instead of just the winner
+    #[serde(default)]
+    all: bool,
+    #[serde(default)]
+    size: Resolution,
+}
+
+#[derive(Deserialize)]
+struct EmoteQuery {
+    /// `1x` to `4x` or a height like `48px`
+    #[serde(default)]
+    size: Resolution,
+}
+
+async fn emotes_by_username(
+    Path(username): Path<String>,
+    Query(query): Query<ChannelQuery>,
+    Extension(manager): Extension<EmoteManager>,
+) -> Response {
+    if query.all {
+        manager
+            .get_channel_emote_candidates(&username, query.priority.as_ref())
+            .await
+            .into_response()
+    } else {
+        manager
+            .get_channel_emotes_with_priority(&username, query.priority.as_ref())
+            .await
+            .into_response()
+    }
+}
+
+async fn channel_emote_frame(
+    Path((channel, name, file)): Path<(String, String, String)>,
+    Query(query): Query<ChannelQuery>,
+    accept: AcceptFormat,
+    Extension(manager): Extension<EmoteManager>,
+) -> Result<Response<Body>, PlatformError> {
+    let emotes = manager
+        .get_channel_emotes_with_priority(&channel, query.priority.as_ref())
+        .await?
+        .emotes;
+    let info = emotes.get(&name).ok_or(PlatformError::EmoteNotFound)?;
+    let emote = manager
+        .get_emote_sized(info.platform, &info.id, query.size)
+        .await?;
+
+    emote_file(&emote, &file, accept).await
+}
+
+async fn channel_emote_info(
+    Path((channel, name)): Path<(String, String)>,
+    Query(query): Query<ChannelQuery>,
+    Extension(manager): Extension<EmoteManager>,
+) -> Result<Response<Body>, PlatformError> {
+    static CACHE_HEADER: LazyLock<HeaderValue> = LazyLock::new(|| {
+        format!("max-age={}, public", { 60 * 60 * 15 })
+            .try_into()
+            .expect("oh no")
+    });
+
+    let emotes = manager
+        .get_channel_emotes_with_priority(&channel, query.priority.as_ref())
+        .await?
+        .emotes;
+    let info = emotes.get(&name).ok_or(PlatformError::EmoteNotFound)?;
+    let emote = manager
+        .get_emote_sized(info.platform, &info.id, query.size)
+        .await?;
+
+    let mut resp = Json::from(EmoteInfo::new(info.value(), &emote)).into_response();
+
+    resp.headers_mut()
+        .insert(CACHE_CONTROL, CACHE_HEADER.clone());
+    Ok(resp)
+}
+
+/// splits `name.ext` into the name and the format the extension asks for,
+/// no extension means whatever `Accept` picked
+fn split_format(file: &str, accept: AcceptFormat) -> Option<(&str, OutputFormat, bool)> {
+    match file.rsplit_once('.') {
+        Some((name, ext)) => Some((name, OutputFormat::from_extension(ext)?, false)),
+        None => Some((file, accept.0, true)),
+    }
+}
+
+async fn send_image(
+    emote: &Emote,
+    image: &EncodedImage,
+    format: OutputFormat,
+    negotiated: bool,
+) -> Result<Response<Body>, PlatformError> {
+    Ok(EncodedResponse {
+        data: emote.encode(image, format).await?,
+        format,
+        negotiated,
+    }
+    .into_response())
+}
+
+/// `N.webp` is frame N and `atlas.webp` the atlas, or `.png`, `.avif` and
+/// `.gif`, or no extension at all to go by `Accept`. `animated.webp`,
+/// `.gif` and `.png` are every frame rebuilt into one animated file
+async fn emote_file(
+    emote: &Emote,
+    file: &str,
+    accept: AcceptFormat,
+) -> Result<Response<Body>, PlatformError> {
+    let (name, format, negotiated) =
+        split_format(file, accept).ok_or(PlatformError::EmoteNotFound)?;
+
+    if name.eq_ignore_ascii_case("animated") {
+        let format = match (AnimatedFormat::from_output(format), negotiated) {
+            (Some(format), _) => format,
+            // nothing makes animated AVIFs, WebP is the next best thing
+            (None, true) => AnimatedFormat::WebP,
+            (None, false) => return Err(PlatformError::EmoteNotFound),
+        };
+        return Ok(EncodedResponse {
+            data: emote.animated(format).await?,
+            format: format.output(),
+            negotiated,
+        }
+        .into_response());
+    }
+
+    let image = if name.eq_ignore_ascii_case("atlas") {
+        emote.atlas.as_ref().and_then(|a| a.page(0)).map(|p| p.0)
+    } else {
+        let frame = name
+            .parse::<usize>()
+            .map_err(|_| PlatformError::EmoteNotFound)?;
+        emote.frames.get(frame).map(|f| f.image().clone())
+    };
+
+    match image {
+        Some(image) => send_image(emote, &image, format, negotiated).await,
+        None => Ok((StatusCode::NOT_FOUND, ()).into_response()),
+    }
+}
+
+/// atlases too big for a single texture get split into pages, `N.webp` is
+/// page N, other extensions work like they do for frames
+async fn atlas_page(
+    emote: &Emote,
+    page: &str,
+    accept: AcceptFormat,
+) -> Result<Response<Body>, PlatformError> {
+    let (page, format, negotiated) =
+        split_format(page, accept).ok_or(PlatformError::EmoteNotFound)?;
+    let page = page
+        .parse::<usize>()
+        .map_err(|_| PlatformError::EmoteNotFound)?;
+
+    match emote.atlas.as_ref().and_then(|a| a.page(page)) {
+        Some(page) => send_image(emote, &page.0, format, negotiated).await,
+        None => Ok((StatusCode::NOT_FOUND, ()).into_response()),
+    }
+}
+
+async fn channel_emote_atlas_page(
+    Path((channel, name, page)): Path<(String, String, String)>,
+    Query(query): Query<ChannelQuery>,
+    accept: AcceptFormat,
+    Extension(manager): Extension<EmoteManager>,
+) -> Result<Response<Body>, PlatformError> {
+    let emotes = manager
+        .get_channel_emotes_with_priority(&channel, query.priority.as_ref())
+        .await?
+        .emotes;
+    let info = emotes.get(&name).ok_or(PlatformError::EmoteNotFound)?;
+    let emote = manager
+        .get_emote_sized(info.platform, &info.id, query.size)
+        .await?;
+
+    atlas_page(&emote, &page, accept).await
+}
+
+async fn twitch_emote_info(
+    Path(id): Path<String>,
+    query: Query<EmoteQuery>,
+    manager: Extension<EmoteManager>,
+) -> Result<Response<Body>, PlatformError> {
+    id_emote_info(Path((Platform::Twitch, id)), query, manager).await
+}
+
+async fn twitch_emote_frame(
+    Path((id, frame)): Path<(String, String)>,
+    query: Query<EmoteQuery>,
+    accept: AcceptFormat,
+    manager: Extension<EmoteManager>,
+) -> Result<Response<Body>, PlatformError> {
+    id_emote_frame(Path((Platform::Twitch, id, frame)), query, accept, manager).await
+}
+
+async fn twitch_emote_atlas_page(
+    Path((id, page)): Path<(String, String)>,
+    query: Query<EmoteQuery>,
+    accept: AcceptFormat,
+    manager: Extension<EmoteManager>,
+) -> Result<Response<Body>, PlatformError> {
+    id_emote_atlas_page(Path((Platform::Twitch, id, page)), query, accept, manager).await
+}
+
+async fn id_emote_info(
+    Path((platform, id)): Path<(Platform, String)>,
+    Query(query): Query<EmoteQuery>,
+    Extension(manager): Extension<EmoteManager>,
+) -> Result<Response<Body>, PlatformError> {
+    static CACHE_HEADER: LazyLock<HeaderValue> = LazyLock::new(|| {
+        format!("max-age={}, public", { 60 * 60 * 15 })
+            .try_into()
+            .expect("oh no")
+    });
+
+    let emote = manager.get_emote_sized(platform, &id, query.size).await?;
+
+    let mut resp = Json::from(EmoteInfo::new_by_id(platform, &emote)).into_response();
+
+    resp.headers_mut()
+        .insert(CACHE_CONTROL, CACHE_HEADER.clone());
+    Ok(resp)
+}
+
+async fn id_emote_frame(
+    Path((platform, id, file)): Path<(Platform, String, String)>,
+    Query(query): Query<EmoteQuery>,
+    accept: AcceptFormat,
+    Extension(manager): Extension<EmoteManager>,
+) -> Result<Response<Body>, PlatformError> {
+    let emote = manager.get_emote_sized(platform, &id, query.size).await?;
+
+    emote_file(&emote, &file, accept).await
+}
+
+async fn id_emote_atlas_page(
+    Path((platform, id, page)): Path<(Platform, String, String)>,
+    Query(query): Query<EmoteQuery>,
+    accept: AcceptFormat,
+    Extension(manager): Extension<EmoteManager>,
+) -> Result<Response<Body>, PlatformError> {
+    let emote = manager.get_emote_sized(platform, &id, query.size).await?;
+
+    atlas_page(&emote, &page, accept).await
+}
+
+async fn platform_global_emotes(
+    Path(platform): Path<Platform>,
+    Extension(manager): Extension<EmoteManager>,
+) -> Result<Response<Body>, PlatformError> {
+    Ok(Json::from(manager.get_global_emotes(platform).await?).into_response())
+}
+
+async fn platform_global_emote_info(
+    Path((platform, emote)): Path<(Platform, String)>,
+    Query(query): Query<EmoteQuery>,
+    Extension(manager): Extension<EmoteManager>,
+) -> Result<Response<Body>, PlatformError> {
+    static CACHE_HEADER: LazyLock<HeaderValue> = LazyLock::new(|| {
+        format!("max-age={}, public", { 60 * 60 * 24 })
+            .try_into()
+            .expect("oh no")
+    });
+
+    let emotes = manager.get_global_emotes(platform).await?;
+    let info = emotes.get(&emote).ok_or(PlatformError::EmoteNotFound)?;
+    let emote = manager
+        .get_emote_sized(info.platform, &info.id, query.size)
+        .await?;
+
+    let mut resp = Json::from(EmoteInfo::new(info.value(), &emote)).into_response();
+
+    resp.headers_mut()
+        .insert(CACHE_CONTROL, CACHE_HEADER.clone());
+    Ok(resp)
+}
+
+async fn platform_global_emote_frame(
+    Path((platform, emote, file)): Path<(Platform, String, String)>,
+    Query(query): Query<EmoteQuery>,
+    accept: AcceptFormat,
+    Extension(manager): Extension<EmoteManager>,
+) -> Result<Response<Body>, PlatformError> {
+    let emotes = manager.get_global_emotes(platform).await?;
+    let info = emotes.get(&emote).ok_or(PlatformError::EmoteNotFound)?;
+    let emote = manager
+        .get_emote_sized(info.platform, &info.id, query.size)
+        .await?;
+
+    emote_file(&emote, &file, accept).await
+}
+
+async fn platform_global_emote_atlas_page(
+    Path((platform, emote, page)): Path<(Platform, String, String)>,
+    Query(query): Query<EmoteQuery>,
+    accept: AcceptFormat,
+    Extension(manager): Extension<EmoteManager>,
+) -> Result<Response<Body>, PlatformError> {
+    let emotes = manager.get_global_emotes(platform).await?;
+    let info = emotes.get(&emote).ok_or(PlatformError::EmoteNotFound)?;
+    let emote = manager
+        .get_emote_sized(info.platform, &info.id, query.size)
+        .await?;
+
+    atlas_page(&emote, &page, accept).await
+}
+
+/// queue depth and wait times of the decode scheduler
+async fn decode_stats(Extension(manager): Extension<EmoteManager>) -> Json<SchedulerStats> {
+    Json(manager.decode_stats())
+}
+
+/// bytes taken up by cached emotes, against the budget
+async fn memory_stats(Extension(manager): Extension<EmoteManager>) -> Json<MemoryStats> {
+    Json(manager.memory_stats())
+}
+
+/// how far warming up the configured channels got
+async fn warmup_stats(Extension(manager): Extension<EmoteManager>) -> Json<WarmupStats> {
+    Json(manager.warmup_stats())
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used, reason = "bruh this is a tests module")]
+
+    use axum::{body::to_bytes, Extension};
+    use http::{Request, StatusCode};
+    use tower::ServiceExt;
+
+    use crate::platforms::{mock::MockUpstream, EmoteManager, EmoteManagerConfig};
+
+    // OMEGALUL on 7TV
+    const EMOTE_ID: &str = "01F00Z3A9G0007E4VV006YKSK9";
+
+    async fn get(app: &axum::Router, uri: &str) -> (StatusCode, serde_json::Value) {
+        let resp = app
+            .clone()
+            .oneshot(Request::get(uri).body(axum::body::Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = resp.status();
+        let body = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn emote_routes() {
+        let mock = MockUpstream::start().await;
+        let manager = EmoteManager::with_config(
+            "client_id",
+            "client_secret",
+            EmoteManagerConfig {
+                urls: mock.urls(),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let app = super::router().layer(Extension(manager));
+
+        let (status, info) = get(&app, &format!("/emote/id/7tv/{EMOTE_ID}")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(info["id"], EMOTE_ID);
+        assert_eq!(info["platform"], "7tv");
+
+        let (status, info) = get(&app, "/emote/psp1g/OMEGALUL").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(info["name"], "OMEGALUL");
+
+        let (status, _) = get(&app, &format!("/emote/id/7tv/{EMOTE_ID}/0.webp")).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
@@ -0,0 +1,571 @@
+//! emotes kept on disk under the in memory caches, so a restarted server
+//! doesn't have to download and decode everything all over again
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc, Weak,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool},
+    Row,
+};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, warn};
+
+use crate::{
+    emote::{
+        atlas::AtlasLayout, format::OutputFormat, frame::RawFrames, resolution::Resolution,
+        scheduler::Priority, Decoded, Emote, EmoteError, EmoteOptions,
+    },
+    platforms::Platform,
+};
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS emotes (
+    platform TEXT NOT NULL,
+    id TEXT NOT NULL,
+    resolution TEXT NOT NULL,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    -- json, seconds for every frame
+    delays TEXT NOT NULL,
+    -- json, null for stills which don't have an atlas
+    atlas_layout TEXT,
+    -- bytes of every image together
+    size INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    accessed_at INTEGER NOT NULL,
+    PRIMARY KEY (platform, id, resolution)
+);
+
+-- every frame as lossless WebP, plus whichever atlas pages had been encoded
+CREATE TABLE IF NOT EXISTS images (
+    platform TEXT NOT NULL,
+    id TEXT NOT NULL,
+    resolution TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    idx INTEGER NOT NULL,
+    data BLOB NOT NULL,
+    PRIMARY KEY (platform, id, resolution, kind, idx),
+    FOREIGN KEY (platform, id, resolution) REFERENCES emotes ON DELETE CASCADE
+);
+"#;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StoreConfig {
+    pub path: PathBuf,
+    /// every stored image together, the least recently used emotes get
+    /// thrown out first when it's over
+    pub max_bytes: u64,
+    /// since an emote got stored, it's downloaded again after that
+    pub max_age: Duration,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error(transparent)]
+    Sql(#[from] sqlx::Error),
+    #[error(transparent)]
+    Emote(#[from] EmoteError),
+    #[error("stored emote doesn't make sense: {0}")]
+    Corrupt(String),
+}
+
+/// how often expired emotes get thrown out, the size limit's checked on
+/// every save
+const TRIM_INTERVAL: Duration = Duration::from_secs(60 * 15);
+
+#[derive(Debug)]
+struct Inner {
+    pool: SqlitePool,
+    config: StoreConfig,
+    /// bytes of every stored image, counted up on every save and recounted
+    /// whenever it's trimmed
+    size: AtomicI64,
+}
+
+/// an SQLite database of encoded emotes, keyed by platform, id and
+/// resolution. the default one is disabled and never has anything
+#[derive(Debug, Clone, Default)]
+pub struct EmoteStore(Option<Arc<Inner>>);
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+impl EmoteStore {
+    /// creates the database if it isn't there yet, and throws out whatever
+    /// expired while the server was down
+    pub async fn open(config: StoreConfig) -> Result<Self, StoreError> {
+        let options = SqliteConnectOptions::new()
+            .filename(&config.path)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .foreign_keys(true);
+        let pool = SqlitePool::connect_with(options).await?;
+        sqlx::raw_sql(SCHEMA).execute(&pool).await?;
+
+        let inner = Arc::new(Inner {
+            pool,
+            config,
+            size: AtomicI64::new(0),
+        });
+        inner.trim().await?;
+        tokio::spawn(trimmer(Arc::downgrade(&inner)));
+        Ok(Self(Some(inner)))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// the emote, if it's stored and hasn't expired. anything going wrong just
+    /// counts as it not being there
+    pub async fn load(
+        &self,
+        platform: Platform,
+        id: &str,
+        resolution: Resolution,
+        options: &EmoteOptions,
+    ) -> Option<Emote> {
+        let inner = self.0.as_ref()?;
+        match inner.load(platform, id, resolution, options).await {
+            Ok(emote) => emote,
+            Err(e) => {
+                warn!("failed to load stored {platform} emote {id}: {e}");
+                None
+            }
+        }
+    }
+
+    /// [`Self::save`] in the background, at background priority
+    pub fn spawn_save(&self, platform: Platform, emote: &Emote) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let store = self.clone();
+        let emote = emote.clone();
+        tokio::spawn(Priority::Background.scope(async move {
+            if let Err(e) = store.save(platform, &emote).await {
+                warn!("failed to store {platform} emote {}: {e}", emote.id);
+            }
+        }));
+    }
+
+    /// writes down every frame and whichever atlas pages have been encoded
+    /// so far, replacing whatever was stored for the emote before. frames
+    /// that haven't been encoded yet get encoded at background priority,
+    /// raw pixels would take up way too much room
+    pub async fn save(&self, platform: Platform, emote: &Emote) -> Result<(), StoreError> {
+        let Some(inner) = &self.0 else {
+            return Ok(());
+        };
+
+        // WebP's lossless, so frames come back exactly the same
+        let mut images = Vec::new();
+        for (i, frame) in emote.frames.iter().enumerate() {
+            let webp = Priority::Background
+                .scope(emote.encode(frame.image(), OutputFormat::WebP))
+                .await?;
+            images.push(("frame", i, webp));
+        }
+        // the rest get drawn from the frames again when they're needed
+        for (i, page) in emote.atlas.iter().flat_map(|a| a.pages.iter()).enumerate() {
+            if let Some(webp) = page.get(OutputFormat::WebP) {
+                images.push(("atlas", i, webp));
+            }
+        }
+
+        let delays: Vec<f64> = emote.frames.iter().map(|f| f.delay).collect();
+        let layout = emote
+            .atlas
+            .as_ref()
+            .map(|a| serde_json::to_string(&a.layout).expect("layouts always serialize"));
+        let size: usize = images.iter().map(|(.., data)| data.len()).sum();
+        let resolution = emote.resolution.to_string();
+        let now = unix_now();
+
+        let mut tx = inner.pool.begin().await?;
+        let replaced: Option<i64> = sqlx::query_scalar(
+            "DELETE FROM emotes WHERE platform = ? AND id = ? AND resolution = ? RETURNING size",
+        )
+        .bind(platform.name())
+        .bind(&*emote.id)
+        .bind(&resolution)
+        .fetch_optional(&mut *tx)
+        .await?;
+        sqlx::query(
+            "INSERT INTO emotes (platform, id, resolution, width, height, delays, atlas_layout, \
+             size, created_at, accessed_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(platform.name())
+        .bind(&*emote.id)
+        .bind(&resolution)
+        .bind(emote.width)
+        .bind(emote.height)
+        .bind(serde_json::to_string(&delays).expect("delays always serialize"))
+        .bind(layout)
+        .bind(size as i64)
+        .bind(now)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+        for (kind, i, data) in &images {
+            sqlx::query(
+                "INSERT INTO images (platform, id, resolution, kind, idx, data) \
+                 VALUES (?, ?, ?, ?, ?, ?)",
+            )
+            .bind(platform.name())
+            .bind(&*emote.id)
+            .bind(&resolution)
+            .bind(kind)
+            .bind(*i as i64)
+            .bind(&data[..])
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        debug!("stored {platform} emote {} at {resolution}", emote.id);
+
+        let added = size as i64 - replaced.unwrap_or(0);
+        let total = inner.size.fetch_add(added, Ordering::Relaxed) + added;
+        if total > inner.max_bytes() {
+            inner.trim().await?;
+        }
+        Ok(())
+    }
+}
+
+impl Inner {
+    async fn load(
+        &self,
+        platform: Platform,
+        id: &str,
+        resolution: Resolution,
+        options: &EmoteOptions,
+    ) -> Result<Option<Emote>, StoreError> {
+        let corrupt = |e: &dyn std::fmt::Display| StoreError::Corrupt(e.to_string());
+        let resolution_name = resolution.to_string();
+        let key = |query: &'static str| {
+            sqlx::query(query)
+                .bind(platform.name())
+                .bind(id)
+                .bind(resolution_name.clone())
+        };
+
+        let Some(row) = key(
+            "SELECT width, height, delays, atlas_layout, created_at FROM emotes \
+             WHERE platform = ? AND id = ? AND resolution = ?",
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        if row.get::<i64, _>("created_at") < self.oldest() {
+            key("DELETE FROM emotes WHERE platform = ? AND id = ? AND resolution = ?")
+                .execute(&self.pool)
+                .await?;
+            return Ok(None);
+        }
+        sqlx::query(
+            "UPDATE emotes SET accessed_at = ? WHERE platform = ? AND id = ? AND resolution = ?",
+        )
+        .bind(unix_now())
+        .bind(platform.name())
+        .bind(id)
+        .bind(&resolution_name)
+        .execute(&self.pool)
+        .await?;
+
+        let width: u32 = row.get("width");
+        let height: u32 = row.get("height");
+        let delays: Vec<f64> = serde_json::from_str(row.get("delays")).map_err(|e| corrupt(&e))?;
+        let layout: Option<AtlasLayout> = row
+            .get::<Option<&str>, _>("atlas_layout")
+            .map(serde_json::from_str)
+            .transpose()
+            .map_err(|e| corrupt(&e))?;
+
+        let mut frames = vec![None; delays.len()];
+        let mut pages = Vec::new();
+        for image in key(
+            "SELECT kind, idx, data FROM images WHERE platform = ? AND id = ? AND resolution = ?",
+        )
+        .fetch_all(&self.pool)
+        .await?
+        {
+            let kind: &str = image.get("kind");
+            let idx = image.get::<i64, _>("idx") as usize;
+            let data: Vec<u8> = image.get("data");
+            match kind {
+                "frame" => {
+                    *frames
+                        .get_mut(idx)
+                        .ok_or_else(|| corrupt(&"frame count doesn't match"))? = Some(data)
+                }
+                "atlas" => pages.push((idx, data)),
+                kind => return Err(corrupt(&format!("unknown image kind {kind}"))),
+            }
+        }
+        let frames = frames
+            .into_iter()
+            .collect::<Option<Vec<_>>>()
+            .filter(|f| !f.is_empty())
+            .ok_or_else(|| corrupt(&"frames are missing"))?;
+
+        let id = id.to_owned();
+        let options = options.clone();
+        let emote = options
+            .scheduler
+            .clone()
+            .run_blocking(move || {
+                let decoded = frames
+                    .iter()
+                    .map(|webp| {
+                        image::load_from_memory_with_format(webp, image::ImageFormat::WebP)
+                            .map(|i| i.to_rgba8())
+                            .map_err(|e| StoreError::Emote(e.into()))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                let raw =
+                    RawFrames::new(width, height, decoded.iter()).map_err(EmoteError::from)?;
+                let decoded = Decoded {
+                    raw,
+                    delays,
+                    animated: layout.is_some(),
+                };
+                let emote = Emote::from_decoded(decoded, id, resolution, &options);
+
+                // no need to encode those again
+                for (frame, webp) in emote.frames.iter().zip(frames) {
+                    frame.image().insert(OutputFormat::WebP, webp.into());
+                }
+                // unless the layout's been changed since
+                if let Some(atlas) = &emote.atlas {
+                    if layout == Some(options.atlas) {
+                        for (idx, webp) in pages {
+                            if let Some(page) = atlas.pages.get(idx) {
+                                page.insert(OutputFormat::WebP, webp.into());
+                            }
+                        }
+                    }
+                }
+                Ok::<_, StoreError>(emote)
+            })
+            .await??;
+        Ok(Some(emote))
+    }
+
+    /// anything created before this has expired
+    fn oldest(&self) -> i64 {
+        unix_now() - self.config.max_age.as_secs() as i64
+    }
+
+    fn max_bytes(&self) -> i64 {
+        self.config.max_bytes.min(i64::MAX as u64) as i64
+    }
+
+    /// throws out everything expired, and then the least recently used emotes
+    /// until the rest fits in the size limit
+    async fn trim(&self) -> sqlx::Result<()> {
+        sqlx::query("DELETE FROM emotes WHERE created_at < ?")
+            .bind(self.oldest())
+            .execute(&self.pool)
+            .await?;
+        sqlx::query(
+            "DELETE FROM emotes WHERE rowid IN (SELECT rowid FROM (SELECT rowid, SUM(size) OVER \
+             (ORDER BY accessed_at DESC, rowid DESC) AS total FROM emotes) WHERE total > ?)",
+        )
+        .bind(self.max_bytes())
+        .execute(&self.pool)
+        .await?;
+
+        let size: i64 = sqlx::query_scalar("SELECT COALESCE(SUM(size), 0) FROM emotes")
+            .fetch_one(&self.pool)
+            .await?;
+        self.size.store(size, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// throws out expired emotes every once in a while, until the store's gone
+async fn trimmer(inner: Weak<Inner>) {
+    let mut interval = tokio::time::interval(TRIM_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // it was just trimmed when it was opened
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        if let Err(e) = inner.trim().await {
+            warn!("failed to trim the emote store: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used, reason = "bruh this is a tests module")]
+
+    use std::{path::PathBuf, sync::atomic::Ordering, time::Duration};
+
+    use super::{EmoteStore, StoreConfig};
+    use crate::{
+        emote::{format::OutputFormat, resolution::Resolution, test_gif, Emote, EmoteOptions},
+        platforms::Platform,
+    };
+
+    /// a fresh database that's gone again once the test is done
+    struct TempDb(PathBuf);
+
+    impl TempDb {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("emote-store-{name}-{}.db", std::process::id()));
+            Self(path)
+        }
+
+        fn config(&self) -> StoreConfig {
+            StoreConfig {
+                path: self.0.clone(),
+                max_bytes: 1024 * 1024 * 1024,
+                max_age: Duration::from_secs(60 * 60),
+            }
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            for suffix in ["", "-wal", "-shm"] {
+                let mut path = self.0.clone().into_os_string();
+                path.push(suffix);
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    fn emote(id: &str, frames: usize) -> Emote {
+        let gif = test_gif(frames, 8, 6);
+        Emote::try_new(
+            &gif,
+            image::ImageFormat::Gif,
+            id,
+            Resolution::Scale(2),
+            None,
+            &EmoteOptions::default(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn roundtrip() {
+        let db = TempDb::new("roundtrip");
+        let store = EmoteStore::open(db.config()).await.unwrap();
+        let options = EmoteOptions::default();
+        let original = emote("sourpls", 4);
+        // frames get encoded to be stored, atlas pages only if they were already
+        let atlas = original.atlas.as_ref().unwrap();
+        original
+            .encode(&atlas.pages[0], OutputFormat::WebP)
+            .await
+            .unwrap();
+        store.save(Platform::BetterTtv, &original).await.unwrap();
+        assert!(original.frames[1].image().get(OutputFormat::WebP).is_some());
+
+        // same thing, as far as a fresh server can tell
+        drop(store);
+        let store = EmoteStore::open(db.config()).await.unwrap();
+        let loaded = store
+            .load(
+                Platform::BetterTtv,
+                "sourpls",
+                Resolution::Scale(2),
+                &options,
+            )
+            .await
+            .unwrap();
+        assert_eq!((loaded.width, loaded.height), (8, 6));
+        assert_eq!(loaded.frames.len(), 4);
+        for (a, b) in original.frames.iter().zip(loaded.frames.iter()) {
+            assert_eq!(a.delay, b.delay);
+            assert_eq!(a.image().render().unwrap(), b.image().render().unwrap());
+            assert!(b.image().get(OutputFormat::WebP).is_some());
+        }
+        let atlas = loaded.atlas.unwrap();
+        assert!(atlas.pages[0].get(OutputFormat::WebP).is_some());
+
+        // other platforms and sizes are their own thing
+        for (platform, resolution) in [
+            (Platform::SevenTv, Resolution::Scale(2)),
+            (Platform::BetterTtv, Resolution::Scale(1)),
+        ] {
+            assert!(store
+                .load(platform, "sourpls", resolution, &options)
+                .await
+                .is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn limits() {
+        let db = TempDb::new("limits");
+        let options = EmoteOptions::default();
+        let expired = EmoteStore::open(StoreConfig {
+            max_age: Duration::ZERO,
+            ..db.config()
+        })
+        .await
+        .unwrap();
+        expired
+            .save(Platform::Twitch, &emote("a", 1))
+            .await
+            .unwrap();
+        // a second later it's too old
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(expired
+            .load(Platform::Twitch, "a", Resolution::Scale(2), &options)
+            .await
+            .is_none());
+        drop(expired);
+
+        // only room for about one of them
+        let store = EmoteStore::open(db.config()).await.unwrap();
+        store.save(Platform::Twitch, &emote("a", 3)).await.unwrap();
+        let size: i64 = sqlx::query_scalar("SELECT size FROM emotes")
+            .fetch_one(&store.0.as_ref().unwrap().pool)
+            .await
+            .unwrap();
+        // counted without asking the database
+        let inner = store.0.as_ref().unwrap();
+        assert_eq!(inner.size.load(Ordering::Relaxed), size);
+        drop(store);
+        let store = EmoteStore::open(StoreConfig {
+            max_bytes: size as u64 + size as u64 / 2,
+            ..db.config()
+        })
+        .await
+        .unwrap();
+        store.save(Platform::Twitch, &emote("b", 3)).await.unwrap();
+        assert!(store
+            .load(Platform::Twitch, "a", Resolution::Scale(2), &options)
+            .await
+            .is_none());
+        assert!(store
+            .load(Platform::Twitch, "b", Resolution::Scale(2), &options)
+            .await
+            .is_some());
+    }
+}